@@ -0,0 +1,114 @@
+// Compares the plain interpreter with the decode cache and with translated blocks on a few guest
+// workloads. Run with
+// `cargo bench`; the number of steps per run can be set with CPUWU_BENCH_STEPS.
+
+use std::time::{Duration, Instant};
+
+use cpuwu::loader::{Image, Segment};
+use cpuwu::{Cpu, SimpleAddress};
+
+const DEFAULT_STEPS: u64 = 5_000_000;
+
+struct Workload {
+    name: &'static str,
+    program: Vec<u8>,
+    paging: bool,
+}
+
+fn workloads() -> Vec<Workload> {
+    // li x0, 0; li x1, 1; li x2, 3
+    // loop: add x0, x1; xor x3, x0; and x3, x2; bsl x3, x1; stc; bc loop
+    let arith = vec![
+        0x40, 0, 0, 0, 0, 0x41, 1, 0, 0, 0, 0x42, 3, 0, 0, 0, 0x80, 0x01, 0x8d, 0x30, 0x8b, 0x32, 0x89, 0x31, 0x11,
+        0x02, 0x0f, 0, 0, 0,
+    ];
+
+    // loop: call f; stc; bc loop
+    // f (at 0x10): add x0, x1; ret
+    let mut calls = vec![0x18, 0x10, 0, 0, 0, 0x11, 0x02, 0, 0, 0, 0];
+    calls.resize(0x10, 0);
+    calls.extend_from_slice(&[0x80, 0x01, 0x19]);
+
+    // li x1, 0x10000; li x2, 0x20000; li x3, 4
+    // loop: lwi x0, [x1]; swi x0, [x2]; clc; add x1, x3; clc; add x2, x3; stc; bc loop
+    let copy = vec![
+        0x41, 0, 0, 1, 0, 0x42, 0, 0, 2, 0, 0x43, 4, 0, 0, 0, 0x94, 0x01, 0x96, 0x02, 0x10, 0x80, 0x13, 0x10, 0x80,
+        0x23, 0x11, 0x02, 0x0f, 0, 0, 0,
+    ];
+
+    vec![
+        Workload { name: "arith", program: arith.clone(), paging: false },
+        Workload { name: "calls", program: calls, paging: false },
+        Workload { name: "copy", program: copy, paging: false },
+        Workload { name: "arith-paged", program: arith, paging: true },
+    ]
+}
+
+fn cpu(workload: &Workload, cached: bool, blocks: bool) -> Cpu<SimpleAddress> {
+    let mut segments = vec![Segment { addr: 0, data: workload.program.clone(), zeros: 0 }];
+    if workload.paging {
+        // Identity map the first page with every permission, with the page table at 0x100000
+        segments.push(Segment { addr: 0x100000, data: 0x110000u32.to_le_bytes().to_vec(), zeros: 0 });
+        segments.push(Segment { addr: 0x110000, data: 0xf0000000u32.to_le_bytes().to_vec(), zeros: 0 });
+    }
+
+    let mut cpu = Cpu::new(SimpleAddress::default());
+    cpu.load_image(&Image { segments, entry: Some(0) }, 0x8000).unwrap();
+    if workload.paging {
+        cpu.set_memmap(0x100000);
+        cpu.set_flags(1 << 12);
+    }
+    cpu.set_decode_cache(cached);
+    cpu.set_block_translation(blocks);
+    cpu
+}
+
+fn time(cpu: &mut Cpu<SimpleAddress>, steps: u64) -> Duration {
+    let start = Instant::now();
+    if cpu.block_translation_enabled() {
+        cpu.run(steps);
+    } else {
+        for _ in 0..steps {
+            cpu.step();
+        }
+    }
+    start.elapsed()
+}
+
+fn main() {
+    let steps = std::env::var("CPUWU_BENCH_STEPS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(DEFAULT_STEPS);
+
+    println!(
+        "{:<14} {:>16} {:>16} {:>16} {:>8}",
+        "workload", "plain (Minst/s)", "cached (Minst/s)", "blocks (Minst/s)", "speedup"
+    );
+    for workload in workloads() {
+        let mut plain = cpu(&workload, false, false);
+        let mut cached = cpu(&workload, true, false);
+        let mut blocks = cpu(&workload, false, true);
+        let plain_time = time(&mut plain, steps);
+        let cached_time = time(&mut cached, steps);
+        let blocks_time = time(&mut blocks, steps);
+
+        // Every interpreter must end up in the same state
+        for other in [&cached, &blocks] {
+            for x in 0..16 {
+                assert_eq!(plain.int_register(x), other.int_register(x), "{}: x{} differs", workload.name, x);
+            }
+            assert_eq!(plain.cycles(), other.cycles(), "{}: cycles differ", workload.name);
+        }
+
+        let rate = |d: Duration| steps as f64 / d.as_secs_f64() / 1e6;
+        println!(
+            "{:<14} {:>16.1} {:>16.1} {:>16.1} {:>7.1}x",
+            workload.name,
+            rate(plain_time),
+            rate(cached_time),
+            rate(blocks_time),
+            plain_time.as_secs_f64() / blocks_time.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,202 @@
+// Call stack unwinding
+//
+// `call` pushes the caller's base pointer and then the return address, and points x14 at the byte
+// below them. So for a frame with base pointer bp, bp+1..=bp+4 holds the return address and
+// bp+5..=bp+8 the caller's base pointer, both little endian, and the frames form a chain that can
+// be walked up to the outermost one.
+
+use crate::symbols::Symbols;
+use crate::{Address, BusError, Cpu, InvalidMemoryAccess, PAGE_SIZE, READ, R_BASE, R_PC};
+
+// Unwinding stops after this many frames in case the chain loops
+pub const MAX_FRAMES: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub pc: u32,
+    pub bp: u32,
+}
+
+// Why unwinding stopped before reaching the outermost frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnwindError {
+    // The frame record of the frame with this base pointer could not be read
+    Unreadable { bp: u32, addr: u32, fault: InvalidMemoryAccess },
+
+    // The saved base pointer is not above the frame it was saved in, so the stack is corrupted
+    NotAscending { bp: u32, saved: u32 },
+
+    TooDeep,
+}
+
+impl std::fmt::Display for UnwindError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            UnwindError::Unreadable { bp, addr, fault } => {
+                let reason = match fault {
+                    InvalidMemoryAccess::UsedFreePage => "is not mapped",
+                    _ => "is not readable",
+                };
+                write!(f, "frame record of frame at bp {:#010x} {} (at {:#010x})", bp, reason, addr)
+            }
+            UnwindError::NotAscending { bp, saved } => write!(
+                f,
+                "frame at bp {:#010x} saved bp {:#010x}, which is not above it; the stack may be corrupted",
+                bp, saved
+            ),
+            UnwindError::TooDeep => write!(f, "more than {} frames", MAX_FRAMES),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Backtrace {
+    // Innermost frame first
+    pub frames: Vec<Frame>,
+    pub error: Option<UnwindError>,
+}
+
+impl Backtrace {
+    // One line per frame like `#1  0x00000005 <main+0x5> bp 0x00007ff8`, followed by why
+    // unwinding stopped early if it did
+    pub fn format(&self, symbols: &Symbols) -> String {
+        let mut s = String::new();
+        for (i, frame) in self.frames.iter().enumerate() {
+            s.push_str(&format!("#{:<3} {:#010x}", i, frame.pc));
+            if let Some(symbol) = symbols.describe(frame.pc) {
+                s.push_str(&format!(" <{}>", symbol));
+            }
+            s.push_str(&format!(" bp {:#010x}\n", frame.bp));
+        }
+        if let Some(error) = self.error {
+            s.push_str(&format!("backtrace stopped: {}\n", error));
+        }
+        s
+    }
+}
+
+impl<T> Cpu<T>
+where
+    T: Address,
+{
+    fn read_frame_u32(&mut self, bp: u32, addr: u32) -> Result<u32, UnwindError> {
+        // A word within one page takes a single translation
+        if addr % PAGE_SIZE <= PAGE_SIZE - 4 {
+            let paddr = self.check_memory(addr, READ).map_err(|fault| UnwindError::Unreadable { bp, addr, fault })?;
+            if paddr <= 0x0fffffff - 3 {
+                return self.addressing.read_u32(paddr).map_err(|BusError(p)| UnwindError::Unreadable {
+                    bp,
+                    addr: addr.wrapping_add(p.wrapping_sub(paddr)),
+                    fault: InvalidMemoryAccess::BusError(p),
+                });
+            }
+        }
+
+        let mut value = 0;
+        for i in 0..4 {
+            let addr = addr.wrapping_add(i);
+            let unreadable = |fault| UnwindError::Unreadable { bp, addr, fault };
+            let paddr = self.check_memory(addr, READ).map_err(unreadable)?;
+            value |= (self.addressing.read(paddr).map_err(|e| unreadable(e.into()))? as u32) << (8 * i);
+        }
+        Ok(value)
+    }
+
+    // Walks the base pointer chain from the current frame through virtual memory, without
+    // triggering watchpoints or being traced. The chain ends at a frame with a base pointer of
+    // zero or whose frame record is all zeros, as stack memory that was never pushed to is.
+    pub fn backtrace(&mut self) -> Backtrace {
+        let mut frames = vec![Frame { pc: self.xs[R_PC], bp: self.xs[R_BASE] }];
+
+        loop {
+            let bp = frames.last().unwrap().bp;
+            if bp == 0 {
+                return Backtrace { frames, error: None };
+            }
+            if frames.len() == MAX_FRAMES {
+                return Backtrace { frames, error: Some(UnwindError::TooDeep) };
+            }
+
+            let record = self
+                .read_frame_u32(bp, bp.wrapping_add(1))
+                .and_then(|pc| Ok((pc, self.read_frame_u32(bp, bp.wrapping_add(5))?)));
+            let (pc, saved) = match record {
+                Ok(record) => record,
+                Err(e) => return Backtrace { frames, error: Some(e) },
+            };
+
+            if pc == 0 && saved == 0 {
+                return Backtrace { frames, error: None };
+            }
+            if saved != 0 && saved <= bp {
+                return Backtrace { frames, error: Some(UnwindError::NotAscending { bp, saved }) };
+            }
+            frames.push(Frame { pc, bp: saved });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flags::Flag;
+    use crate::{SimpleAddress, R_SP};
+
+    // 0x00: call 0x10
+    // 0x10: call 0x20
+    // 0x20: ret
+    fn cpu() -> Cpu<SimpleAddress> {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[0x00..0x05].copy_from_slice(&[0x18, 0x10, 0, 0, 0]);
+        cpu.addressing.memory[0x10..0x15].copy_from_slice(&[0x18, 0x20, 0, 0, 0]);
+        cpu.addressing.memory[0x20] = 0x19;
+        cpu.xs[R_SP] = 0x8000;
+        cpu.xs[R_BASE] = 0x8000;
+        cpu
+    }
+
+    #[test]
+    fn backtrace_frames() {
+        let mut cpu = cpu();
+        cpu.run(2);
+
+        let backtrace = cpu.backtrace();
+        assert_eq!(backtrace.error, None);
+        assert_eq!(
+            backtrace.frames,
+            vec![
+                Frame { pc: 0x20, bp: 0x7ff0 },
+                Frame { pc: 0x15, bp: 0x7ff8 },
+                Frame { pc: 0x05, bp: 0x8000 },
+            ]
+        );
+
+        let symbols = Symbols::parse_map("0x0 main\n0x10 f\n0x20 g\n").unwrap();
+        let text = backtrace.format(&symbols);
+        assert_eq!(text.lines().nth(1), Some("#1   0x00000015 <f+0x5> bp 0x00007ff8"));
+
+        cpu.step();
+        assert_eq!(cpu.backtrace().frames.len(), 2);
+    }
+
+    #[test]
+    fn backtrace_errors() {
+        let mut cpu = cpu();
+        cpu.run(2);
+
+        // Overwrite the saved base pointer of the innermost frame with one below it
+        cpu.addressing.memory[0x7ff5..0x7ff9].copy_from_slice(&[0x00, 0x10, 0, 0]);
+        let backtrace = cpu.backtrace();
+        assert_eq!(backtrace.frames.len(), 1);
+        assert_eq!(backtrace.error, Some(UnwindError::NotAscending { bp: 0x7ff0, saved: 0x1000 }));
+
+        // Paging on with an empty page table, so no frame record can be read
+        let mut cpu = self::cpu();
+        cpu.run(2);
+        cpu.set_flag(Flag::MemmapEnable, true);
+        cpu.memmap = 0x10000;
+        let backtrace = cpu.backtrace();
+        assert_eq!(backtrace.frames.len(), 1);
+        assert!(matches!(backtrace.error, Some(UnwindError::Unreadable { bp: 0x7ff0, addr: 0x7ff1, .. })));
+    }
+}
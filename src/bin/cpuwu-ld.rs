@@ -0,0 +1,103 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+
+use cpuwu::link::{self, Script};
+use cpuwu::object::Object;
+
+const USAGE: &str = "\
+usage: cpuwu-ld [-T script] [-M map] [-o output] <objects...>
+
+Links cpuwu objects into an executable (default a.cwx). An output ending in .bin is written as a
+flat binary starting at the lowest section address instead, with any gaps between sections filled
+with zeros, up to 16 MiB in total.";
+
+// Largest flat binary written, so that sections far apart don't fill a file with gigabytes of zeros
+const MAX_BINARY_SIZE: usize = 16 << 20;
+
+fn fail(message: String) -> ! {
+    eprintln!("error: {}", message);
+    std::process::exit(1);
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let mut script = None;
+    let mut map = None;
+    let mut output = "a.cwx".to_string();
+    let mut paths = vec![];
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next().unwrap_or_else(|| fail(format!("`{}` needs a value\n{}", arg, USAGE)))
+        };
+        match arg.as_str() {
+            "-T" => script = Some(value()),
+            "-M" => map = Some(value()),
+            "-o" => output = value(),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        fail(format!("no input files\n{}", USAGE));
+    }
+
+    let script = match script {
+        Some(path) => {
+            let text = std::fs::read_to_string(&path)
+                .unwrap_or_else(|e| fail(format!("could not read `{}`: {}", path, e)));
+            Script::parse(&text).unwrap_or_else(|e| fail(format!("{}: {}", path, e)))
+        }
+        None => Script::default(),
+    };
+
+    let inputs: Vec<(String, Object)> = paths
+        .into_iter()
+        .map(|path| {
+            let file = File::open(&path).unwrap_or_else(|e| fail(format!("could not read `{}`: {}", path, e)));
+            let object = Object::read(BufReader::new(file)).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+            (path, object)
+        })
+        .collect();
+
+    let linked = link::link(&script, &inputs).unwrap_or_else(|e| fail(e.to_string()));
+
+    let create = |path: &str| {
+        let file = File::create(path).unwrap_or_else(|e| fail(format!("could not create `{}`: {}", path, e)));
+        BufWriter::new(file)
+    };
+    let result = if output.ends_with(".bin") {
+        let image = linked.executable.to_image().unwrap_or_else(|e| fail(e.to_string()));
+        let start = image.segments.iter().map(|s| s.addr).min().unwrap_or(0);
+        let end = image.segments.iter().map(|s| s.addr as usize + s.len() as usize).max().unwrap_or(0);
+        if end - start as usize > MAX_BINARY_SIZE {
+            fail(format!(
+                "sections from {:#010x} to {:#x} span more than the {} MiB a flat binary can hold",
+                start,
+                end,
+                MAX_BINARY_SIZE >> 20
+            ));
+        }
+        let mut data = vec![0; end - start as usize];
+        for s in image.segments.iter() {
+            let at = (s.addr - start) as usize;
+            data[at..at + s.data.len()].copy_from_slice(&s.data);
+        }
+        let mut w = create(&output);
+        w.write_all(&data).and_then(|_| w.flush())
+    } else {
+        linked.executable.write(create(&output))
+    };
+    if let Err(e) = result {
+        fail(format!("could not write `{}`: {}", output, e));
+    }
+
+    if let Some(path) = map {
+        if let Err(e) = linked.write_map(create(&path)) {
+            fail(format!("could not write `{}`: {}", path, e));
+        }
+    }
+}
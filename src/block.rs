@@ -0,0 +1,462 @@
+// Basic block translation
+//
+// With block translation enabled, `run` executes code a basic block at a time. A block is the
+// straight line code from an address up to and including the first instruction that can jump or
+// change privileged state (branches, call, ret, the ring, paging and interrupt enables and the
+// privileged moves). It is decoded once into a list of ops, which are then executed back to back
+// without fetching or decoding their bytes again. Blocks lie within one virtual and one physical
+// page of ordinary memory, so translating the address of their first instruction covers all of
+// them.
+//
+// Every instruction in a block is accounted for the same way as a step: cycles, TLB penalties,
+// counters, coverage, profiler samples and device events. A block stops early after an
+// instruction that faults, writes the pc, invalidates a translated block (writes to code in the
+// block itself included) or leaves an interrupt ready to be handled, so that faults are raised
+// with the pc of the instruction that caused them and interrupts are delivered at block
+// boundaries just as they would be between steps. Blocks are invalidated and flushed together
+// with the decode cache, see decode.rs.
+//
+// Tracing, reverse execution and breakpoints observe every step, so `run` falls back to stepping
+// while any of them are in use.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::debug::StopReason;
+use crate::decode::{decode_op, Op};
+use crate::flags::Flag;
+use crate::timing::OpClass;
+use crate::{perf, Address, Cpu, EXEC, PAGE_SIZE, R_PC};
+
+// Longest block translated, in instructions
+const MAX_BLOCK_OPS: usize = 64;
+
+// Number of physical pages, and of words in the bitmap of pages holding blocks
+const PAGES: usize = (1 << 32) / PAGE_SIZE as usize;
+const PAGE_WORDS: usize = PAGES / 64;
+
+#[derive(Debug, Clone, Copy)]
+struct BlockOp {
+    op: Op,
+    class: OpClass,
+    len: u8,
+}
+
+impl BlockOp {
+    // Whether the instruction writes to memory, which may change the page tables
+    fn writes(&self) -> bool {
+        match self.op {
+            Op::StoreInt(..) | Op::StoreShort(..) | Op::StoreByte(..) | Op::StoreFloat(..) => true,
+            Op::Registers(opcode, _, _) => (0x16..=0x19).contains(&opcode),
+            _ => false,
+        }
+    }
+}
+
+// Whether an instruction ends a block
+fn ends_block(op: Op) -> bool {
+    match op {
+        Op::BranchTrue(..) | Op::BranchFalse(..) | Op::Call(_) | Op::Ret => true,
+        Op::SetMemmapEnable(_) | Op::SetInterruptEnable(_) | Op::EnterUserRing => true,
+        Op::Registers(opcode, _, _) => opcode == 0x1a || opcode == 0x1b,
+        _ => false,
+    }
+}
+
+struct Block {
+    // Length in bytes
+    len: u32,
+    ops: Vec<BlockOp>,
+}
+
+// Blocks by the physical address of their first instruction
+pub(crate) struct BlockCache {
+    blocks: HashMap<u32, Arc<Block>>,
+
+    // Start and end of the blocks in each physical page, and a bit per page that has any, so
+    // that writes to pages without code are cheap to check
+    pages: HashMap<u32, Vec<(u32, u32)>>,
+    occupied: Vec<u64>,
+
+    // Page table address and whether paging was enabled when the blocks were translated
+    context: Option<(u32, bool)>,
+
+    // Set when blocks are dropped, so that a running block stops after the instruction that
+    // dropped them
+    dirty: bool,
+
+    translated: u64,
+    executed: u64,
+}
+
+impl Default for BlockCache {
+    fn default() -> BlockCache {
+        BlockCache {
+            blocks: HashMap::new(),
+            pages: HashMap::new(),
+            occupied: vec![0; PAGE_WORDS],
+            context: None,
+            dirty: false,
+            translated: 0,
+            executed: 0,
+        }
+    }
+}
+
+impl BlockCache {
+    fn insert(&mut self, paddr: u32, block: Arc<Block>) {
+        let page = paddr / PAGE_SIZE;
+        let ranges = self.pages.entry(page).or_default();
+        ranges.retain(|&(start, _)| start != paddr);
+        ranges.push((paddr, paddr + block.len));
+        self.occupied[page as usize / 64] |= 1 << (page % 64);
+        self.blocks.insert(paddr, block);
+    }
+
+    // Drops any block containing a byte of the len bytes at paddr, which are in one page
+    pub(crate) fn invalidate(&mut self, paddr: u32, len: u32) {
+        let page = paddr / PAGE_SIZE;
+        if self.occupied[page as usize / 64] & 1 << (page % 64) == 0 {
+            return;
+        }
+
+        let ranges = self.pages.get_mut(&page).unwrap();
+        let (blocks, mut dropped) = (&mut self.blocks, false);
+        ranges.retain(|&(start, end)| {
+            let hit = start < paddr + len && paddr < end;
+            if hit {
+                blocks.remove(&start);
+                dropped = true;
+            }
+            !hit
+        });
+        if ranges.is_empty() {
+            self.pages.remove(&page);
+            self.occupied[page as usize / 64] &= !(1 << (page % 64));
+        }
+        self.dirty |= dropped;
+    }
+
+    pub(crate) fn flush(&mut self) {
+        if !self.blocks.is_empty() {
+            self.blocks.clear();
+            self.pages.clear();
+            self.occupied.iter_mut().for_each(|w| *w = 0);
+        }
+        self.dirty = true;
+    }
+}
+
+impl<T> Cpu<T>
+where
+    T: Address,
+{
+    pub fn block_translation_enabled(&self) -> bool {
+        self.blocks.is_some()
+    }
+
+    // Enables or disables running translated blocks in `run`. Disabling it drops every block.
+    pub fn set_block_translation(&mut self, enabled: bool) {
+        if enabled != self.blocks.is_some() {
+            self.blocks = if enabled { Some(Box::default()) } else { None };
+        }
+    }
+
+    // Number of blocks translated and executed
+    pub fn block_stats(&self) -> Option<(u64, u64)> {
+        self.blocks.as_ref().map(|b| (b.translated, b.executed))
+    }
+
+    // Whether `run` can execute blocks, which it can't while anything needs to see every step
+    pub(crate) fn blocks_usable(&self) -> bool {
+        self.blocks.is_some() && self.tracer.is_none() && self.history.is_none() && self.triggers.is_empty()
+    }
+
+    // Decodes the block starting at pc, which translates to paddr, without any side effects.
+    // Returns None if not even its first instruction can be translated.
+    fn translate_block(&mut self, pc: u32, paddr: u32) -> Option<Block> {
+        let room = (PAGE_SIZE - pc % PAGE_SIZE).min(PAGE_SIZE - paddr % PAGE_SIZE);
+        let mut block = Block { len: 0, ops: vec![] };
+        while block.ops.len() < MAX_BLOCK_OPS {
+            let (start, mut len) = (block.len, 0);
+            let addressing = &mut self.addressing;
+            let mut next = || {
+                let offset = start + len;
+                if offset >= room || addressing.is_device(paddr + offset) {
+                    return Err(());
+                }
+                len += 1;
+                addressing.read(paddr + offset).map_err(|_| ())
+            };
+
+            let (op, class) = match next().and_then(|opcode| Ok((decode_op(opcode, &mut next)?, opcode))) {
+                Ok((op, opcode)) => (op, OpClass::of(opcode)),
+                Err(()) => break,
+            };
+            block.ops.push(BlockOp { op, class, len: len as u8 });
+            block.len += len;
+            if ends_block(op) {
+                break;
+            }
+        }
+        Some(block).filter(|b| !b.ops.is_empty())
+    }
+
+    // The block starting at pc, translating it if needed
+    fn block(&mut self, pc: u32, paddr: u32) -> Option<Arc<Block>> {
+        let context = (self.memmap, self.get_flag(Flag::MemmapEnable));
+        let blocks = self.blocks.as_mut().unwrap();
+        if blocks.context != Some(context) {
+            blocks.flush();
+            blocks.context = Some(context);
+        }
+
+        // A block reached through another mapping may cross the end of this virtual page
+        let fits = |b: &&Arc<Block>| pc % PAGE_SIZE + b.len <= PAGE_SIZE;
+        if let Some(block) = blocks.blocks.get(&paddr).filter(fits) {
+            return Some(block.clone());
+        }
+
+        let block = Arc::new(self.translate_block(pc, paddr)?);
+        let blocks = self.blocks.as_mut().unwrap();
+        blocks.translated += 1;
+        blocks.insert(paddr, block.clone());
+        Some(block)
+    }
+
+    // Executes the block at pc, taking at most limit steps, or a single step if there is an
+    // interrupt to handle or no block can be translated. Returns the number of steps taken and
+    // the pc of the last instruction executed.
+    fn step_block(&mut self, limit: u64) -> (u64, u32) {
+        let pc = self.xs[R_PC];
+        let block = match self.interrupt_pending() {
+            true => None,
+            false => match self.check_memory(pc, EXEC) {
+                Ok(paddr) => self.block(pc, paddr).map(|block| (block, paddr)),
+                Err(_) => None,
+            },
+        };
+        let (block, paddr) = match block {
+            Some(block) => block,
+            None => {
+                self.step();
+                return (1, pc);
+            }
+        };
+
+        let blocks = self.blocks.as_mut().unwrap();
+        blocks.executed += 1;
+        blocks.dirty = false;
+
+        let (mut steps, mut start) = (0, pc);
+        for op in block.ops.iter() {
+            if steps == limit {
+                break;
+            }
+
+            start = self.xs[R_PC];
+            let next = start.wrapping_add(op.len as u32);
+            self.charge_translation(start);
+            self.charge(self.costs.cost(op.class));
+            self.xs[R_PC] = next;
+            let result = self.execute(op.op);
+            match result {
+                Ok(()) => {
+                    self.counters.count(perf::Counter::Instructions, 1);
+                    self.cover_instruction(start);
+                }
+                Err(e) => self.fault(e),
+            }
+            self.run_events();
+            self.sample_profile();
+            steps += 1;
+
+            let dirty = self.blocks.as_ref().is_some_and(|b| b.dirty);
+            if result.is_err() || self.xs[R_PC] != next || dirty || self.interrupt_pending() {
+                break;
+            }
+
+            // A write to the page tables may have remapped the rest of the block
+            if op.writes() && self.get_flag(Flag::MemmapEnable) {
+                let expected = paddr.wrapping_add(next.wrapping_sub(pc));
+                if self.check_memory(next, EXEC) != Ok(expected) {
+                    break;
+                }
+            }
+        }
+        (steps, start)
+    }
+
+    // The translated block version of `run`, for when nothing needs to see every step
+    pub(crate) fn run_blocks(&mut self, limit: u64) -> StopReason {
+        let mut done = 0;
+        while done < limit {
+            let (steps, pc) = self.step_block(limit - done);
+            done += steps;
+            if let Some(reason) = self.triggers.take_fault(pc) {
+                return reason;
+            }
+        }
+
+        StopReason::StepLimit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debug::{Space, Trigger};
+    use crate::timing::EventQueue;
+    use crate::{BusError, InvalidMemoryAccess, SimpleAddress, R_SP};
+
+    // Raises interrupt 1 every 25 cycles
+    struct Timer {
+        memory: SimpleAddress,
+        events: EventQueue,
+    }
+
+    impl Address for Timer {
+        fn read(&mut self, addr: u32) -> Result<u8, BusError> {
+            self.memory.read(addr)
+        }
+
+        fn write(&mut self, addr: u32, data: u8) -> Result<(), BusError> {
+            self.memory.write(addr, data)
+        }
+
+        fn next_event(&self) -> Option<u64> {
+            self.events.next()
+        }
+
+        fn run_event(&mut self, now: u64) -> Option<u8> {
+            self.events.pop_due(now)?;
+            self.events.schedule(now + 25, 0);
+            Some(1)
+        }
+    }
+
+    fn cpu(program: &[u8], blocks: bool) -> Cpu<SimpleAddress> {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[..program.len()].copy_from_slice(program);
+        cpu.xs[R_SP] = 0x8000;
+        cpu.set_block_translation(blocks);
+        cpu
+    }
+
+    fn assert_same<T: Address>(a: &Cpu<T>, b: &Cpu<T>) {
+        assert_eq!(a.xs, b.xs);
+        assert_eq!(a.flags, b.flags);
+        assert_eq!(a.cycles(), b.cycles());
+        assert_eq!(a.interrupt_queue, b.interrupt_queue);
+    }
+
+    #[test]
+    fn blocks_match_stepping() {
+        // li x0, 0; li x1, 1; loop: call f; add x0, x1; xor x2, x0; stc; bc loop
+        // f (at 0x20): sw x0, [0x1000]; lw x3, [0x1000]; ret
+        let mut program = vec![
+            0x40, 0, 0, 0, 0, 0x41, 1, 0, 0, 0, 0x18, 0x20, 0, 0, 0, 0x80, 0x01, 0x8d, 0x20, 0x11, 0x02, 0x0a, 0, 0, 0,
+        ];
+        program.resize(0x20, 0);
+        program.extend_from_slice(&[0xc0, 0, 0x10, 0, 0, 0x63, 0, 0x10, 0, 0, 0x19]);
+
+        for steps in [1, 2, 7, 100, 1001] {
+            let mut blocks = cpu(&program, true);
+            let mut plain = cpu(&program, false);
+            assert_eq!(blocks.run(steps), StopReason::StepLimit);
+            assert_eq!(plain.run(steps), StopReason::StepLimit);
+            assert_same(&blocks, &plain);
+        }
+
+        // The entry, the call in the loop, f and the rest of the loop after returning
+        let mut blocks = cpu(&program, true);
+        blocks.run(1000);
+        let (translated, executed) = blocks.block_stats().unwrap();
+        assert_eq!(translated, 4);
+        assert!(executed < 500);
+        assert_eq!(cpu(&program, false).block_stats(), None);
+    }
+
+    #[test]
+    fn blocks_self_modifying() {
+        // li x1, 9; sb x1, [0x0b]; li x0, 7
+        // The store changes the last instruction of its own block into li x0, 9
+        let program = [0x41, 9, 0, 0, 0, 0xe1, 0x0b, 0, 0, 0, 0x40, 7, 0, 0, 0];
+        let mut cpu = cpu(&program, true);
+        cpu.run(3);
+        assert_eq!(cpu.xs[0], 9);
+        assert_eq!(cpu.block_stats(), Some((2, 2)));
+
+        // Changing memory from outside the cpu is seen too
+        cpu.addressing_mut().write(0x0b, 3).unwrap();
+        cpu.xs[R_PC] = 0x0a;
+        cpu.run(1);
+        assert_eq!(cpu.xs[0], 3);
+    }
+
+    #[test]
+    fn blocks_fault_mid_block() {
+        // li x0, 1; sw x0, [0x10000]; li x0, 2 with only the first page mapped
+        let program = [0x40, 1, 0, 0, 0, 0xc0, 0, 0, 1, 0, 0x40, 2, 0, 0, 0];
+        let setup = |blocks| {
+            let mut cpu = cpu(&program, blocks);
+            cpu.addressing.memory[0x20000..0x20004].copy_from_slice(&0x30000u32.to_le_bytes());
+            cpu.addressing.memory[0x30000..0x30004].copy_from_slice(&0xf0000000u32.to_le_bytes());
+            cpu.memmap = 0x20000;
+            cpu.set_flag(Flag::MemmapEnable, true);
+            cpu
+        };
+
+        let (mut blocks, mut plain) = (setup(true), setup(false));
+        let cause = Some(InvalidMemoryAccess::UsedFreePage);
+        let fault = StopReason::Fault { interrupt: 0x80000000, pc: 0x05, cause };
+        assert_eq!(blocks.run(10), fault);
+        assert_eq!(plain.run(10), fault);
+        assert_same(&blocks, &plain);
+        assert_eq!(blocks.xs[0], 1);
+    }
+
+    #[test]
+    fn blocks_interrupts() {
+        // A timer interrupt arrives in the middle of the block of a loop, and has to be taken
+        // after the same instruction as when stepping
+        // loop: add x0, x1; add x0, x1; add x0, x1; stc; bc loop
+        let setup = |blocks| {
+            let mut events = EventQueue::new();
+            events.schedule(25, 0);
+            let mut cpu = Cpu::new(Timer { memory: SimpleAddress::default(), events });
+            cpu.addressing.memory.memory[0x100..0x10c]
+                .copy_from_slice(&[0x80, 0x01, 0x80, 0x01, 0x80, 0x01, 0x11, 0x02, 0x00, 0x01, 0, 0]);
+            cpu.xs[1] = 1;
+            cpu.xs[R_PC] = 0x100;
+            cpu.set_flag(Flag::InterruptEnable, true);
+            cpu.set_block_translation(blocks);
+            cpu
+        };
+
+        for steps in [10, 11, 12, 13, 2000] {
+            let (mut blocks, mut plain) = (setup(true), setup(false));
+            blocks.run(steps);
+            plain.run(steps);
+            assert_same(&blocks, &plain);
+        }
+    }
+
+    #[test]
+    fn blocks_send() {
+        // Cpus with block translation can still be moved to other threads
+        fn assert_send<T: Send>() {}
+        assert_send::<Cpu<SimpleAddress>>();
+    }
+
+    #[test]
+    fn blocks_fall_back_to_stepping() {
+        // li x0, 1; li x0, 2; bz 0x00
+        let program = [0x40, 1, 0, 0, 0, 0x40, 2, 0, 0, 0, 0x00, 0, 0, 0, 0];
+        let mut cpu = cpu(&program, true);
+        cpu.add_trigger(Trigger::Breakpoint { space: Space::Virtual, addr: 0x05 });
+        assert!(!cpu.blocks_usable());
+        assert_eq!(cpu.run(10), StopReason::Breakpoint { id: 0, addr: 0x05 });
+        assert_eq!(cpu.block_stats(), Some((0, 0)));
+    }
+}
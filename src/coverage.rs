@@ -0,0 +1,240 @@
+// Code coverage
+//
+// While coverage is attached, the cpu counts how many times each instruction address executed and
+// how many times each conditional branch was taken and not taken. Reports are written in the lcov
+// tracefile format, mapped to source lines with the line info of an executable.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+use crate::disasm;
+use crate::lines::LineTable;
+use crate::{Address, Cpu, R_PC};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BranchCounts {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    executed: HashMap<u32, u64>,
+    branches: HashMap<u32, BranchCounts>,
+}
+
+// Opcodes 0x00 to 0x0f are the conditional branches
+fn is_branch(opcode: u8) -> bool {
+    opcode <= 0x0f
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    // Number of times the instruction at addr executed
+    pub fn hits(&self, addr: u32) -> u64 {
+        self.executed.get(&addr).copied().unwrap_or(0)
+    }
+
+    pub fn branch(&self, addr: u32) -> Option<BranchCounts> {
+        self.branches.get(&addr).copied()
+    }
+
+    // Number of distinct instruction addresses executed
+    pub fn instructions(&self) -> usize {
+        self.executed.len()
+    }
+
+    // Number of distinct branches executed, and how many of them went both ways
+    pub fn branches(&self) -> (usize, usize) {
+        let both = self.branches.values().filter(|b| b.taken != 0 && b.not_taken != 0).count();
+        (self.branches.len(), both)
+    }
+
+    pub fn clear(&mut self) {
+        self.executed.clear();
+        self.branches.clear();
+    }
+
+    fn execute(&mut self, pc: u32) {
+        *self.executed.entry(pc).or_insert(0) += 1;
+    }
+
+    fn take_branch(&mut self, pc: u32, taken: bool) {
+        let counts = self.branches.entry(pc).or_default();
+        match taken {
+            true => counts.taken += 1,
+            false => counts.not_taken += 1,
+        }
+    }
+
+    // Writes an lcov tracefile with a record per source file in lines. A line's count is the
+    // highest count of the instructions assembled from it. Conditional branches are found by
+    // disassembling each line with fetch, and reported as a taken and a not taken branch.
+    pub fn write_lcov<W, F>(&self, mut w: W, lines: &LineTable, mut fetch: F) -> io::Result<()>
+    where
+        W: Write,
+        F: FnMut(u32) -> Option<u8>,
+    {
+        // Per file, line counts and the branch instructions of each line
+        let mut files: Vec<BTreeMap<u32, (u64, Vec<u32>)>> = vec![BTreeMap::new(); lines.files().len()];
+        for range in lines.ranges() {
+            let entry = files[range.file].entry(range.line).or_insert((0, vec![]));
+            let mut addr = range.start;
+            while addr < range.end {
+                entry.0 = entry.0.max(self.hits(addr));
+                let i = match disasm::disassemble(addr, &mut fetch) {
+                    Some(i) => i,
+                    None => break,
+                };
+                if fetch(addr).is_some_and(is_branch) {
+                    entry.1.push(addr);
+                }
+                addr = match addr.checked_add(i.len) {
+                    Some(next) => next,
+                    None => break,
+                };
+            }
+        }
+
+        for (name, file) in lines.files().iter().zip(files.iter()) {
+            writeln!(w, "TN:")?;
+            writeln!(w, "SF:{}", name)?;
+
+            let (mut found, mut hit) = (0, 0);
+            for (line, (_, branches)) in file.iter() {
+                for (block, &addr) in branches.iter().enumerate() {
+                    let counts = self.branch(addr);
+                    let directions = [counts.map(|c| c.taken), counts.map(|c| c.not_taken)];
+                    for (branch, count) in directions.iter().enumerate() {
+                        match count {
+                            Some(count) => writeln!(w, "BRDA:{},{},{},{}", line, block, branch, count)?,
+                            None => writeln!(w, "BRDA:{},{},{},-", line, block, branch)?,
+                        }
+                        found += 1;
+                        hit += count.is_some_and(|c| c != 0) as usize;
+                    }
+                }
+            }
+            writeln!(w, "BRF:{}", found)?;
+            writeln!(w, "BRH:{}", hit)?;
+
+            for (line, (count, _)) in file.iter() {
+                writeln!(w, "DA:{},{}", line, count)?;
+            }
+            writeln!(w, "LF:{}", file.len())?;
+            writeln!(w, "LH:{}", file.values().filter(|(count, _)| *count != 0).count())?;
+            writeln!(w, "end_of_record")?;
+        }
+        w.flush()
+    }
+}
+
+impl<T> Cpu<T>
+where
+    T: Address,
+{
+    pub fn set_coverage(&mut self, coverage: Option<Coverage>) -> Option<Coverage> {
+        std::mem::replace(&mut self.coverage, coverage)
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    pub fn coverage_mut(&mut self) -> Option<&mut Coverage> {
+        self.coverage.as_mut()
+    }
+
+    pub(crate) fn cover_instruction(&mut self, pc: u32) {
+        if let Some(coverage) = &mut self.coverage {
+            coverage.execute(pc);
+        }
+    }
+
+    // Called by conditional branches after fetching their target, before jumping
+    pub(crate) fn cover_branch(&mut self, taken: bool) {
+        if let Some(coverage) = &mut self.coverage {
+            coverage.take_branch(self.xs[R_PC].wrapping_sub(5), taken);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{LineEntry, Object, Section, SectionKind};
+    use crate::SimpleAddress;
+
+    // 0x00: li x0, 4          line 1
+    // 0x05: sub x0, x1        line 2 (x1 = 1, and the first sub also borrows as carry is clear)
+    // 0x07: bnz 0x05          line 3
+    // 0x0c: bo 0x00           line 4
+    // 0x11: nop               line 5
+    const PROGRAM: [u8; 18] = [
+        0x40, 4, 0, 0, 0, 0x81, 0x01, 0x08, 0x05, 0, 0, 0, 0x01, 0, 0, 0, 0, 0x3f,
+    ];
+
+    fn lines() -> LineTable {
+        let offsets = [0, 5, 7, 0x0c, 0x11];
+        let object = Object {
+            sections: vec![Section::new(".text", SectionKind::Text, PROGRAM.to_vec())],
+            files: vec!["loop.s".to_string()],
+            lines: offsets
+                .iter()
+                .enumerate()
+                .map(|(i, &offset)| LineEntry { section: 0, offset, file: 0, line: i as u32 + 1 })
+                .collect(),
+            ..Object::default()
+        };
+        LineTable::from_object(&object)
+    }
+
+    fn covered(steps: u64) -> Cpu<SimpleAddress> {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[..PROGRAM.len()].copy_from_slice(&PROGRAM);
+        cpu.xs[1] = 1;
+        cpu.set_coverage(Some(Coverage::new()));
+        cpu.run(steps);
+        cpu
+    }
+
+    #[test]
+    fn coverage_counts() {
+        let cpu = covered(8);
+        let coverage = cpu.coverage().unwrap();
+        assert_eq!(coverage.hits(0x05), 3);
+        assert_eq!(coverage.branch(0x07), Some(BranchCounts { taken: 2, not_taken: 1 }));
+        assert_eq!(coverage.branch(0x0c), Some(BranchCounts { taken: 0, not_taken: 1 }));
+        assert_eq!(coverage.instructions(), 4);
+        assert_eq!(coverage.branches(), (2, 1));
+    }
+
+    #[test]
+    fn coverage_lcov() {
+        let cpu = covered(8);
+        let mut lcov = vec![];
+        cpu.coverage()
+            .unwrap()
+            .write_lcov(&mut lcov, &lines(), |a| PROGRAM.get(a as usize).copied())
+            .unwrap();
+        let lcov = String::from_utf8(lcov).unwrap();
+        assert_eq!(
+            lcov,
+            "TN:\nSF:loop.s\n\
+             BRDA:3,0,0,2\nBRDA:3,0,1,1\nBRDA:4,0,0,0\nBRDA:4,0,1,1\nBRF:4\nBRH:3\n\
+             DA:1,1\nDA:2,3\nDA:3,3\nDA:4,1\nDA:5,0\nLF:5\nLH:4\nend_of_record\n"
+        );
+
+        // Branches that never executed are reported as such
+        let cpu = covered(1);
+        let mut lcov = vec![];
+        cpu.coverage()
+            .unwrap()
+            .write_lcov(&mut lcov, &lines(), |a| PROGRAM.get(a as usize).copied())
+            .unwrap();
+        assert!(String::from_utf8(lcov).unwrap().contains("BRDA:3,0,0,-\nBRDA:3,0,1,-\n"));
+    }
+}
@@ -0,0 +1,288 @@
+// Execution breakpoints and memory watchpoints
+
+use crate::{Address, Cpu, InvalidMemoryAccess, EXEC, R_PC};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Space {
+    Virtual,
+    Physical,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Trigger {
+    // Stops before executing the instruction at addr
+    Breakpoint { space: Space, addr: u32 },
+
+    // Stops after the instruction that accessed a byte in start..start + len
+    Watchpoint { space: Space, start: u32, len: u32, access: Access },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StopReason {
+    Breakpoint { id: usize, addr: u32 },
+    Watchpoint { id: usize, addr: u32, paddr: u32, value: u8, write: bool },
+    StepLimit,
+
+    // A step queued a nonmaskable interrupt for a fault, with pc at the start of the step and the
+    // failed access if it was raised for one
+    Fault { interrupt: u32, pc: u32, cause: Option<InvalidMemoryAccess> },
+
+    // Reverse execution reached the oldest recorded step
+    HistoryExhausted,
+}
+
+impl std::fmt::Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            StopReason::Breakpoint { id, addr } => write!(f, "breakpoint {} hit at {:#010x}", id, addr),
+            StopReason::Watchpoint { id, addr, paddr, value, write } => write!(
+                f,
+                "watchpoint {} {} {:#04x} at {:#010x} (physical {:#010x})",
+                id,
+                if *write { "wrote" } else { "read" },
+                value,
+                addr,
+                paddr
+            ),
+            StopReason::StepLimit => write!(f, "step limit reached"),
+            StopReason::Fault { interrupt, pc, .. } => {
+                write!(f, "fault {:#x} at {:#010x}", interrupt & 0x7fffffff, pc)
+            }
+            StopReason::HistoryExhausted => write!(f, "reached the start of the recorded history"),
+        }
+    }
+}
+
+impl Trigger {
+    fn watches(&self, addr: u32, paddr: u32, write: bool) -> bool {
+        match *self {
+            Trigger::Watchpoint { space, start, len, access } => {
+                let addr = if space == Space::Virtual { addr } else { paddr };
+                let matches_access = match access {
+                    Access::Read => !write,
+                    Access::Write => write,
+                    Access::ReadWrite => true,
+                };
+                matches_access && addr.wrapping_sub(start) < len
+            }
+
+            Trigger::Breakpoint { .. } => false,
+        }
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Triggers {
+    triggers: Vec<Option<Trigger>>,
+
+    // Number of watchpoints set, so that memory accesses can skip the search when there are none
+    watchpoints: usize,
+
+    // First watchpoint hit during the current instruction
+    hit: Option<StopReason>,
+
+    // Nonmaskable interrupt the current instruction raised, and the failed access it was raised
+    // for if any
+    raised: Option<u32>,
+    fault: Option<InvalidMemoryAccess>,
+}
+
+impl Triggers {
+    // Whether any memory access can trigger a watchpoint
+    pub(crate) fn has_watchpoints(&self) -> bool {
+        self.watchpoints != 0
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.triggers.iter().all(Option::is_none)
+    }
+
+    pub(crate) fn check_access(&mut self, addr: u32, paddr: u32, value: u8, write: bool) {
+        if self.watchpoints == 0 || self.hit.is_some() {
+            return;
+        }
+
+        if let Some(id) = self.watching(addr, paddr, write) {
+            self.hit = Some(StopReason::Watchpoint { id, addr, paddr, value, write });
+        }
+    }
+
+    pub(crate) fn fault(&mut self, e: InvalidMemoryAccess) {
+        self.fault = Some(e);
+    }
+
+    pub(crate) fn nmi(&mut self, interrupt: u32) {
+        self.raised = Some(interrupt);
+    }
+
+    // The fault stop for the nonmaskable interrupt raised since the last call, if any, for an
+    // instruction at pc
+    pub(crate) fn take_fault(&mut self, pc: u32) -> Option<StopReason> {
+        let cause = self.fault.take();
+        self.raised.take().map(|interrupt| StopReason::Fault { interrupt, pc, cause })
+    }
+
+    pub(crate) fn watching(&self, addr: u32, paddr: u32, write: bool) -> Option<usize> {
+        if self.watchpoints == 0 {
+            return None;
+        }
+        self.triggers.iter().position(|t| t.is_some_and(|t| t.watches(addr, paddr, write)))
+    }
+}
+
+impl<T> Cpu<T>
+where
+    T: Address,
+{
+    pub fn add_trigger(&mut self, trigger: Trigger) -> usize {
+        if let Trigger::Watchpoint { .. } = trigger {
+            self.triggers.watchpoints += 1;
+        }
+
+        match self.triggers.triggers.iter().position(Option::is_none) {
+            Some(id) => {
+                self.triggers.triggers[id] = Some(trigger);
+                id
+            }
+
+            None => {
+                self.triggers.triggers.push(Some(trigger));
+                self.triggers.triggers.len() - 1
+            }
+        }
+    }
+
+    pub fn remove_trigger(&mut self, id: usize) -> Option<Trigger> {
+        let trigger = self.triggers.triggers.get_mut(id)?.take();
+        if let Some(Trigger::Watchpoint { .. }) = trigger {
+            self.triggers.watchpoints -= 1;
+        }
+        trigger
+    }
+
+    pub fn triggers(&self) -> impl Iterator<Item = (usize, &Trigger)> {
+        self.triggers.triggers.iter().enumerate().filter_map(|(id, t)| Some((id, t.as_ref()?)))
+    }
+
+    pub(crate) fn breakpoint_hit(&mut self) -> Option<StopReason> {
+        let pc = self.xs[R_PC];
+        let paddr = self.check_memory(pc, EXEC).ok();
+        let id = self.triggers.triggers.iter().position(|t| match *t {
+            Some(Trigger::Breakpoint { space: Space::Virtual, addr }) => addr == pc,
+            Some(Trigger::Breakpoint { space: Space::Physical, addr }) => Some(addr) == paddr,
+            _ => false,
+        })?;
+        Some(StopReason::Breakpoint { id, addr: pc })
+    }
+
+    // Executes at most limit instructions, stopping early at a breakpoint or after an instruction
+    // that triggered a watchpoint. A breakpoint at the current pc does not stop the first step, so
+    // that execution can be resumed from it.
+    pub fn run(&mut self, limit: u64) -> StopReason {
+        self.triggers.hit = None;
+        self.triggers.take_fault(0);
+        if self.blocks_usable() {
+            return self.run_blocks(limit);
+        }
+
+        for i in 0..limit {
+            if i != 0 {
+                if let Some(reason) = self.breakpoint_hit() {
+                    return reason;
+                }
+            }
+
+            let pc = self.xs[R_PC];
+            self.step();
+            let fault = self.triggers.take_fault(pc);
+            if let Some(reason) = self.triggers.hit.take() {
+                return reason;
+            }
+            if let Some(reason) = fault {
+                return reason;
+            }
+        }
+
+        StopReason::StepLimit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flags::Flag;
+    use crate::SimpleAddress;
+
+    // li x0, 5; sw x0, [0x1000]; lw x1, [0x1000]; add x0, x1
+    const PROGRAM: [u8; 17] = [
+        0x40, 5, 0, 0, 0, 0xc0, 0x00, 0x10, 0, 0, 0x61, 0x00, 0x10, 0, 0, 0x80, 0x01,
+    ];
+
+    fn cpu() -> Cpu<SimpleAddress> {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[..PROGRAM.len()].copy_from_slice(&PROGRAM);
+        cpu
+    }
+
+    #[test]
+    fn debug_breakpoints() {
+        let mut cpu = cpu();
+        let id = cpu.add_trigger(Trigger::Breakpoint { space: Space::Virtual, addr: 0x0f });
+        assert_eq!(cpu.run(100), StopReason::Breakpoint { id, addr: 0x0f });
+        assert_eq!(cpu.xs[1], 5);
+
+        // Resuming from the breakpoint executes it
+        assert_eq!(cpu.run(1), StopReason::StepLimit);
+        assert_eq!(cpu.xs[0], 10);
+
+        cpu.remove_trigger(id);
+        cpu.xs[R_PC] = 0;
+        assert_eq!(cpu.run(4), StopReason::StepLimit);
+    }
+
+    #[test]
+    fn debug_watchpoints() {
+        let mut cpu = cpu();
+        let id = cpu.add_trigger(Trigger::Watchpoint {
+            space: Space::Physical,
+            start: 0x1002,
+            len: 2,
+            access: Access::Read,
+        });
+        cpu.add_trigger(Trigger::Watchpoint {
+            space: Space::Virtual,
+            start: 0x1000,
+            len: 1,
+            access: Access::Write,
+        });
+
+        // The store triggers the write watchpoint first
+        let reason = cpu.run(100);
+        assert_eq!(reason, StopReason::Watchpoint { id: 1, addr: 0x1000, paddr: 0x1000, value: 5, write: true });
+        assert_eq!(cpu.xs[R_PC], 0x0a);
+
+        // Stores to the range watched for reads do not trigger it
+        let reason = cpu.run(100);
+        assert_eq!(reason, StopReason::Watchpoint { id, addr: 0x1002, paddr: 0x1002, value: 0, write: false });
+        assert_eq!(cpu.xs[R_PC], 0x0f);
+    }
+
+    #[test]
+    fn debug_faults() {
+        let mut cpu = cpu();
+        cpu.run(1);
+
+        // Paging on with an empty page table makes the next fetch fault
+        cpu.set_flag(Flag::MemmapEnable, true);
+        cpu.memmap = 0x8000;
+        let cause = Some(InvalidMemoryAccess::UsedFreePage);
+        assert_eq!(cpu.run(100), StopReason::Fault { interrupt: 0x80000000, pc: 0x05, cause });
+    }
+}
@@ -0,0 +1,499 @@
+// Instruction decoding and the predecoded instruction cache
+//
+// Instructions are decoded into an `Op` with their immediate operands already assembled, and then
+// executed. Decoding fetches one byte at a time through `exec`, translating and reading each byte.
+// With the decode cache enabled, instructions that were decoded before are instead looked up by
+// the physical address of their first byte, which takes a single translation for the whole
+// instruction. Only instructions that lie within one page of ordinary (not device) memory are
+// cached, so the translation of the first byte covers all of them and fetching them has no other
+// side effects; the cycles, TLB and trace output are the same as decoding them again. The cache
+// is off by default.
+//
+// Writes through the cpu invalidate the entries they overlap. The whole cache is flushed when the
+// page table or paging state changes, when memory may have been changed by anything other than
+// the cpu (`addressing_mut`, loading an image, restoring a snapshot, reverse execution or the gdb
+// stub) and after the backend runs an event, in case a device wrote to memory.
+
+use crate::flags::Flag;
+use crate::float::Rounding;
+use crate::timing::OpClass;
+use crate::{Address, Cpu, InvalidMemoryAccess, EXEC, PAGE_SIZE, R_PC};
+
+// Length of the longest instruction
+pub const MAX_INSTRUCTION_LEN: u32 = 5;
+
+const CACHE_ENTRIES: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Op {
+    // Flag and target address
+    BranchTrue(Flag, u32),
+    BranchFalse(Flag, u32),
+
+    SetCarry(bool),
+    SetMemmapEnable(bool),
+    SetInterruptEnable(bool),
+    EnterUserRing,
+    Call(u32),
+    Ret,
+    Nop,
+
+    // Register and 32 bit data or address
+    LoadLitInt(usize, u32),
+    LoadLitFloat(usize, u32),
+    LoadInt(usize, u32),
+    LoadFloat(usize, u32),
+    StoreInt(usize, u32),
+    StoreShort(usize, u32),
+    StoreByte(usize, u32),
+    StoreFloat(usize, u32),
+
+    // Low six bits of the opcode and both register arguments
+    Registers(u8, usize, usize),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    paddr: u32,
+    generation: u32,
+    op: Op,
+    class: OpClass,
+    len: u8,
+    bytes: [u8; MAX_INSTRUCTION_LEN as usize],
+}
+
+// Direct mapped by physical address. Entries from before the last flush have an older generation.
+pub(crate) struct DecodeCache {
+    entries: Vec<Option<Entry>>,
+    generation: u32,
+
+    // Page table address and whether paging was enabled when the entries were decoded
+    context: Option<(u32, bool)>,
+
+    hits: u64,
+    misses: u64,
+}
+
+impl Default for DecodeCache {
+    fn default() -> DecodeCache {
+        DecodeCache { entries: vec![None; CACHE_ENTRIES], generation: 0, context: None, hits: 0, misses: 0 }
+    }
+}
+
+impl DecodeCache {
+    fn get(&self, paddr: u32) -> Option<&Entry> {
+        self.entries[paddr as usize % CACHE_ENTRIES]
+            .as_ref()
+            .filter(|e| e.paddr == paddr && e.generation == self.generation)
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        self.entries[entry.paddr as usize % CACHE_ENTRIES] = Some(Entry { generation: self.generation, ..entry });
+    }
+
+    // Invalidates any instruction containing the byte at paddr
+    pub(crate) fn invalidate(&mut self, paddr: u32) {
+        for i in 0..MAX_INSTRUCTION_LEN {
+            let start = paddr.wrapping_sub(i);
+            let slot = &mut self.entries[start as usize % CACHE_ENTRIES];
+            if slot.is_some_and(|e| e.paddr == start && (e.len as u32) > i) {
+                *slot = None;
+            }
+        }
+    }
+
+    pub(crate) fn flush(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
+        if self.generation == 0 {
+            self.entries.iter_mut().for_each(|e| *e = None);
+        }
+    }
+}
+
+// Decodes the instruction starting with opcode, taking its operand bytes from next
+pub(crate) fn decode_op<E, F>(opcode: u8, mut next: F) -> Result<Op, E>
+where
+    F: FnMut() -> Result<u8, E>,
+{
+    Ok(match opcode & 0xc0 {
+        // 0b00xxxxxx -> no arguments
+        0x00 => {
+            match opcode & 0x3f {
+                // Branches
+                // Jumping is just mov x13, addr
+                // Takes in 32 bit data as an argument
+                0x00 => Op::BranchTrue(Flag::Zero, next_u32(&mut next)?),
+                0x01 => Op::BranchTrue(Flag::Overflow, next_u32(&mut next)?),
+                0x02 => Op::BranchTrue(Flag::Carry, next_u32(&mut next)?),
+                0x03 => Op::BranchTrue(Flag::Negative, next_u32(&mut next)?),
+                0x04 => Op::BranchTrue(Flag::Parity, next_u32(&mut next)?),
+                0x05 => Op::BranchTrue(Flag::NaN, next_u32(&mut next)?),
+                0x06 => Op::BranchTrue(Flag::Infinite, next_u32(&mut next)?),
+                0x07 => Op::BranchTrue(Flag::MemmapEnable, next_u32(&mut next)?),
+                0x08 => Op::BranchFalse(Flag::Zero, next_u32(&mut next)?),
+                0x09 => Op::BranchFalse(Flag::Overflow, next_u32(&mut next)?),
+                0x0a => Op::BranchFalse(Flag::Carry, next_u32(&mut next)?),
+                0x0b => Op::BranchFalse(Flag::Negative, next_u32(&mut next)?),
+                0x0c => Op::BranchFalse(Flag::Parity, next_u32(&mut next)?),
+                0x0d => Op::BranchFalse(Flag::NaN, next_u32(&mut next)?),
+                0x0e => Op::BranchFalse(Flag::Infinite, next_u32(&mut next)?),
+                0x0f => Op::BranchFalse(Flag::MemmapEnable, next_u32(&mut next)?),
+
+                // Setting and clearing flags
+                0x10 => Op::SetCarry(false),
+                0x11 => Op::SetCarry(true),
+                0x12 => Op::SetMemmapEnable(false),
+                0x13 => Op::SetMemmapEnable(true),
+                0x14 => Op::SetInterruptEnable(false),
+                0x15 => Op::SetInterruptEnable(true),
+                0x17 => Op::EnterUserRing,
+
+                0x18 => Op::Call(next_u32(&mut next)?),
+                0x19 => Op::Ret,
+
+                _ => Op::Nop,
+            }
+        }
+
+        // 0b01xxyyyy data -> one register argument and 32 bit data
+        0x40 => {
+            let data = opcode as usize & 0x0f;
+            match opcode & 0x30 {
+                // Load literal
+                0x00 => Op::LoadLitInt(data, next_u32(&mut next)?),
+                0x10 => Op::LoadLitFloat(data, next_u32(&mut next)?),
+
+                // Load memory address
+                0x20 => Op::LoadInt(data, next_u32(&mut next)?),
+                0x30 => Op::LoadFloat(data, next_u32(&mut next)?),
+
+                _ => unreachable!("nya :("),
+            }
+        }
+
+        // 0b10xxxxxx 0byyyyzzzz -> two register arguments
+        0x80 => {
+            let data = next()?;
+            Op::Registers(opcode & 0x3f, ((data & 0xf0) >> 4) as usize, (data & 0x0f) as usize)
+        }
+
+        // 0b11xxyyyy data -> one register argument and 32 bit data
+        0xc0 => {
+            let data = opcode as usize & 0x0f;
+            match opcode & 0x30 {
+                // Store at memory address
+                0x00 => Op::StoreInt(data, next_u32(&mut next)?),
+                0x10 => Op::StoreShort(data, next_u32(&mut next)?),
+                0x20 => Op::StoreByte(data, next_u32(&mut next)?),
+                0x30 => Op::StoreFloat(data, next_u32(&mut next)?),
+
+                _ => unreachable!("nya :("),
+            }
+        }
+
+        _ => unreachable!("nya :("),
+    })
+}
+
+pub(crate) fn next_u32<E, F>(next: &mut F) -> Result<u32, E>
+where
+    F: FnMut() -> Result<u8, E>,
+{
+    Ok((next()? as u32) | (next()? as u32) << 8 | (next()? as u32) << 16 | (next()? as u32) << 24)
+}
+
+impl<T> Cpu<T>
+where
+    T: Address,
+{
+    pub fn decode_cache_enabled(&self) -> bool {
+        self.decode_cache.is_some()
+    }
+
+    // Enables or disables the decode cache. Disabling it drops every entry.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        if enabled != self.decode_cache.is_some() {
+            self.decode_cache = if enabled { Some(Box::default()) } else { None };
+        }
+    }
+
+    // Number of instructions fetched from the decode cache and decoded into it
+    pub fn decode_cache_stats(&self) -> Option<(u64, u64)> {
+        self.decode_cache.as_ref().map(|c| (c.hits, c.misses))
+    }
+
+    // Drops every decoded instruction and translated block. Backends that change memory on their
+    // own other than in run_event should have this called afterwards.
+    pub fn flush_decode_cache(&mut self) {
+        if let Some(cache) = &mut self.decode_cache {
+            cache.flush();
+        }
+        if let Some(blocks) = &mut self.blocks {
+            blocks.flush();
+        }
+    }
+
+    // Drops the decoded instructions and translated blocks containing any of the len bytes at
+    // paddr, which are in one page
+    pub(crate) fn invalidate_code(&mut self, paddr: u32, len: u32) {
+        if let Some(cache) = &mut self.decode_cache {
+            for i in 0..len {
+                cache.invalidate(paddr + i);
+            }
+        }
+        if let Some(blocks) = &mut self.blocks {
+            blocks.invalidate(paddr, len);
+        }
+    }
+
+    // Fetches and decodes the instruction at pc one byte at a time, leaving pc after it
+    fn decode(&mut self) -> Result<(Op, OpClass), InvalidMemoryAccess> {
+        let opcode = self.exec()?;
+        let class = OpClass::of(opcode);
+        self.charge(self.costs.cost(class));
+        Ok((decode_op(opcode, || self.exec())?, class))
+    }
+
+    // Fetches the instruction at pc, through the decode cache if it is enabled, leaving pc after it
+    pub(crate) fn fetch(&mut self) -> Result<Op, InvalidMemoryAccess> {
+        if self.decode_cache.is_none() {
+            return self.decode().map(|(op, _)| op);
+        }
+
+        // A fault on the first byte is left to decode to raise
+        let pc = self.xs[R_PC];
+        self.charge_translation(pc);
+        let paddr = match self.check_memory(pc, EXEC) {
+            Ok(paddr) => paddr,
+            Err(_) => return self.decode().map(|(op, _)| op),
+        };
+
+        let context = (self.memmap, self.get_flag(Flag::MemmapEnable));
+        let cache = self.decode_cache.as_mut().unwrap();
+        if cache.context != Some(context) {
+            cache.flush();
+            cache.context = Some(context);
+        }
+
+        if let Some(&entry) = cache.get(paddr) {
+            cache.hits += 1;
+            self.charge(self.costs.cost(entry.class));
+            self.xs[R_PC] = pc.wrapping_add(entry.len as u32);
+            if let Some(tracer) = &mut self.tracer {
+                for &byte in entry.bytes[..entry.len as usize].iter() {
+                    tracer.fetch(byte);
+                }
+            }
+            return Ok(entry.op);
+        }
+
+        let (op, class) = self.decode()?;
+        let len = self.xs[R_PC].wrapping_sub(pc);
+        let cacheable = pc % PAGE_SIZE + len <= PAGE_SIZE
+            && (0..len).all(|i| !self.addressing.is_device(paddr.wrapping_add(i)));
+        let mut bytes = [0; MAX_INSTRUCTION_LEN as usize];
+        if cacheable && self.addressing.read_slice(paddr, &mut bytes[..len as usize]).is_ok() {
+            let cache = self.decode_cache.as_mut().unwrap();
+            cache.misses += 1;
+            cache.insert(Entry { paddr, generation: 0, op, class, len: len as u8, bytes });
+        }
+        Ok(op)
+    }
+
+    pub(crate) fn execute(&mut self, op: Op) -> Result<(), InvalidMemoryAccess> {
+        match op {
+            Op::BranchTrue(flag, addr) => self.branch_true(flag, addr),
+            Op::BranchFalse(flag, addr) => self.branch_false(flag, addr),
+
+            Op::SetCarry(val) => self.set_carry(val),
+            Op::SetMemmapEnable(val) => self.set_memmap_enable(val)?,
+            Op::SetInterruptEnable(val) => self.set_interrupt_enable(val)?,
+            Op::EnterUserRing => self.set_user_ring(true)?,
+            Op::Call(addr) => self.call(addr)?,
+            Op::Ret => self.ret()?,
+            Op::Nop => (),
+
+            Op::LoadLitInt(x0, data) => self.load_lit_int(x0, data),
+            Op::LoadLitFloat(f0, data) => self.load_lit_float(f0, data),
+            Op::LoadInt(x0, addr) => self.load_int(x0, addr)?,
+            Op::LoadFloat(f0, addr) => self.load_float(f0, addr)?,
+            Op::StoreInt(x0, addr) => self.store_int(x0, addr)?,
+            Op::StoreShort(x0, addr) => self.store_short(x0, addr)?,
+            Op::StoreByte(x0, addr) => self.store_byte(x0, addr)?,
+            Op::StoreFloat(f0, addr) => self.store_float(f0, addr)?,
+
+            Op::Registers(opcode, fst, snd) => match opcode {
+                // Integer arithmetic
+                0x00 => self.iadd(fst, snd),
+                0x01 => self.isub(fst, snd),
+                0x02 => self.imul(fst, snd),
+                0x03 => self.idiv(fst, snd),
+                0x04 => self.imod(fst, snd),
+
+                // Floating point arithmetic
+                0x05 => self.fadd(fst, snd),
+                0x06 => self.fsub(fst, snd),
+                0x07 => self.fmul(fst, snd),
+                0x08 => self.fdiv(fst, snd),
+
+                // Bitwise operations
+                0x09 => self.bsl(fst, snd),
+                0x0a => self.bsr(fst, snd),
+                0x0b => self.and(fst, snd),
+                0x0c => self.or(fst, snd),
+                0x0d => self.xor(fst, snd),
+
+                // Move and transmute operations
+                0x0e => self.move_int(fst, snd),
+                0x0f => self.move_float(fst, snd),
+                0x10 => self.move_int_float(fst, snd, Rounding::TowardZero),
+                0x11 => self.move_float_int(fst, snd),
+                0x12 => self.transmute_int_float(fst, snd),
+                0x13 => self.transmute_float_int(fst, snd),
+
+                // Load operations
+                0x14 => self.load_indirect_int(fst, snd)?,
+                0x15 => self.load_indirect_float(fst, snd)?,
+
+                // Store operations
+                0x16 => self.store_indirect_int(fst, snd)?,
+                0x17 => self.store_indirect_short(fst, snd)?,
+                0x18 => self.store_indirect_byte(fst, snd)?,
+                0x19 => self.store_indirect_float(fst, snd)?,
+
+                // Privileged move operations
+                0x1a => self.privileged_move(fst, snd)?,
+                0x1b => self.unprivileged_move(fst, snd)?,
+
+                // Floating point comparison, sign, square root and minimum and maximum
+                0x1c => self.fcmp(fst, snd),
+                0x1d => self.fneg(fst, snd),
+                0x1e => self.fabs(fst, snd),
+                0x1f => self.fsqrt(fst, snd),
+                0x20 => self.fmin(fst, snd),
+                0x21 => self.fmax(fst, snd),
+
+                // Rounding to integral values and to integers
+                0x22 => self.fround(fst, snd, Rounding::NearestEven),
+                0x23 => self.fround(fst, snd, Rounding::Down),
+                0x24 => self.fround(fst, snd, Rounding::Up),
+                0x25 => self.fround(fst, snd, Rounding::TowardZero),
+                0x26 => self.move_int_float(fst, snd, Rounding::NearestEven),
+                0x27 => self.move_int_float(fst, snd, Rounding::Down),
+                0x28 => self.move_int_float(fst, snd, Rounding::Up),
+
+                _ => (),
+            },
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SimpleAddress, R_SP};
+
+    fn cpu(program: &[u8]) -> Cpu<SimpleAddress> {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[..program.len()].copy_from_slice(program);
+        cpu.set_decode_cache(true);
+        cpu
+    }
+
+    #[test]
+    fn decode_cache_disabled_by_default() {
+        // li x0, 7, changed into li x0, 9 behind the cpu's back as a device would
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[..5].copy_from_slice(&[0x40, 7, 0, 0, 0]);
+        assert!(!cpu.decode_cache_enabled());
+        cpu.run(1);
+        assert_eq!(cpu.xs[0], 7);
+
+        cpu.addressing.memory[1] = 9;
+        cpu.xs[R_PC] = 0;
+        cpu.run(1);
+        assert_eq!(cpu.xs[0], 9);
+        assert_eq!(cpu.decode_cache_stats(), None);
+    }
+
+    #[test]
+    fn decode_cache_hits() {
+        // li x0, 0; li x1, 1; loop: add x0, x1; bnc loop
+        let program = [0x40, 0, 0, 0, 0, 0x41, 1, 0, 0, 0, 0x80, 0x01, 0x0a, 0x0a, 0, 0, 0];
+        let mut cached = cpu(&program);
+        let mut plain = cpu(&program);
+        plain.set_decode_cache(false);
+        cached.run(1000);
+        plain.run(1000);
+
+        assert_eq!(cached.xs, plain.xs);
+        assert_eq!(cached.flags, plain.flags);
+        assert_eq!(cached.cycles(), plain.cycles());
+        assert_eq!(cached.decode_cache_stats(), Some((996, 4)));
+        assert_eq!(plain.decode_cache_stats(), None);
+    }
+
+    #[test]
+    fn decode_cache_self_modifying() {
+        // li x0, 7; li x1, 0x41; sb x1, [0x01]; bnz 0x00
+        // The store turns the first instruction into li x0, 0x41 for its second run
+        let mut cpu = cpu(&[0x40, 7, 0, 0, 0, 0x41, 0x41, 0, 0, 0, 0xe1, 0x01, 0, 0, 0, 0x08, 0, 0, 0, 0]);
+        cpu.run(5);
+        assert_eq!(cpu.xs[0], 0x41);
+
+        // Changing memory from outside the cpu is seen too
+        cpu.xs[R_PC] = 0;
+        cpu.run(1);
+        cpu.addressing_mut().write(0x01, 9).unwrap();
+        cpu.xs[R_PC] = 0;
+        cpu.run(1);
+        assert_eq!(cpu.xs[0], 9);
+    }
+
+    #[test]
+    fn decode_cache_paging() {
+        // li x0, 1 at physical 0x10000 and li x0, 2 at physical 0x20000
+        let mut cpu = cpu(&[]);
+        cpu.addressing.memory[0x10000..0x10005].copy_from_slice(&[0x40, 1, 0, 0, 0]);
+        cpu.addressing.memory[0x20000..0x20005].copy_from_slice(&[0x40, 2, 0, 0, 0]);
+
+        // Two page tables mapping virtual page 0 to each of them
+        cpu.addressing.memory[0x30000..0x30004].copy_from_slice(&0x40000u32.to_le_bytes());
+        cpu.addressing.memory[0x40000..0x40004].copy_from_slice(&0xd0010000u32.to_le_bytes());
+        cpu.addressing.memory[0x50000..0x50004].copy_from_slice(&0x60000u32.to_le_bytes());
+        cpu.addressing.memory[0x60000..0x60004].copy_from_slice(&0xd0020000u32.to_le_bytes());
+        cpu.set_flag(Flag::MemmapEnable, true);
+        cpu.xs[R_SP] = 0x8000;
+
+        cpu.memmap = 0x30000;
+        cpu.run(1);
+        assert_eq!(cpu.xs[0], 1);
+
+        cpu.memmap = 0x50000;
+        cpu.xs[R_PC] = 0;
+        cpu.run(1);
+        assert_eq!(cpu.xs[0], 2);
+
+        // Faults on the first byte are still raised
+        cpu.memmap = 0x70000;
+        cpu.xs[R_PC] = 0;
+        cpu.run(1);
+        assert_eq!(cpu.interrupt_queue.back(), Some(&0x80000000));
+    }
+
+    #[test]
+    fn decode_cache_invalidate() {
+        let mut cache = DecodeCache::default();
+        let entry = Entry { paddr: 0x100, generation: 0, op: Op::Nop, class: OpClass::Move, len: 5, bytes: [0; 5] };
+        cache.insert(entry);
+        cache.invalidate(0x105);
+        assert!(cache.get(0x100).is_some());
+        cache.invalidate(0x104);
+        assert!(cache.get(0x100).is_none());
+
+        cache.insert(entry);
+        cache.flush();
+        assert!(cache.get(0x100).is_none());
+    }
+}
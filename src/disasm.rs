@@ -0,0 +1,237 @@
+// Disassembler for the instruction encoding decoded by `Cpu::decode_instruction`
+
+use crate::symbols::Symbols;
+
+const BRANCH_FLAGS: [&str; 8] = ["z", "v", "c", "n", "p", "nan", "inf", "mm"];
+
+const SPECIAL_REGISTERS: [&str; 14] = [
+    "flags", "memmap", "mask", "cycles", "cycleh", "instret", "perfcyc", "walks", "faults", "ints", "branches",
+    "perfh", "perfctl", "fpcr",
+];
+
+pub struct Instruction {
+    pub addr: u32,
+    pub len: u32,
+    pub text: String,
+
+    // Address operand of branches, calls, loads and stores
+    pub target: Option<u32>,
+}
+
+impl Instruction {
+    // The instruction text followed by the symbol its target is in, like `call 0x0000000b <inc>`
+    pub fn annotate(&self, symbols: &Symbols) -> String {
+        match self.target.and_then(|t| symbols.describe(t)) {
+            Some(symbol) => format!("{} <{}>", self.text, symbol),
+            None => self.text.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for Instruction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "{}", self.text)
+    }
+}
+
+pub fn special_register_name(p: usize) -> String {
+    match SPECIAL_REGISTERS.get(p) {
+        Some(name) => name.to_string(),
+        None => format!("s{}", p),
+    }
+}
+
+// Disassembles the instruction at `addr`, fetching bytes with `fetch`. Returns None if any byte
+// of the instruction could not be fetched.
+pub fn disassemble<F>(addr: u32, mut fetch: F) -> Option<Instruction>
+where
+    F: FnMut(u32) -> Option<u8>,
+{
+    let mut len = 0;
+    let mut next = || {
+        let byte = fetch(addr.wrapping_add(len));
+        len += 1;
+        byte
+    };
+
+    let opcode = next()?;
+    let mut target = None;
+    let text = match opcode & 0xc0 {
+        0x00 => {
+            match opcode & 0x3f {
+                op @ 0x00..=0x0f => {
+                    let addr = next_u32(&mut next)?;
+                    let negate = if op & 0x08 != 0 { "n" } else { "" };
+                    target = Some(addr);
+                    format!("b{}{} {:#010x}", negate, BRANCH_FLAGS[op as usize & 0x07], addr)
+                }
+
+                0x10 => "clc".to_string(),
+                0x11 => "stc".to_string(),
+                0x12 => "clm".to_string(),
+                0x13 => "stm".to_string(),
+                0x14 => "cli".to_string(),
+                0x15 => "sti".to_string(),
+                0x17 => "user".to_string(),
+
+                0x18 => {
+                    let addr = next_u32(&mut next)?;
+                    target = Some(addr);
+                    format!("call {:#010x}", addr)
+                }
+                0x19 => "ret".to_string(),
+
+                _ => format!("nop {:#04x}", opcode),
+            }
+        }
+
+        0x40 => {
+            let reg = opcode & 0x0f;
+            let data = next_u32(&mut next)?;
+            if opcode & 0x20 != 0 {
+                target = Some(data);
+            }
+            match opcode & 0x30 {
+                0x00 => format!("li x{}, {:#x}", reg, data),
+                0x10 => format!("lf f{}, {:?}", reg, f32::from_bits(data)),
+                0x20 => format!("lw x{}, [{:#010x}]", reg, data),
+                0x30 => format!("lwf f{}, [{:#010x}]", reg, data),
+
+                _ => unreachable!("nya :("),
+            }
+        }
+
+        0x80 => {
+            let data = next()?;
+            let (fst, snd) = ((data & 0xf0) >> 4, data & 0x0f);
+
+            match opcode & 0x3f {
+                0x00 => format!("add x{}, x{}", fst, snd),
+                0x01 => format!("sub x{}, x{}", fst, snd),
+                0x02 => format!("mul x{}, x{}", fst, snd),
+                0x03 => format!("div x{}, x{}", fst, snd),
+                0x04 => format!("mod x{}, x{}", fst, snd),
+
+                0x05 => format!("fadd f{}, f{}", fst, snd),
+                0x06 => format!("fsub f{}, f{}", fst, snd),
+                0x07 => format!("fmul f{}, f{}", fst, snd),
+                0x08 => format!("fdiv f{}, f{}", fst, snd),
+
+                0x09 => format!("bsl x{}, x{}", fst, snd),
+                0x0a => format!("bsr x{}, x{}", fst, snd),
+                0x0b => format!("and x{}, x{}", fst, snd),
+                0x0c => format!("or x{}, x{}", fst, snd),
+                0x0d => format!("xor x{}, x{}", fst, snd),
+
+                0x0e => format!("mov x{}, x{}", fst, snd),
+                0x0f => format!("fmov f{}, f{}", fst, snd),
+                0x10 => format!("ftoi x{}, f{}", fst, snd),
+                0x11 => format!("itof f{}, x{}", fst, snd),
+                0x12 => format!("fbits x{}, f{}", fst, snd),
+                0x13 => format!("ibits f{}, x{}", fst, snd),
+
+                0x14 => format!("lw x{}, [x{}]", fst, snd),
+                0x15 => format!("lwf f{}, [x{}]", fst, snd),
+
+                0x16 => format!("sw x{}, [x{}]", fst, snd),
+                0x17 => format!("sh x{}, [x{}]", fst, snd),
+                0x18 => format!("sb x{}, [x{}]", fst, snd),
+                0x19 => format!("swf f{}, [x{}]", fst, snd),
+
+                0x1a => format!("wsr {}, x{}", special_register_name(snd as usize), fst),
+                0x1b => format!("rsr x{}, {}", snd, special_register_name(fst as usize)),
+
+                0x1c => format!("fcmp f{}, f{}", fst, snd),
+                0x1d => format!("fneg f{}, f{}", fst, snd),
+                0x1e => format!("fabs f{}, f{}", fst, snd),
+                0x1f => format!("fsqrt f{}, f{}", fst, snd),
+                0x20 => format!("fmin f{}, f{}", fst, snd),
+                0x21 => format!("fmax f{}, f{}", fst, snd),
+
+                0x22 => format!("frint f{}, f{}", fst, snd),
+                0x23 => format!("ffloor f{}, f{}", fst, snd),
+                0x24 => format!("fceil f{}, f{}", fst, snd),
+                0x25 => format!("ftrunc f{}, f{}", fst, snd),
+                0x26 => format!("ftoin x{}, f{}", fst, snd),
+                0x27 => format!("ftoid x{}, f{}", fst, snd),
+                0x28 => format!("ftoiu x{}, f{}", fst, snd),
+
+                _ => format!("nop {:#04x}, {:#04x}", opcode, data),
+            }
+        }
+
+        0xc0 => {
+            let reg = opcode & 0x0f;
+            let addr = next_u32(&mut next)?;
+            target = Some(addr);
+            match opcode & 0x30 {
+                0x00 => format!("sw x{}, [{:#010x}]", reg, addr),
+                0x10 => format!("sh x{}, [{:#010x}]", reg, addr),
+                0x20 => format!("sb x{}, [{:#010x}]", reg, addr),
+                0x30 => format!("swf f{}, [{:#010x}]", reg, addr),
+
+                _ => unreachable!("nya :("),
+            }
+        }
+
+        _ => unreachable!("nya :("),
+    };
+
+    Some(Instruction { addr, len, text, target })
+}
+
+fn next_u32<F>(next: &mut F) -> Option<u32>
+where
+    F: FnMut() -> Option<u8>,
+{
+    Some((next()? as u32)
+        | (next()? as u32) << 8
+        | (next()? as u32) << 16
+        | (next()? as u32) << 24)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dis(bytes: &[u8]) -> Option<Instruction> {
+        disassemble(0, |a| bytes.get(a as usize).copied())
+    }
+
+    #[test]
+    fn disasm_branches() {
+        let i = dis(&[0x00, 0x42, 0xaf, 0x00, 0x00]).unwrap();
+        assert_eq!(i.text, "bz 0x0000af42");
+        assert_eq!(i.len, 5);
+        assert_eq!(dis(&[0x0e, 0, 0, 0, 0]).unwrap().text, "bninf 0x00000000");
+        assert_eq!(dis(&[0x18, 0x34, 0x12, 0, 0]).unwrap().text, "call 0x00001234");
+        assert_eq!(dis(&[0x19]).unwrap().text, "ret");
+
+        // Truncated immediate
+        assert!(dis(&[0x00, 0x42]).is_none());
+    }
+
+    #[test]
+    fn disasm_symbols() {
+        let symbols = Symbols::parse_map("0x1000 main\n0x1234 inc\n").unwrap();
+        assert_eq!(dis(&[0x18, 0x34, 0x12, 0, 0]).unwrap().annotate(&symbols), "call 0x00001234 <inc>");
+        assert_eq!(dis(&[0x62, 0x08, 0x10, 0, 0]).unwrap().annotate(&symbols), "lw x2, [0x00001008] <main+0x8>");
+
+        // Immediates are not addresses
+        assert_eq!(dis(&[0x42, 0x08, 0x10, 0, 0]).unwrap().annotate(&symbols), "li x2, 0x1008");
+    }
+
+    #[test]
+    fn disasm_registers() {
+        let i = dis(&[0x80, 0x12]).unwrap();
+        assert_eq!(i.text, "add x1, x2");
+        assert_eq!(i.len, 2);
+        assert_eq!(dis(&[0x9a, 0x31]).unwrap().text, "wsr memmap, x3");
+        assert_eq!(dis(&[0x9b, 0x02]).unwrap().text, "rsr x2, flags");
+        assert_eq!(dis(&[0x9c, 0x45]).unwrap().text, "fcmp f4, f5");
+        assert_eq!(dis(&[0xa3, 0x10]).unwrap().text, "ffloor f1, f0");
+        assert_eq!(dis(&[0xa8, 0x21]).unwrap().text, "ftoiu x2, f1");
+        assert_eq!(dis(&[0x4d, 0xd0, 0xc0, 0xb0, 0xa0]).unwrap().text, "li x13, 0xa0b0c0d0");
+        assert_eq!(dis(&[0xe3, 0x00, 0xff, 0, 0]).unwrap().text, "sb x3, [0x0000ff00]");
+    }
+}
@@ -0,0 +1,355 @@
+// Flag register
+//
+// Flags are named by `Flag` and kept in a `Flags` register, so they can only be tested and changed
+// through their mask: setting a flag to false clears it, and a bit index can't be mistaken for a
+// mask. The bit layout is the one in the README, and is what `rsr`/`wsr`, the debugger and
+// snapshots see:
+//
+//                     GMRFAN PCVZQLLL
+// 10987654 32109876 54321098 76543210
+// 33222222 22221111 111111
+//
+// LLL holds the last interrupt taken.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Flag {
+    InterruptEnable = 3,
+    Zero = 4,
+    Overflow = 5,
+    Carry = 6,
+    Parity = 7,
+    Negative = 8,
+    NaN = 9,
+    Infinite = 10,
+
+    // If set, certain features are locked down until an interrupt occurs
+    UserRing = 11,
+    MemmapEnable = 12,
+    AlignmentCheck = 13,
+}
+
+impl Flag {
+    pub const ALL: [Flag; 11] = [
+        Flag::InterruptEnable,
+        Flag::Zero,
+        Flag::Overflow,
+        Flag::Carry,
+        Flag::Parity,
+        Flag::Negative,
+        Flag::NaN,
+        Flag::Infinite,
+        Flag::UserRing,
+        Flag::MemmapEnable,
+        Flag::AlignmentCheck,
+    ];
+
+    pub fn bit(self) -> u32 {
+        self as u32
+    }
+
+    pub fn mask(self) -> u32 {
+        1 << self.bit()
+    }
+
+    // Label in the README's flag table
+    pub fn label(self) -> char {
+        match self {
+            Flag::InterruptEnable => 'Q',
+            Flag::Zero => 'Z',
+            Flag::Overflow => 'V',
+            Flag::Carry => 'C',
+            Flag::Parity => 'P',
+            Flag::Negative => 'N',
+            Flag::NaN => 'A',
+            Flag::Infinite => 'F',
+            Flag::UserRing => 'R',
+            Flag::MemmapEnable => 'M',
+            Flag::AlignmentCheck => 'G',
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Flag::InterruptEnable => "interrupt enable",
+            Flag::Zero => "zero",
+            Flag::Overflow => "overflow",
+            Flag::Carry => "carry",
+            Flag::Parity => "parity",
+            Flag::Negative => "negative",
+            Flag::NaN => "nan",
+            Flag::Infinite => "infinite",
+            Flag::UserRing => "user ring",
+            Flag::MemmapEnable => "memory map",
+            Flag::AlignmentCheck => "alignment check",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Flags(u32);
+
+impl Flags {
+    pub fn from_bits(bits: u32) -> Flags {
+        Flags(bits)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn get(self, flag: Flag) -> bool {
+        self.0 & flag.mask() != 0
+    }
+
+    pub fn set(&mut self, flag: Flag, val: bool) {
+        self.0 = self.0 & !flag.mask() | (val as u32) << flag.bit();
+    }
+
+    pub fn last_interrupt(self) -> u32 {
+        self.0 & 0x7
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Cpu, SimpleAddress, R_INT, R_PC};
+
+    const INT: &[Flag] = &[Flag::Zero, Flag::Parity, Flag::Negative];
+    const ADD: &[Flag] = &[Flag::Zero, Flag::Overflow, Flag::Carry, Flag::Parity, Flag::Negative];
+    const SHIFT: &[Flag] = &[Flag::Zero, Flag::Carry, Flag::Parity, Flag::Negative];
+    const FLOAT: &[Flag] = &[Flag::Zero, Flag::Negative, Flag::NaN, Flag::Infinite];
+    const TO_INT: &[Flag] = &[Flag::Zero, Flag::Overflow, Flag::Parity, Flag::Negative];
+
+    // An instruction run with x0 and x1, f0 and f1 and the word at 0x100 as operands and the carry
+    // flag as given, along with the flags it writes and the ones of those it sets
+    struct Case {
+        code: &'static [u8],
+        xs: [u32; 2],
+        fs: [f32; 2],
+        mem: u32,
+        carry: bool,
+        writes: &'static [Flag],
+        sets: &'static [Flag],
+    }
+
+    const CASE: Case = Case {
+        code: &[],
+        xs: [0; 2],
+        fs: [0.0; 2],
+        mem: 0,
+        carry: false,
+        writes: &[],
+        sets: &[],
+    };
+
+    #[rustfmt::skip]
+    const CASES: &[Case] = &[
+        // li, lw and lw indirect
+        Case { code: &[0x40, 0, 0, 0, 0], writes: INT, sets: &[Flag::Zero], ..CASE },
+        Case { code: &[0x40, 1, 0, 0, 0x80], writes: INT, sets: &[Flag::Parity, Flag::Negative], ..CASE },
+        Case { code: &[0x60, 0, 1, 0, 0], mem: 0x80000000, writes: INT, sets: &[Flag::Negative], ..CASE },
+        Case { code: &[0x94, 0x01], xs: [7, 0x100], writes: INT, sets: &[Flag::Zero], ..CASE },
+
+        // add and sub, which add the carry in
+        Case { code: &[0x80, 0x01], xs: [u32::MAX, 1], writes: ADD, sets: &[Flag::Zero, Flag::Carry], ..CASE },
+        Case { code: &[0x80, 0x01], xs: [1, 1], carry: true, writes: ADD, sets: &[Flag::Parity], ..CASE },
+        Case {
+            code: &[0x80, 0x01], xs: [0x7fffffff, 1], writes: ADD, sets: &[Flag::Overflow, Flag::Negative], ..CASE
+        },
+        Case {
+            code: &[0x80, 0x01], xs: [0x80000000, 0x80000000], writes: ADD,
+            sets: &[Flag::Zero, Flag::Overflow, Flag::Carry], ..CASE
+        },
+        Case { code: &[0x81, 0x01], xs: [5, 5], carry: true, writes: ADD, sets: &[Flag::Zero, Flag::Carry], ..CASE },
+        Case { code: &[0x81, 0x01], xs: [5, 3], writes: ADD, sets: &[Flag::Carry, Flag::Parity], ..CASE },
+        Case {
+            code: &[0x81, 0x01], xs: [0x80000000, 1], carry: true, writes: ADD,
+            sets: &[Flag::Overflow, Flag::Carry, Flag::Parity], ..CASE
+        },
+
+        // mul, div and mod
+        Case { code: &[0x82, 0x01], xs: [3, 5], writes: INT, sets: &[Flag::Parity], ..CASE },
+        Case { code: &[0x83, 0x01], xs: [7, 8], writes: INT, sets: &[Flag::Zero], ..CASE },
+        Case { code: &[0x84, 0x01], xs: [u32::MAX, 0x80000000], writes: INT, sets: &[Flag::Parity], ..CASE },
+
+        // bsl and bsr, which or in the carry and only carry out of shifts by one
+        Case { code: &[0x89, 0x01], xs: [0x80000000, 1], writes: SHIFT, sets: &[Flag::Zero, Flag::Carry], ..CASE },
+        Case { code: &[0x89, 0x01], xs: [1, 4], carry: true, writes: SHIFT, sets: &[Flag::Parity], ..CASE },
+        Case { code: &[0x89, 0x01], xs: [0x40000000, 1], writes: SHIFT, sets: &[Flag::Negative], ..CASE },
+        Case { code: &[0x8a, 0x01], xs: [3, 1], writes: SHIFT, sets: &[Flag::Carry, Flag::Parity], ..CASE },
+        Case { code: &[0x8a, 0x01], xs: [2, 1], carry: true, writes: SHIFT, sets: &[Flag::Parity], ..CASE },
+        Case { code: &[0x8a, 0x01], xs: [0x80000000, 32], writes: SHIFT, sets: &[Flag::Zero], ..CASE },
+
+        // and, or, xor, mov and fbits
+        Case { code: &[0x8b, 0x01], xs: [0xf0, 0x0f], writes: INT, sets: &[Flag::Zero], ..CASE },
+        Case { code: &[0x8c, 0x01], xs: [0x80000000, 1], writes: INT, sets: &[Flag::Parity, Flag::Negative], ..CASE },
+        Case { code: &[0x8d, 0x01], xs: [5, 5], writes: INT, sets: &[Flag::Zero], ..CASE },
+        Case { code: &[0x8e, 0x01], xs: [1, 0x80000000], writes: INT, sets: &[Flag::Negative], ..CASE },
+        Case { code: &[0x92, 0x01], fs: [0.0, -0.0], writes: INT, sets: &[Flag::Negative], ..CASE },
+
+        // lf, lwf and lwf indirect
+        Case { code: &[0x50, 0, 0, 0x80, 0xff], writes: FLOAT, sets: &[Flag::Negative, Flag::Infinite], ..CASE },
+        Case { code: &[0x70, 0, 1, 0, 0], mem: 0x7fc00000, writes: FLOAT, sets: &[Flag::NaN], ..CASE },
+        Case { code: &[0x95, 0x01], xs: [0, 0x100], fs: [1.0, 0.0], writes: FLOAT, sets: &[Flag::Zero], ..CASE },
+
+        // fadd, fsub, fmul and fdiv
+        Case { code: &[0x85, 0x01], fs: [1.0, -1.0], writes: FLOAT, sets: &[Flag::Zero], ..CASE },
+        Case { code: &[0x86, 0x01], fs: [1.0, 2.0], writes: FLOAT, sets: &[Flag::Negative], ..CASE },
+        Case { code: &[0x87, 0x01], fs: [f32::MAX, 2.0], writes: FLOAT, sets: &[Flag::Infinite], ..CASE },
+        Case {
+            code: &[0x88, 0x01], fs: [1.0, -0.0], writes: FLOAT, sets: &[Flag::Negative, Flag::Infinite], ..CASE
+        },
+
+        // fmov, itof and ibits
+        Case { code: &[0x8f, 0x01], fs: [1.0, -0.0], writes: FLOAT, sets: &[Flag::Zero, Flag::Negative], ..CASE },
+        Case { code: &[0x91, 0x01], xs: [0, -3i32 as u32], writes: FLOAT, sets: &[Flag::Negative], ..CASE },
+        Case { code: &[0x93, 0x01], xs: [0, 0x7f800000], writes: FLOAT, sets: &[Flag::Infinite], ..CASE },
+
+        // fcmp
+        Case { code: &[0x9c, 0x01], fs: [1.0, 2.0], writes: FLOAT, sets: &[Flag::Negative], ..CASE },
+        Case { code: &[0x9c, 0x01], fs: [2.0, 2.0], writes: FLOAT, sets: &[Flag::Zero], ..CASE },
+        Case { code: &[0x9c, 0x01], fs: [3.0, 2.0], writes: FLOAT, sets: &[], ..CASE },
+        Case { code: &[0x9c, 0x01], fs: [f32::NAN, 2.0], writes: FLOAT, sets: &[Flag::NaN], ..CASE },
+
+        // fneg, fabs, fsqrt, fmin and fmax
+        Case { code: &[0x9d, 0x01], writes: FLOAT, sets: &[Flag::Zero, Flag::Negative], ..CASE },
+        Case { code: &[0x9e, 0x01], fs: [0.0, f32::NEG_INFINITY], writes: FLOAT, sets: &[Flag::Infinite], ..CASE },
+        Case { code: &[0x9f, 0x01], fs: [1.0, -0.0], writes: FLOAT, sets: &[Flag::Zero, Flag::Negative], ..CASE },
+        Case { code: &[0xa0, 0x01], fs: [f32::NAN, -2.0], writes: FLOAT, sets: &[Flag::Negative], ..CASE },
+        Case { code: &[0xa1, 0x01], fs: [1.0, f32::INFINITY], writes: FLOAT, sets: &[Flag::Infinite], ..CASE },
+
+        // frint, ffloor, fceil and ftrunc
+        Case { code: &[0xa2, 0x01], fs: [1.0, 0.5], writes: FLOAT, sets: &[Flag::Zero], ..CASE },
+        Case { code: &[0xa3, 0x01], fs: [0.0, -0.5], writes: FLOAT, sets: &[Flag::Negative], ..CASE },
+        Case { code: &[0xa4, 0x01], fs: [1.0, -0.5], writes: FLOAT, sets: &[Flag::Zero, Flag::Negative], ..CASE },
+        Case { code: &[0xa5, 0x01], fs: [0.0, f32::INFINITY], writes: FLOAT, sets: &[Flag::Infinite], ..CASE },
+
+        // ftoi, ftoin, ftoid and ftoiu, which set overflow when they saturate
+        Case { code: &[0x90, 0x01], fs: [0.0, -1.5], writes: TO_INT, sets: &[Flag::Parity, Flag::Negative], ..CASE },
+        Case { code: &[0x90, 0x01], fs: [0.0, f32::NAN], writes: TO_INT, sets: &[Flag::Zero, Flag::Overflow], ..CASE },
+        Case { code: &[0xa6, 0x01], fs: [0.0, 2.5], writes: TO_INT, sets: &[], ..CASE },
+        Case { code: &[0xa7, 0x01], fs: [0.0, 1e10], writes: TO_INT, sets: &[Flag::Overflow, Flag::Parity], ..CASE },
+        Case { code: &[0xa8, 0x01], fs: [0.0, -0.5], writes: TO_INT, sets: &[Flag::Zero], ..CASE },
+
+        // clc, stc, paging, interrupts and entering the user ring
+        Case { code: &[0x10], carry: true, writes: &[Flag::Carry], sets: &[], ..CASE },
+        Case { code: &[0x11], writes: &[Flag::Carry], sets: &[Flag::Carry], ..CASE },
+        Case { code: &[0x12], writes: &[Flag::MemmapEnable], sets: &[], ..CASE },
+        Case { code: &[0x13], writes: &[Flag::MemmapEnable], sets: &[Flag::MemmapEnable], ..CASE },
+        Case { code: &[0x14], writes: &[Flag::InterruptEnable], sets: &[], ..CASE },
+        Case { code: &[0x15], writes: &[Flag::InterruptEnable], sets: &[Flag::InterruptEnable], ..CASE },
+        Case { code: &[0x17], writes: &[Flag::UserRing], sets: &[Flag::UserRing], ..CASE },
+    ];
+
+    #[test]
+    fn flags_set_and_clear() {
+        let mut flags = Flags::from_bits(0x0b);
+        flags.set(Flag::Zero, true);
+        flags.set(Flag::Zero, true);
+        assert_eq!(flags.bits(), 0x1b);
+        flags.set(Flag::InterruptEnable, false);
+        assert_eq!(flags.bits(), 0x13);
+        assert!(flags.get(Flag::Zero) && !flags.get(Flag::InterruptEnable));
+        assert_eq!(flags.last_interrupt(), 3);
+
+        // Labels follow the README's diagram from bit 3 up
+        let labels: String = Flag::ALL.iter().map(|f| f.label()).collect();
+        assert_eq!(labels, "QZVCPNAFRMG");
+        assert!(Flag::ALL.iter().enumerate().all(|(i, f)| f.bit() == i as u32 + 3));
+    }
+
+    #[test]
+    fn flags_instructions() {
+        // Expectations are worked out from masks so they don't rely on Flags::set
+        let mask = |flags: &[Flag]| flags.iter().fold(0, |mask, flag| mask | flag.mask());
+        let arithmetic = [
+            Flag::Zero,
+            Flag::Overflow,
+            Flag::Carry,
+            Flag::Parity,
+            Flag::Negative,
+            Flag::NaN,
+            Flag::Infinite,
+        ];
+        let all = 0x5 | mask(&arithmetic) | mask(&[Flag::InterruptEnable, Flag::AlignmentCheck]);
+
+        for (i, case) in CASES.iter().enumerate() {
+            // Flags the instruction doesn't write must be left alone whether they are set or not
+            for set in [false, true] {
+                let carry = if case.writes.contains(&Flag::Carry) {
+                    case.carry
+                } else {
+                    set
+                };
+                let before = if set { all } else { 0 } & !Flag::Carry.mask() | (carry as u32) << Flag::Carry.bit();
+
+                let mut cpu = Cpu::new(SimpleAddress::default());
+                cpu.addressing.memory[..case.code.len()].copy_from_slice(case.code);
+                cpu.addressing.memory[0x100..0x104].copy_from_slice(&case.mem.to_le_bytes());
+                cpu.xs[..2].copy_from_slice(&case.xs);
+                cpu.fs[..2].copy_from_slice(&case.fs);
+                cpu.set_flags(before);
+                cpu.step();
+
+                let expected = before & !mask(case.writes) | mask(case.sets);
+                assert_eq!(cpu.xs[R_PC], case.code.len() as u32, "case {}", i);
+                assert_eq!(cpu.flags(), expected, "case {} from {:#x}", i, before);
+            }
+        }
+    }
+
+    #[test]
+    fn flags_interrupt_enable() {
+        // ei; nop
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[..2].copy_from_slice(&[0x15, 0x3f]);
+        cpu.irq(2);
+        cpu.step();
+        assert_eq!(cpu.interrupt_queue.len(), 1);
+
+        // Only the interrupt enable flag lets the interrupt through, not the last interrupt bits
+        cpu.step();
+        assert!(cpu.interrupt_queue.is_empty());
+        assert_eq!(cpu.xs[R_INT], 2);
+        assert_eq!(cpu.xs[R_PC], 1);
+
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[0] = 0x3f;
+        cpu.set_flags(0x7);
+        cpu.irq(2);
+        cpu.step();
+        assert_eq!(cpu.interrupt_queue.len(), 1);
+        assert_eq!(cpu.xs[R_PC], 1);
+    }
+
+    #[test]
+    fn flags_special_register() {
+        // wsr flags, x0; rsr x1, flags
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[..4].copy_from_slice(&[0x9a, 0x00, 0x9b, 0x01]);
+        cpu.set_flag(Flag::Carry, true);
+        cpu.xs[0] = Flag::Zero.mask() | Flag::NaN.mask();
+        cpu.run(2);
+        assert_eq!(cpu.flags(), Flag::Zero.mask() | Flag::NaN.mask());
+        assert_eq!(cpu.xs[1], cpu.flags());
+    }
+
+    #[test]
+    fn flags_user_ring_special_register() {
+        // wsr <selector>, x0 from the user ring faults for everything but the float control register
+        for selector in [0, 1, 2, crate::float::FPCR_SELECTOR as u8] {
+            let mut cpu = Cpu::new(SimpleAddress::default());
+            cpu.addressing.memory[..2].copy_from_slice(&[0x9a, selector]);
+            cpu.set_flag(Flag::UserRing, true);
+            cpu.xs[0] = 0x5;
+            cpu.step();
+
+            let faulted = cpu.interrupt_queue.back() == Some(&0x80000002);
+            assert_eq!(faulted, selector != crate::float::FPCR_SELECTOR as u8, "selector {}", selector);
+            assert_eq!(cpu.flags(), Flag::UserRing.mask(), "selector {}", selector);
+            assert_eq!(cpu.memmap(), 0, "selector {}", selector);
+            assert_eq!(cpu.interrupt_mask(), 0xff, "selector {}", selector);
+        }
+    }
+}
@@ -0,0 +1,349 @@
+// Floating point rounding, conversions and exceptions
+//
+// Rounding to an integral value and converting to an integer both take an explicit rounding mode,
+// instead of relying on what Rust's `as` casts happen to do. Conversions to integers saturate:
+// values below or above the range of an i32 become i32::MIN or i32::MAX, and NaN becomes 0, and
+// the caller is told so it can set the overflow flag.
+//
+// Arithmetic is rounded in the mode selected by the float control register (`fpcr`, special
+// register 13), and reports the IEEE 754 exceptions it raised. Results are computed exactly, or as
+// an f64 together with the sign of the error left over, and then rounded to an f32, which gives
+// the correctly rounded result in every mode. The register holds:
+//
+// bits 0-4   - sticky inexact, underflow, overflow, divide by zero and invalid exception flags
+// bits 8-12  - trap enables for the same exceptions, raising nonmaskable interrupt 5
+// bits 16-17 - rounding mode: nearest even, down, up or toward zero
+//
+// Both rings can read and write it.
+
+use crate::{Address, Cpu};
+
+pub const FP_INEXACT: u32 = 1 << 0;
+pub const FP_UNDERFLOW: u32 = 1 << 1;
+pub const FP_OVERFLOW: u32 = 1 << 2;
+pub const FP_DIV_BY_ZERO: u32 = 1 << 3;
+pub const FP_INVALID: u32 = 1 << 4;
+pub const FP_EXCEPTIONS: u32 = 0x1f;
+
+pub const FP_TRAP_SHIFT: u32 = 8;
+pub const FP_ROUNDING_SHIFT: u32 = 16;
+
+// Bits of the register that can be written
+pub const FPCR_MASK: u32 = FP_EXCEPTIONS | FP_EXCEPTIONS << FP_TRAP_SHIFT | 3 << FP_ROUNDING_SHIFT;
+
+pub const FPCR_SELECTOR: usize = 13;
+
+// Nonmaskable interrupt raised by an exception with its trap enabled
+pub const FP_TRAP_INTERRUPT: u32 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rounding {
+    // To the nearest integer, with ties going to the even one
+    NearestEven,
+
+    // Towards negative infinity
+    Down,
+
+    // Towards positive infinity
+    Up,
+
+    // Towards zero, discarding the fraction
+    TowardZero,
+}
+
+impl Rounding {
+    // The mode with the given number, as in the rounding mode field of the control register
+    pub fn from_bits(bits: u32) -> Rounding {
+        match bits & 3 {
+            0 => Rounding::NearestEven,
+            1 => Rounding::Down,
+            2 => Rounding::Up,
+            _ => Rounding::TowardZero,
+        }
+    }
+}
+
+// Rounds x to an integral value. NaNs, infinities and zeros are returned unchanged.
+pub fn round(x: f32, mode: Rounding) -> f32 {
+    match mode {
+        Rounding::NearestEven => x.round_ties_even(),
+        Rounding::Down => x.floor(),
+        Rounding::Up => x.ceil(),
+        Rounding::TowardZero => x.trunc(),
+    }
+}
+
+// Rounds x and converts it to an i32, returning whether it had to saturate
+pub fn to_int(x: f32, mode: Rounding) -> (i32, bool) {
+    let x = round(x, mode);
+    if x.is_nan() {
+        (0, true)
+    } else if x < i32::MIN as f32 {
+        (i32::MIN, true)
+    } else if x >= -(i32::MIN as f32) {
+        (i32::MAX, true)
+    } else {
+        (x as i32, false)
+    }
+}
+
+// Rounds the exact value hi + lo to an f32, where lo is zero or has the sign of the error left in
+// hi, returning the result and the exceptions it raised. The result is NaN only if hi is.
+fn narrow(hi: f64, lo: f64, mode: Rounding) -> (f32, u32) {
+    let nearest = hi as f32;
+    if !hi.is_finite() {
+        return (nearest, 0);
+    }
+
+    // Where the exact value is relative to the nearest f32
+    let d = hi - nearest as f64;
+    let above = if d == 0.0 { lo } else { d };
+    if above == 0.0 {
+        return (nearest, 0);
+    }
+    let (lower, upper) = match above > 0.0 {
+        true => (nearest, nearest.next_up()),
+        false => (nearest.next_down(), nearest),
+    };
+
+    let res = match mode {
+        // hi can be a midpoint between two f32s only because the error was rounded off
+        Rounding::NearestEven if hi * 2.0 == lower as f64 + upper as f64 && lo != 0.0 => {
+            if lo > 0.0 {
+                upper
+            } else {
+                lower
+            }
+        }
+        Rounding::NearestEven => nearest,
+        Rounding::Down => lower,
+        Rounding::Up => upper,
+        Rounding::TowardZero if hi > 0.0 => lower,
+        Rounding::TowardZero => upper,
+    };
+
+    let mut exceptions = FP_INEXACT;
+    if hi.abs() < f32::MIN_POSITIVE as f64 {
+        exceptions |= FP_UNDERFLOW;
+    }
+    if res.is_infinite() || hi.abs() >= 2f64.powi(128) {
+        exceptions |= FP_OVERFLOW;
+    }
+    (res, exceptions)
+}
+
+// A NaN result is an invalid operation unless it came from a NaN operand
+fn invalid(res: f32, operands: &[f32]) -> u32 {
+    match res.is_nan() && !operands.iter().any(|x| x.is_nan()) {
+        true => FP_INVALID,
+        false => 0,
+    }
+}
+
+pub fn add(a: f32, b: f32, mode: Rounding) -> (f32, u32) {
+    let (a64, b64) = (a as f64, b as f64);
+    let hi = a64 + b64;
+
+    // The error of the f64 sum, which is exact
+    let b_part = hi - a64;
+    let lo = (a64 - (hi - b_part)) + (b64 - b_part);
+    let (res, exceptions) = narrow(hi, if hi.is_finite() { lo } else { 0.0 }, mode);
+
+    // An exact zero sum of operands of opposite signs is -0 only when rounding down
+    let res = if res == 0.0 && hi == 0.0 && a.to_bits() != b.to_bits() {
+        if mode == Rounding::Down {
+            -0.0
+        } else {
+            0.0
+        }
+    } else {
+        res
+    };
+    (res, exceptions | invalid(res, &[a, b]))
+}
+
+pub fn sub(a: f32, b: f32, mode: Rounding) -> (f32, u32) {
+    add(a, -b, mode)
+}
+
+pub fn mul(a: f32, b: f32, mode: Rounding) -> (f32, u32) {
+    // The product of two f32s always fits in an f64
+    let (res, exceptions) = narrow(a as f64 * b as f64, 0.0, mode);
+    (res, exceptions | invalid(res, &[a, b]))
+}
+
+pub fn div(a: f32, b: f32, mode: Rounding) -> (f32, u32) {
+    let (a64, b64) = (a as f64, b as f64);
+    let hi = a64 / b64;
+    let lo = match hi.is_finite() && b != 0.0 {
+        // The remainder a - hi * b has the sign of the error times the sign of b
+        true => (-hi).mul_add(b64, a64) * b64.signum(),
+        false => 0.0,
+    };
+    let (res, mut exceptions) = narrow(hi, lo, mode);
+    if b == 0.0 && a.is_finite() && a != 0.0 {
+        exceptions |= FP_DIV_BY_ZERO;
+    }
+    (res, exceptions | invalid(res, &[a, b]))
+}
+
+pub fn sqrt(a: f32, mode: Rounding) -> (f32, u32) {
+    let hi = (a as f64).sqrt();
+    let lo = match hi.is_finite() {
+        true => (-hi).mul_add(hi, a as f64),
+        false => 0.0,
+    };
+    let (res, exceptions) = narrow(hi, lo, mode);
+    (res, exceptions | invalid(res, &[a]))
+}
+
+pub fn from_int(x: i32, mode: Rounding) -> (f32, u32) {
+    narrow(x as f64, 0.0, mode)
+}
+
+impl<T> Cpu<T>
+where
+    T: Address,
+{
+    pub fn float_control(&self) -> u32 {
+        self.fpcr
+    }
+
+    pub fn set_float_control(&mut self, fpcr: u32) {
+        self.fpcr = fpcr & FPCR_MASK;
+    }
+
+    pub(crate) fn rounding(&self) -> Rounding {
+        Rounding::from_bits(self.fpcr >> FP_ROUNDING_SHIFT)
+    }
+
+    // Sets the sticky flags of the exceptions an instruction raised, and requests the trap
+    // interrupt if any of them is enabled
+    pub(crate) fn raise_float(&mut self, exceptions: u32) {
+        self.fpcr |= exceptions;
+        if exceptions & self.fpcr >> FP_TRAP_SHIFT != 0 {
+            self.nmi(FP_TRAP_INTERRUPT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flags::Flag;
+    use crate::SimpleAddress;
+
+    #[test]
+    fn rounding_modes() {
+        let cases = [
+            (2.5, [2.0, 2.0, 3.0, 2.0]),
+            (3.5, [4.0, 3.0, 4.0, 3.0]),
+            (-2.5, [-2.0, -3.0, -2.0, -2.0]),
+            (-0.25, [-0.0, -1.0, -0.0, -0.0]),
+        ];
+        let modes = [Rounding::NearestEven, Rounding::Down, Rounding::Up, Rounding::TowardZero];
+        for &(x, expected) in cases.iter() {
+            for (&mode, &y) in modes.iter().zip(expected.iter()) {
+                assert_eq!(round(x, mode).to_bits(), f32::to_bits(y), "{} {:?}", x, mode);
+            }
+        }
+        assert!(round(f32::NAN, Rounding::Up).is_nan());
+        assert_eq!(round(f32::NEG_INFINITY, Rounding::NearestEven), f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn saturating_conversions() {
+        assert_eq!(to_int(-7.5, Rounding::NearestEven), (-8, false));
+        assert_eq!(to_int(-7.5, Rounding::TowardZero), (-7, false));
+        assert_eq!(to_int(2147483520.0, Rounding::Up), (2147483520, false));
+        assert_eq!(to_int(-2147483648.0, Rounding::Down), (i32::MIN, false));
+        assert_eq!(to_int(2147483648.0, Rounding::Down), (i32::MAX, true));
+        assert_eq!(to_int(-3e9, Rounding::Up), (i32::MIN, true));
+        assert_eq!(to_int(f32::INFINITY, Rounding::NearestEven), (i32::MAX, true));
+        assert_eq!(to_int(f32::NAN, Rounding::NearestEven), (0, true));
+    }
+
+    #[test]
+    fn arithmetic_rounding() {
+        // Round to nearest matches the hardware, and the directed modes bracket the exact product
+        let mut seed = 0x12345678u32;
+        let mut next = || {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            f32::from_bits(seed & 0xbfffffff)
+        };
+        for _ in 0..10000 {
+            let (a, b) = (next(), next());
+            assert_eq!(add(a, b, Rounding::NearestEven).0.to_bits(), (a + b).to_bits(), "{} + {}", a, b);
+            assert_eq!(mul(a, b, Rounding::NearestEven).0.to_bits(), (a * b).to_bits(), "{} * {}", a, b);
+            assert_eq!(div(a, b, Rounding::NearestEven).0.to_bits(), (a / b).to_bits(), "{} / {}", a, b);
+            assert_eq!(sqrt(a, Rounding::NearestEven).0.to_bits(), a.sqrt().to_bits(), "sqrt {}", a);
+
+            let (down, exceptions) = mul(a, b, Rounding::Down);
+            let (up, _) = mul(a, b, Rounding::Up);
+            let exact = a as f64 * b as f64;
+            assert!(down as f64 <= exact && exact <= up as f64, "{} * {}", a, b);
+            assert_eq!(exceptions & FP_INEXACT == 0, down == up, "{} * {}", a, b);
+        }
+
+        // The error of a sum is not lost to the f64 it is computed in
+        let tiny = f32::from_bits(1);
+        assert_eq!(add(1.0, tiny, Rounding::Up), (1.0f32.next_up(), FP_INEXACT));
+        assert_eq!(sub(1.0, tiny, Rounding::Down), (1.0f32.next_down(), FP_INEXACT));
+        assert_eq!(add(1.0, tiny, Rounding::TowardZero), (1.0, FP_INEXACT));
+        assert_eq!(div(1.0, 3.0, Rounding::Up).0, 1.0f32 / 3.0);
+        assert_eq!(div(1.0, 3.0, Rounding::Down).0, (1.0f32 / 3.0).next_down());
+        assert_eq!(from_int(0x7fffffff, Rounding::Down), (2147483520.0, FP_INEXACT));
+        assert_eq!(add(1.0, -1.0, Rounding::Down).0.to_bits(), (-0.0f32).to_bits());
+        assert_eq!(add(1.0, -1.0, Rounding::Up).0.to_bits(), 0);
+    }
+
+    #[test]
+    fn arithmetic_exceptions() {
+        let mode = Rounding::NearestEven;
+        assert_eq!(add(f32::MAX, f32::MAX, mode), (f32::INFINITY, FP_OVERFLOW | FP_INEXACT));
+        assert_eq!(add(f32::MAX, f32::MAX, Rounding::Down), (f32::MAX, FP_OVERFLOW | FP_INEXACT));
+        assert_eq!(mul(-f32::MAX, 2.0, Rounding::TowardZero), (-f32::MAX, FP_OVERFLOW | FP_INEXACT));
+        assert_eq!(mul(f32::MIN_POSITIVE, 0.5, mode), (f32::MIN_POSITIVE / 2.0, 0));
+        assert_eq!(div(f32::MIN_POSITIVE, 3.0, mode).1, FP_UNDERFLOW | FP_INEXACT);
+        assert_eq!(div(-1.0, 0.0, mode), (f32::NEG_INFINITY, FP_DIV_BY_ZERO));
+        assert_eq!(div(0.0, 0.0, mode).1, FP_INVALID);
+        assert_eq!(sub(f32::INFINITY, f32::INFINITY, mode).1, FP_INVALID);
+        assert_eq!(sqrt(-1.0, mode).1, FP_INVALID);
+        assert_eq!(add(f32::NAN, 1.0, mode).1, 0);
+        assert_eq!(div(f32::INFINITY, 0.0, mode), (f32::INFINITY, 0));
+    }
+
+    #[test]
+    fn float_control_register() {
+        // li x0, 0x11400 (round down, trap on overflow and invalid); wsr fpcr, x0
+        // fdiv f0, f1; fdiv f2, f2; rsr x1, fpcr
+        let program = [0x40, 0x00, 0x14, 0x01, 0x00, 0x9a, 0x0d, 0x88, 0x01, 0x88, 0x22, 0x9b, 0xd1];
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[..program.len()].copy_from_slice(&program);
+        cpu.set_flag(Flag::UserRing, true);
+        cpu.fs[0] = 1.0;
+        cpu.fs[1] = 3.0;
+
+        // Writable from the user ring, and rounds the quotient down
+        cpu.step();
+        cpu.step();
+        cpu.step();
+        assert!(cpu.interrupt_queue.is_empty());
+        assert_eq!(cpu.fs[0], (1.0f32 / 3.0).next_down());
+        assert_eq!(cpu.float_control(), 0x11400 | FP_INEXACT);
+
+        // 0 / 0 raises the trap, but the result is still written and the flags are sticky
+        cpu.step();
+        assert_eq!(cpu.interrupt_queue.back(), Some(&(0x80000000 | FP_TRAP_INTERRUPT)));
+        assert!(cpu.fs[2].is_nan());
+        cpu.step();
+        assert_eq!(cpu.xs[1], 0x11400 | FP_INEXACT | FP_INVALID);
+        assert_eq!(cpu.pc(), program.len() as u32);
+
+        // Unused bits read as zero
+        cpu.set_float_control(0xffffffff);
+        assert_eq!(cpu.float_control(), FPCR_MASK);
+        cpu.reset();
+        assert_eq!(cpu.float_control(), 0);
+    }
+}
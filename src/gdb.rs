@@ -0,0 +1,728 @@
+// GDB remote serial protocol stub
+//
+// Registers are numbered x0-x15, f0-f15, flags, memmap, matching the README. Memory accesses go
+// through the page table unless physical mode is enabled with `monitor phys on`.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::debug::{Access, Space, StopReason, Trigger};
+use crate::symbols::Symbols;
+use crate::{Address, Cpu};
+
+pub const REGISTER_COUNT: usize = 34;
+
+// Number of instructions executed between checks for a break request while continuing
+const POLL_INTERVAL: u32 = 4096;
+
+// Longest packet accepted, as advertised in qSupported
+const PACKET_SIZE: usize = 0x4000;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+pub const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.cpuwu.core">
+    <reg name="x0" bitsize="32" type="uint32" regnum="0"/>
+    <reg name="x1" bitsize="32" type="uint32"/>
+    <reg name="x2" bitsize="32" type="uint32"/>
+    <reg name="x3" bitsize="32" type="uint32"/>
+    <reg name="x4" bitsize="32" type="uint32"/>
+    <reg name="x5" bitsize="32" type="uint32"/>
+    <reg name="x6" bitsize="32" type="uint32"/>
+    <reg name="x7" bitsize="32" type="uint32"/>
+    <reg name="x8" bitsize="32" type="uint32"/>
+    <reg name="x9" bitsize="32" type="uint32"/>
+    <reg name="x10" bitsize="32" type="uint32"/>
+    <reg name="x11" bitsize="32" type="uint32"/>
+    <reg name="x12" bitsize="32" type="uint32"/>
+    <reg name="pc" bitsize="32" type="code_ptr"/>
+    <reg name="bp" bitsize="32" type="data_ptr"/>
+    <reg name="sp" bitsize="32" type="data_ptr"/>
+  </feature>
+  <feature name="org.cpuwu.float">
+    <reg name="f0" bitsize="32" type="ieee_single" regnum="16"/>
+    <reg name="f1" bitsize="32" type="ieee_single"/>
+    <reg name="f2" bitsize="32" type="ieee_single"/>
+    <reg name="f3" bitsize="32" type="ieee_single"/>
+    <reg name="f4" bitsize="32" type="ieee_single"/>
+    <reg name="f5" bitsize="32" type="ieee_single"/>
+    <reg name="f6" bitsize="32" type="ieee_single"/>
+    <reg name="f7" bitsize="32" type="ieee_single"/>
+    <reg name="f8" bitsize="32" type="ieee_single"/>
+    <reg name="f9" bitsize="32" type="ieee_single"/>
+    <reg name="f10" bitsize="32" type="ieee_single"/>
+    <reg name="f11" bitsize="32" type="ieee_single"/>
+    <reg name="f12" bitsize="32" type="ieee_single"/>
+    <reg name="f13" bitsize="32" type="ieee_single"/>
+    <reg name="f14" bitsize="32" type="ieee_single"/>
+    <reg name="f15" bitsize="32" type="ieee_single"/>
+  </feature>
+  <feature name="org.cpuwu.system">
+    <flags id="flags_type" size="4">
+      <field name="LLL" start="0" end="2"/>
+      <field name="Q" start="3" end="3"/>
+      <field name="Z" start="4" end="4"/>
+      <field name="V" start="5" end="5"/>
+      <field name="C" start="6" end="6"/>
+      <field name="P" start="7" end="7"/>
+      <field name="N" start="8" end="8"/>
+      <field name="A" start="9" end="9"/>
+      <field name="F" start="10" end="10"/>
+      <field name="R" start="11" end="11"/>
+      <field name="M" start="12" end="12"/>
+      <field name="G" start="13" end="13"/>
+    </flags>
+    <reg name="flags" bitsize="32" type="flags_type" regnum="32"/>
+    <reg name="memmap" bitsize="32" type="data_ptr"/>
+  </feature>
+</target>
+"#;
+
+// A connection to a debugger that can be polled for a break request (^C) without blocking
+pub trait Connection: Read + Write {
+    fn poll_break(&mut self) -> io::Result<bool>;
+}
+
+impl Connection for TcpStream {
+    fn poll_break(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let mut buf = [0];
+        let res = match self.read(&mut buf) {
+            Ok(1) => Ok(buf[0] == 0x03),
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        };
+        self.set_nonblocking(false)?;
+        res
+    }
+}
+
+#[cfg(unix)]
+impl Connection for std::os::unix::net::UnixStream {
+    fn poll_break(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let mut buf = [0];
+        let res = match self.read(&mut buf) {
+            Ok(1) => Ok(buf[0] == 0x03),
+            Ok(_) => Ok(false),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        };
+        self.set_nonblocking(false)?;
+        res
+    }
+}
+
+// Waits for a single debugger to connect on localhost
+pub fn accept_tcp(port: u16) -> io::Result<TcpStream> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+#[cfg(unix)]
+pub fn accept_unix(path: &str) -> io::Result<std::os::unix::net::UnixStream> {
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    let (stream, _) = listener.accept()?;
+    Ok(stream)
+}
+
+pub struct GdbStub<'a, T>
+where
+    T: Address,
+{
+    cpu: &'a mut Cpu<T>,
+    // Triggers inserted by the debugger, so they can be removed again by address
+    triggers: HashMap<Trigger, usize>,
+    physical: bool,
+}
+
+fn hex_u32(x: u32) -> String {
+    x.to_le_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+fn parse_hex_bytes(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_le_u32(s: &str) -> Option<u32> {
+    let bytes = parse_hex_bytes(s)?;
+    if bytes.len() != 4 {
+        return None;
+    }
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn escape(data: &str) -> String {
+    let mut res = String::new();
+    for c in data.chars() {
+        match c {
+            '#' | '$' | '}' | '*' => {
+                res.push('}');
+                res.push((c as u8 ^ 0x20) as char);
+            }
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+impl<'a, T> GdbStub<'a, T>
+where
+    T: Address,
+{
+    pub fn new(cpu: &'a mut Cpu<T>) -> GdbStub<'a, T> {
+        GdbStub {
+            cpu,
+            triggers: HashMap::new(),
+            physical: false,
+        }
+    }
+
+    fn register(&self, n: usize) -> Option<u32> {
+        match n {
+            0..=15 => Some(self.cpu.xs[n]),
+            16..=31 => Some(self.cpu.fs[n - 16].to_bits()),
+            32 => Some(self.cpu.flags()),
+            33 => Some(self.cpu.memmap),
+            _ => None,
+        }
+    }
+
+    fn set_register(&mut self, n: usize, val: u32) -> Option<()> {
+        match n {
+            0..=15 => self.cpu.xs[n] = val,
+            16..=31 => self.cpu.fs[n - 16] = f32::from_bits(val),
+            32 => self.cpu.set_flags(val),
+            33 => self.cpu.memmap = val,
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn translate(&mut self, addr: u32) -> Option<u32> {
+        if self.physical {
+            Some(addr)
+        } else {
+            self.cpu.check_memory(addr, 0).ok()
+        }
+    }
+
+    // Reads at most as many bytes as fit in a packet, which gdb asks again for the rest of
+    fn read_memory(&mut self, addr: u32, len: u32) -> String {
+        let mut res = String::new();
+        for i in 0..len.min((PACKET_SIZE as u32 - 1) / 2) {
+            match self.translate(addr.wrapping_add(i)).and_then(|a| self.cpu.addressing.read(a).ok()) {
+                Some(byte) => res.push_str(&format!("{:02x}", byte)),
+
+                // Partial reads are allowed, but an empty one is an error
+                None if i == 0 => return "E14".to_string(),
+                None => break,
+            }
+        }
+        res
+    }
+
+    fn write_memory(&mut self, addr: u32, data: &[u8]) -> &'static str {
+        for (i, byte) in data.iter().enumerate() {
+            match self.translate(addr.wrapping_add(i as u32)) {
+                Some(a) => {
+                    self.cpu.invalidate_code(a, 1);
+                    if self.cpu.addressing.write(a, *byte).is_err() {
+                        return "E14";
+                    }
+                }
+                None => return "E14",
+            }
+        }
+        "OK"
+    }
+
+    fn space(&self) -> Space {
+        if self.physical {
+            Space::Physical
+        } else {
+            Space::Virtual
+        }
+    }
+
+    fn stop_reply(&self, reason: StopReason) -> String {
+        match reason {
+            StopReason::Breakpoint { .. } => format!("T{:02x}swbreak:;", SIGTRAP),
+
+            StopReason::Watchpoint { id, addr, write, .. } => {
+                let kind = match self.cpu.triggers().find(|&(i, _)| i == id) {
+                    Some((_, Trigger::Watchpoint { access: Access::ReadWrite, .. })) => "awatch",
+                    _ if write => "watch",
+                    _ => "rwatch",
+                };
+                format!("T{:02x}{}:{:x};", SIGTRAP, kind, addr)
+            }
+
+            StopReason::Fault { .. } => format!("S{:02x}", SIGSEGV),
+            StopReason::HistoryExhausted => format!("T{:02x}replaylog:begin;", SIGTRAP),
+            StopReason::StepLimit => format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    // Runs the cpu until a breakpoint, watchpoint, fault or break request, returning the stop
+    // reply to send
+    fn resume<C: Connection>(&mut self, conn: &mut C, single: bool) -> io::Result<String> {
+        let mut count = 0u32;
+        loop {
+            let reason = self.cpu.run(1);
+            if let StopReason::Watchpoint { .. } | StopReason::Fault { .. } = reason {
+                return Ok(self.stop_reply(reason));
+            }
+
+            if single {
+                return Ok(format!("S{:02x}", SIGTRAP));
+            }
+
+            if let Some(reason) = self.cpu.breakpoint_hit() {
+                return Ok(self.stop_reply(reason));
+            }
+
+            count += 1;
+            if count.is_multiple_of(POLL_INTERVAL) && conn.poll_break()? {
+                return Ok(format!("S{:02x}", SIGINT));
+            }
+        }
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            // Hardware breakpoints aren't supported, so only software breakpoint stops are reported
+            format!("PacketSize={:x};qXfer:features:read+;swbreak+;ReverseStep+;ReverseContinue+", PACKET_SIZE)
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let mut args = args.split(',');
+            let offset = args.next().and_then(parse_hex).unwrap_or(0) as usize;
+            let len = args.next().and_then(parse_hex).unwrap_or(0) as usize;
+            if offset >= TARGET_XML.len() {
+                "l".to_string()
+            } else {
+                let end = (offset + len).min(TARGET_XML.len());
+                let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+                format!("{}{}", more, escape(&TARGET_XML[offset..end]))
+            }
+        } else if let Some(cmd) = packet.strip_prefix("qRcmd,") {
+            let cmd = parse_hex_bytes(cmd).unwrap_or_default();
+            let reply = match String::from_utf8_lossy(&cmd).trim() {
+                "phys on" => {
+                    self.physical = true;
+                    "physical memory access enabled\n".to_string()
+                }
+                "phys off" => {
+                    self.physical = false;
+                    "virtual memory access enabled\n".to_string()
+                }
+                "backtrace" | "bt" => self.cpu.backtrace().format(&Symbols::new()),
+                _ => "commands: phys on, phys off, backtrace\n".to_string(),
+            };
+            reply.bytes().map(|b| format!("{:02x}", b)).collect()
+        } else {
+            String::new()
+        }
+    }
+
+    // Handles a single packet, returning None if the session is over
+    fn handle<C: Connection>(&mut self, conn: &mut C, packet: &str) -> io::Result<Option<String>> {
+        // Commands are a single ASCII character, so anything else is unsupported
+        let (cmd, args) = match packet.as_bytes().first() {
+            Some(c) if !c.is_ascii() => return Ok(Some(String::new())),
+            _ => packet.split_at(packet.len().min(1)),
+        };
+        let reply = match cmd {
+            "?" => format!("S{:02x}", SIGTRAP),
+
+            "g" => (0..REGISTER_COUNT).map(|n| hex_u32(self.register(n).unwrap())).collect(),
+
+            "G" => {
+                let mut ok = args.len() == REGISTER_COUNT * 8;
+                for n in 0..REGISTER_COUNT {
+                    match args.get(n * 8..n * 8 + 8).and_then(parse_le_u32) {
+                        Some(val) if ok => {
+                            self.set_register(n, val);
+                        }
+                        _ => ok = false,
+                    }
+                }
+                if ok { "OK" } else { "E01" }.to_string()
+            }
+
+            "p" => match parse_hex(args).and_then(|n| self.register(n as usize)) {
+                Some(val) => hex_u32(val),
+                None => "E01".to_string(),
+            },
+
+            "P" => {
+                let mut parts = args.split('=');
+                let n = parts.next().and_then(parse_hex);
+                let val = parts.next().and_then(parse_le_u32);
+                match (n, val) {
+                    (Some(n), Some(val)) if self.set_register(n as usize, val).is_some() => "OK",
+                    _ => "E01",
+                }
+                .to_string()
+            }
+
+            "m" => {
+                let mut parts = args.split(',');
+                match (parts.next().and_then(parse_hex), parts.next().and_then(parse_hex)) {
+                    (Some(addr), Some(len)) => self.read_memory(addr, len),
+                    _ => "E01".to_string(),
+                }
+            }
+
+            "M" => {
+                let mut parts = args.split([',', ':']);
+                let addr = parts.next().and_then(parse_hex);
+                let len = parts.next().and_then(parse_hex);
+                let data = parts.next().and_then(parse_hex_bytes);
+                match (addr, len, data) {
+                    (Some(addr), Some(len), Some(data)) if data.len() == len as usize => {
+                        self.write_memory(addr, &data).to_string()
+                    }
+                    _ => "E01".to_string(),
+                }
+            }
+
+            "Z" | "z" => {
+                let mut parts = args.split(',');
+                let kind = parts.next();
+                let addr = parts.next().and_then(parse_hex);
+                let len = parts.next().and_then(parse_hex);
+                let space = self.space();
+                let access = |access| match (addr, len) {
+                    (Some(start), Some(len)) => Some(Trigger::Watchpoint { space, start, len, access }),
+                    _ => None,
+                };
+                let trigger = match kind {
+                    Some("0") => addr.map(|addr| Trigger::Breakpoint { space, addr }),
+                    Some("2") => access(Access::Write),
+                    Some("3") => access(Access::Read),
+                    Some("4") => access(Access::ReadWrite),
+
+                    // Hardware breakpoints are not supported
+                    _ => return Ok(Some(String::new())),
+                };
+
+                match trigger {
+                    Some(trigger) if cmd == "Z" => {
+                        if !self.triggers.contains_key(&trigger) {
+                            let id = self.cpu.add_trigger(trigger);
+                            self.triggers.insert(trigger, id);
+                        }
+                        "OK".to_string()
+                    }
+
+                    Some(trigger) => {
+                        if let Some(id) = self.triggers.remove(&trigger) {
+                            self.cpu.remove_trigger(id);
+                        }
+                        "OK".to_string()
+                    }
+
+                    None => "E01".to_string(),
+                }
+            }
+
+            "s" | "c" => {
+                if let Some(addr) = parse_hex(args) {
+                    self.cpu.xs[crate::R_PC] = addr;
+                }
+                self.resume(conn, cmd == "s")?
+            }
+
+            "b" if self.cpu.history.is_none() => "E01".to_string(),
+            "b" if args == "s" => {
+                if self.cpu.reverse_step() {
+                    format!("S{:02x}", SIGTRAP)
+                } else {
+                    format!("T{:02x}replaylog:begin;", SIGTRAP)
+                }
+            }
+            "b" if args == "c" => {
+                let reason = self.cpu.reverse_continue(u64::MAX);
+                self.stop_reply(reason)
+            }
+
+            "H" => "OK".to_string(),
+            "T" => "OK".to_string(),
+            "q" => self.query(packet),
+
+            "D" => {
+                self.send(conn, "OK")?;
+                return Ok(None);
+            }
+            "k" => return Ok(None),
+
+            _ => String::new(),
+        };
+
+        Ok(Some(reply))
+    }
+
+    fn send<C: Connection>(&mut self, conn: &mut C, data: &str) -> io::Result<()> {
+        let checksum = data.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+        write!(conn, "${}#{:02x}", data, checksum)?;
+        conn.flush()
+    }
+
+    // Reads the next packet, acknowledging it and asking again for any with a bad checksum or
+    // longer than PACKET_SIZE, which are dropped. Returns None when the connection is closed.
+    fn receive<C: Connection>(&mut self, conn: &mut C) -> io::Result<Option<String>> {
+        let mut byte = [0];
+        loop {
+            loop {
+                if conn.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'$' {
+                    break;
+                }
+            }
+
+            let (mut data, mut overlong) = (vec![], false);
+            loop {
+                if conn.read(&mut byte)? == 0 {
+                    return Ok(None);
+                }
+                if byte[0] == b'#' {
+                    break;
+                }
+                if data.len() == PACKET_SIZE {
+                    overlong = true;
+                } else {
+                    data.push(byte[0]);
+                }
+            }
+
+            let mut checksum = [0; 2];
+            conn.read_exact(&mut checksum)?;
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|c| u8::from_str_radix(c, 16).ok());
+            let actual = data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+
+            if expected == Some(actual) && !overlong {
+                conn.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            conn.write_all(b"-")?;
+        }
+    }
+
+    // Serves a debugger session on the connection until it detaches or disconnects
+    pub fn serve<C: Connection>(&mut self, mut conn: C) -> io::Result<()> {
+        let res = self.session(&mut conn);
+        for (_, id) in self.triggers.drain() {
+            self.cpu.remove_trigger(id);
+        }
+        res
+    }
+
+    fn session<C: Connection>(&mut self, conn: &mut C) -> io::Result<()> {
+        while let Some(packet) = self.receive(conn)? {
+            match self.handle(conn, &packet)? {
+                Some(reply) => self.send(conn, &reply)?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flags::Flag;
+    use crate::SimpleAddress;
+    use std::io::Cursor;
+
+    struct TestConnection {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for TestConnection {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for TestConnection {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for &mut TestConnection {
+        fn poll_break(&mut self) -> io::Result<bool> {
+            Ok(false)
+        }
+    }
+
+    fn replies(cpu: &mut Cpu<SimpleAddress>, packets: &[&str]) -> Vec<String> {
+        let mut input = String::new();
+        for p in packets {
+            let checksum = p.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+            input.push_str(&format!("${}#{:02x}", p, checksum));
+        }
+
+        let mut conn = TestConnection {
+            input: Cursor::new(input.into_bytes()),
+            output: vec![],
+        };
+        GdbStub::new(cpu).serve(&mut conn).unwrap();
+
+        let output = String::from_utf8(conn.output).unwrap();
+        output.split('$').skip(1).map(|p| p.split('#').next().unwrap().to_string()).collect()
+    }
+
+    #[test]
+    fn gdb_registers() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.xs[1] = 0xa0b0c0d0;
+        cpu.fs[0] = 1.0;
+
+        let res = replies(&mut cpu, &["p1", "p10", "P2=78563412", "p2", "p22"]);
+        assert_eq!(res, vec!["d0c0b0a0", "0000803f", "OK", "78563412", "E01"]);
+        assert_eq!(cpu.xs[2], 0x12345678);
+
+        let g = replies(&mut cpu, &["g"]).remove(0);
+        assert_eq!(g.len(), REGISTER_COUNT * 8);
+        assert_eq!(&g[8..16], "d0c0b0a0");
+    }
+
+    #[test]
+    fn gdb_bad_checksums() {
+        // Every corrupted packet is refused without growing the stack, then the good one is served
+        let mut input = "$p1#00".repeat(100_000);
+        input.push_str("$p1#a1");
+        let mut conn = TestConnection { input: Cursor::new(input.into_bytes()), output: vec![] };
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.xs[1] = 5;
+        GdbStub::new(&mut cpu).serve(&mut conn).unwrap();
+
+        let output = String::from_utf8(conn.output).unwrap();
+        assert_eq!(output.matches('-').count(), 100_000);
+        assert!(output.ends_with("+$05000000#85"));
+    }
+
+    #[test]
+    fn gdb_memory() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        let res = replies(&mut cpu, &["M100,2:beef", "m100,3"]);
+        assert_eq!(res, vec!["OK", "beef00"]);
+        assert_eq!(cpu.addressing.memory[0x101], 0xef);
+
+        // Unmapped virtual memory
+        cpu.set_flag(Flag::MemmapEnable, true);
+        cpu.memmap = 0x1234;
+        assert_eq!(replies(&mut cpu, &["m100,1"]), vec!["E14"]);
+
+        // Long reads are cut short to fit in a packet
+        cpu.set_flag(Flag::MemmapEnable, false);
+        let res = replies(&mut cpu, &["m0,ffffffff"]);
+        assert_eq!(res[0].len(), (PACKET_SIZE - 1) / 2 * 2);
+    }
+
+    #[test]
+    fn gdb_bad_packets() {
+        // A command that isn't ASCII is unsupported, and a packet that is too long is refused even
+        // though its checksum matches the bytes that fit
+        let mut input = b"$\xff#ff".to_vec();
+        input.push(b'$');
+        input.extend(std::iter::repeat_n(b'0', PACKET_SIZE + 1));
+        input.extend_from_slice(b"#00$p1#a1");
+        let mut conn = TestConnection { input: Cursor::new(input), output: vec![] };
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.xs[1] = 5;
+        GdbStub::new(&mut cpu).serve(&mut conn).unwrap();
+        assert_eq!(String::from_utf8(conn.output).unwrap(), "+$#00-+$05000000#85");
+    }
+
+    #[test]
+    fn gdb_breakpoints() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+
+        // li x0, 5; li x1, 7; add x0, x1
+        let program = [0x40, 5, 0, 0, 0, 0x41, 7, 0, 0, 0, 0x80, 0x01];
+        cpu.addressing.memory[..program.len()].copy_from_slice(&program);
+
+        let res = replies(&mut cpu, &["Z0,a,1", "c", "p0", "s", "p0"]);
+        assert_eq!(res, vec!["OK", "T05swbreak:;", "05000000", "S05", "0c000000"]);
+        assert_eq!(cpu.xs[crate::R_PC], 0x0c);
+
+        // Breakpoints are removed when the session ends
+        assert_eq!(cpu.triggers().count(), 0);
+    }
+
+    #[test]
+    fn gdb_reverse() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+
+        // li x0, 5; li x1, 7; add x0, x1
+        let program = [0x40, 5, 0, 0, 0, 0x41, 7, 0, 0, 0, 0x80, 0x01];
+        cpu.addressing.memory[..program.len()].copy_from_slice(&program);
+        assert_eq!(replies(&mut cpu, &["bs"]), vec!["E01"]);
+
+        cpu.set_history(Some(crate::history::History::new(100)));
+        let res = replies(&mut cpu, &["s", "s", "s", "bs", "p0", "Z0,5,1", "bc", "bc"]);
+        assert_eq!(res[3..], ["S05", "05000000", "OK", "T05swbreak:;", "T05replaylog:begin;"]);
+    }
+
+    #[test]
+    fn gdb_watchpoints() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+
+        // li x0, 5; sw x0, [0x1000]; li x1, 7
+        let program = [0x40, 5, 0, 0, 0, 0xc0, 0x00, 0x10, 0, 0, 0x41, 7, 0, 0, 0];
+        cpu.addressing.memory[..program.len()].copy_from_slice(&program);
+
+        let res = replies(&mut cpu, &["Z2,1002,1", "c", "z2,1002,1", "Z1,0,1", "qSupported:hwbreak+"]);
+        assert_eq!(res[..4], ["OK", "T05watch:1002;", "OK", ""]);
+        assert!(!res[4].contains("hwbreak"));
+        assert_eq!(cpu.xs[crate::R_PC], 0x0a);
+    }
+
+    #[test]
+    fn gdb_target_xml() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        let res = replies(&mut cpu, &["qXfer:features:read:target.xml:0,5", "qXfer:features:read:target.xml:100000,10"]);
+        assert_eq!(res, vec!["m<?xml", "l"]);
+
+        // gdb ignores descriptions for architectures it doesn't know, so the registers alone
+        // describe the target
+        assert!(!TARGET_XML.contains("<architecture>"));
+        assert_eq!(TARGET_XML.matches("<reg ").count(), REGISTER_COUNT);
+    }
+}
@@ -0,0 +1,352 @@
+// Undo history for reverse execution
+//
+// While history is enabled, every step records the registers it changed, the bytes of physical
+// memory it overwrote, how it changed the interrupt queue and the state of the TLB, so that it can
+// be undone with the same timing.
+//
+// The state of devices is kept by the addressing backend, so it can't be undone. Undoing a step
+// that ran a device event removes the interrupt the event requested, but the backend keeps its
+// timers where the event left them, so running forward again doesn't run the event a second time.
+
+use std::collections::VecDeque;
+
+use crate::debug::StopReason;
+use crate::flags::Flags;
+use crate::perf::Counters;
+use crate::timing::Tlb;
+use crate::{Address, Cpu};
+
+#[derive(Default)]
+struct Undo {
+    // Old values of changed registers, with float registers numbered 16-31
+    regs: Vec<(u8, u32)>,
+    fpcr: u32,
+    flags: Flags,
+    interrupt_mask: u8,
+    memmap: u32,
+    system_sp: u32,
+    cycles: u64,
+    counters: Counters,
+    tlb: Tlb,
+
+    // Memory accessed as (address, physical address, value, old value if written)
+    accesses: Vec<(u32, u32, u8, Option<u8>)>,
+
+    // Interrupt taken from the front of the queue and number of interrupts queued
+    popped: Option<u32>,
+    pushed: usize,
+}
+
+pub struct History {
+    undo: VecDeque<Undo>,
+    capacity: usize,
+
+    // Record for the step in progress along with the state before it started
+    current: Undo,
+    xs: [u32; 16],
+    fs: [f32; 16],
+    queue_len: usize,
+    queue_front: Option<u32>,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            undo: VecDeque::new(),
+            capacity,
+            current: Undo::default(),
+            xs: [0; 16],
+            fs: [0.0; 16],
+            queue_len: 0,
+            queue_front: None,
+        }
+    }
+
+    // Number of steps that can be undone
+    pub fn len(&self) -> usize {
+        self.undo.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.undo.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+    }
+
+    pub(crate) fn access(&mut self, addr: u32, paddr: u32, value: u8, old: Option<u8>) {
+        self.current.accesses.push((addr, paddr, value, old));
+    }
+}
+
+impl<T> Cpu<T>
+where
+    T: Address,
+{
+    pub fn set_history(&mut self, history: Option<History>) -> Option<History> {
+        std::mem::replace(&mut self.history, history)
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    pub(crate) fn begin_history(&mut self) {
+        if let Some(history) = &mut self.history {
+            history.current = Undo {
+                fpcr: self.fpcr,
+                flags: self.flags,
+                interrupt_mask: self.interrupt_mask,
+                memmap: self.memmap,
+                system_sp: self.system_sp,
+                cycles: self.cycles,
+                counters: self.counters,
+                tlb: self.tlb,
+                ..Undo::default()
+            };
+            history.xs = self.xs;
+            history.fs = self.fs;
+            history.queue_len = self.interrupt_queue.len();
+            history.queue_front = self.interrupt_queue.front().copied();
+        }
+    }
+
+    pub(crate) fn end_history(&mut self, popped: bool) {
+        if let Some(history) = &mut self.history {
+            let mut undo = std::mem::take(&mut history.current);
+            for i in 0..16 {
+                if history.xs[i] != self.xs[i] {
+                    undo.regs.push((i as u8, history.xs[i]));
+                }
+                if history.fs[i].to_bits() != self.fs[i].to_bits() {
+                    undo.regs.push((i as u8 + 16, history.fs[i].to_bits()));
+                }
+            }
+
+            if popped {
+                undo.popped = history.queue_front;
+            }
+            undo.pushed = self.interrupt_queue.len() + popped as usize - history.queue_len;
+
+            if history.undo.len() == history.capacity {
+                history.undo.pop_front();
+            }
+            if history.capacity != 0 {
+                history.undo.push_back(undo);
+            }
+        }
+    }
+
+    // Undoes the last step, returning the watchpoint its first matching memory access would
+    // trigger if any. Returns None if there is no history to undo.
+    fn undo_step(&mut self) -> Option<Option<StopReason>> {
+        let undo = self.history.as_mut()?.undo.pop_back()?;
+
+        let mut watch = None;
+        for &(addr, paddr, value, old) in undo.accesses.iter().rev() {
+            if let Some(old) = old {
+                // A write that raised a bus error changed nothing, so failing again here is harmless
+                let _ = self.addressing.write(paddr, old);
+                self.invalidate_code(paddr, 1);
+            }
+
+            let write = old.is_some();
+            if let Some(id) = self.triggers.watching(addr, paddr, write) {
+                watch = Some(StopReason::Watchpoint { id, addr, paddr, value, write });
+            }
+        }
+
+        for &(reg, old) in undo.regs.iter() {
+            let reg = reg as usize;
+            if reg < 16 {
+                self.xs[reg] = old;
+            } else {
+                self.fs[reg - 16] = f32::from_bits(old);
+            }
+        }
+        self.fpcr = undo.fpcr;
+        self.flags = undo.flags;
+        self.interrupt_mask = undo.interrupt_mask;
+        self.memmap = undo.memmap;
+        self.system_sp = undo.system_sp;
+        self.cycles = undo.cycles;
+        self.counters = undo.counters;
+        self.tlb = undo.tlb;
+
+        for _ in 0..undo.pushed {
+            self.interrupt_queue.pop_back();
+        }
+        if let Some(interrupt) = undo.popped {
+            self.interrupt_queue.push_front(interrupt);
+        }
+
+        Some(watch)
+    }
+
+    // Undoes the last step, returning false if there is no history left
+    pub fn reverse_step(&mut self) -> bool {
+        self.undo_step().is_some()
+    }
+
+    // Undoes at most limit steps, stopping early at a breakpoint or before an instruction that
+    // triggered a watchpoint
+    pub fn reverse_continue(&mut self, limit: u64) -> StopReason {
+        for _ in 0..limit {
+            match self.undo_step() {
+                Some(Some(watch)) => return watch,
+                Some(None) => (),
+                None => return StopReason::HistoryExhausted,
+            }
+
+            if let Some(reason) = self.breakpoint_hit() {
+                return reason;
+            }
+        }
+
+        StopReason::StepLimit
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::debug::{Access, Space, Trigger};
+    use crate::flags::Flag;
+    use crate::timing::EventQueue;
+    use crate::{BusError, SimpleAddress, R_PC};
+
+    // li x0, 5; sw x0, [0x1000]; li x1, 7; add x0, x1; sw x0, [0x1000]
+    const PROGRAM: [u8; 22] = [
+        0x40, 5, 0, 0, 0, 0xc0, 0x00, 0x10, 0, 0, 0x41, 7, 0, 0, 0, 0x80, 0x01, 0xc0, 0x00, 0x10, 0, 0,
+    ];
+
+    fn cpu() -> Cpu<SimpleAddress> {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[..PROGRAM.len()].copy_from_slice(&PROGRAM);
+        cpu.set_history(Some(History::new(100)));
+        cpu
+    }
+
+    #[test]
+    fn history_reverse_step() {
+        let mut cpu = cpu();
+        cpu.run(5);
+        assert_eq!(cpu.addressing.memory[0x1000], 12);
+
+        assert!(cpu.reverse_step());
+        assert_eq!(cpu.addressing.memory[0x1000], 5);
+        assert_eq!(cpu.xs[R_PC], 0x11);
+
+        assert!(cpu.reverse_step());
+        assert_eq!(cpu.xs[0], 5);
+        assert_eq!(cpu.flags(), 0x80);
+
+        while cpu.reverse_step() {}
+        assert_eq!(cpu.xs, [0; 16]);
+        assert_eq!(cpu.flags(), 0);
+        assert_eq!(cpu.cycles, 0);
+        assert_eq!(cpu.addressing.memory[0x1000], 0);
+    }
+
+    #[test]
+    fn history_reverse_continue() {
+        let mut cpu = cpu();
+        cpu.run(5);
+
+        let id = cpu.add_trigger(Trigger::Breakpoint { space: Space::Virtual, addr: 0x0a });
+        assert_eq!(cpu.reverse_continue(100), StopReason::Breakpoint { id, addr: 0x0a });
+        cpu.remove_trigger(id);
+
+        let id = cpu.add_trigger(Trigger::Watchpoint {
+            space: Space::Virtual,
+            start: 0x1000,
+            len: 4,
+            access: Access::Write,
+        });
+        let reason = cpu.reverse_continue(100);
+        assert_eq!(reason, StopReason::Watchpoint { id, addr: 0x1000, paddr: 0x1000, value: 5, write: true });
+        assert_eq!(cpu.xs[R_PC], 0x05);
+        assert_eq!(cpu.addressing.memory[0x1000], 0);
+
+        assert_eq!(cpu.reverse_continue(100), StopReason::HistoryExhausted);
+        assert_eq!(cpu.xs[R_PC], 0);
+    }
+
+    #[test]
+    fn history_interrupt_queue() {
+        let mut cpu = cpu();
+        cpu.set_flags(0x0b);
+        cpu.irq(3);
+        cpu.step();
+        assert!(cpu.interrupt_queue.is_empty());
+
+        assert!(cpu.reverse_step());
+        assert_eq!(cpu.interrupt_queue.front(), Some(&3));
+    }
+
+    #[test]
+    fn history_tlb() {
+        // The program identity mapped with the page table at 0x20000
+        let mut cpu = cpu();
+        cpu.addressing.memory[0x20000..0x20004].copy_from_slice(&0x30000u32.to_le_bytes());
+        cpu.addressing.memory[0x30000..0x30004].copy_from_slice(&0xf0000000u32.to_le_bytes());
+        cpu.memmap = 0x20000;
+        cpu.set_flag(Flag::MemmapEnable, true);
+
+        // The first step misses the TLB, so running it again after undoing it misses again
+        cpu.run(2);
+        let cycles = cpu.cycles;
+        cpu.reverse_step();
+        cpu.reverse_step();
+        cpu.run(2);
+        assert_eq!(cpu.cycles, cycles);
+    }
+
+    // Raises interrupt 1 every 4 cycles
+    struct Timer {
+        memory: SimpleAddress,
+        events: EventQueue,
+    }
+
+    impl Address for Timer {
+        fn read(&mut self, addr: u32) -> Result<u8, BusError> {
+            self.memory.read(addr)
+        }
+
+        fn write(&mut self, addr: u32, data: u8) -> Result<(), BusError> {
+            self.memory.write(addr, data)
+        }
+
+        fn next_event(&self) -> Option<u64> {
+            self.events.next()
+        }
+
+        fn run_event(&mut self, now: u64) -> Option<u8> {
+            self.events.pop_due(now)?;
+            self.events.schedule(now + 4, 0);
+            Some(1)
+        }
+    }
+
+    #[test]
+    fn history_device_events() {
+        // Each nop costs one cycle
+        let mut events = EventQueue::new();
+        events.schedule(4, 0);
+        let mut cpu = Cpu::new(Timer { memory: SimpleAddress::default(), events });
+        cpu.addressing.memory.memory[..8].copy_from_slice(&[0x3f; 8]);
+        cpu.set_history(Some(History::new(100)));
+        cpu.run(4);
+        assert_eq!(cpu.interrupt_queue, [1]);
+
+        // Undoing the step that ran the event drops its interrupt, but not the timer's progress
+        cpu.reverse_step();
+        assert!(cpu.interrupt_queue.is_empty());
+        assert_eq!(cpu.addressing.events.next(), Some(8));
+        cpu.run(1);
+        assert!(cpu.interrupt_queue.is_empty());
+        cpu.run(4);
+        assert_eq!(cpu.interrupt_queue, [1]);
+    }
+}
@@ -1,5 +1,9 @@
+use std::cmp::Ordering;
 use std::collections::VecDeque;
 
+use flags::{Flag, Flags};
+use float::Rounding;
+
 /*
 - interrupts
 - returning from interrupts
@@ -9,15 +13,56 @@ use std::collections::VecDeque;
 
 */
 
-const READ:  u8 = 0b100;
-const WRITE: u8 = 0b010;
-const EXEC:  u8 = 0b001;
+pub mod backtrace;
+pub mod block;
+pub mod coverage;
+pub mod debug;
+pub mod decode;
+pub mod disasm;
+pub mod flags;
+pub mod float;
+pub mod gdb;
+pub mod history;
+pub mod lines;
+pub mod link;
+pub mod loader;
+pub mod memory;
+pub mod object;
+pub mod perf;
+pub mod profile;
+pub mod snapshot;
+pub mod symbols;
+pub mod timing;
+pub mod trace;
+
+pub const READ:  u8 = 0b100;
+pub const WRITE: u8 = 0b010;
+pub const EXEC:  u8 = 0b001;
+
+// Size of a page mapped by a second level page table entry
+pub const PAGE_SIZE: u32 = 0x10000;
+
+// Parses a decimal or `0x` prefixed hexadecimal number, as used by the debugger, map files and
+// linker scripts
+pub fn parse_num(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum InvalidMemoryAccess {
     UsedFreePage,
     InvalidPermissions(u8, u8),
-    UnprivilegedOpcode
+    UnprivilegedOpcode,
+
+    // Virtual address of a multi-byte access that is not aligned to its size while alignment
+    // checking is enabled
+    Misaligned(u32),
+
+    // Physical address the addressing backend has no memory for
+    BusError(u32),
 }
 
 impl std::fmt::Display for InvalidMemoryAccess {
@@ -28,40 +73,220 @@ impl std::fmt::Display for InvalidMemoryAccess {
 
 impl std::error::Error for InvalidMemoryAccess {}
 
+// A physical access the addressing backend could not complete, at the address of the first byte
+// that failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusError(pub u32);
+
+impl std::fmt::Display for BusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(f, "bus error at physical {:#010x}", self.0)
+    }
+}
+
+impl std::error::Error for BusError {}
+
+impl From<BusError> for InvalidMemoryAccess {
+    fn from(e: BusError) -> InvalidMemoryAccess {
+        InvalidMemoryAccess::BusError(e.0)
+    }
+}
+
 pub trait Address {
-    fn read(&mut self, addr: u32) -> u8;
+    fn read(&mut self, addr: u32) -> Result<u8, BusError>;
+
+    fn write(&mut self, addr: u32, data: u8) -> Result<(), BusError>;
+
+    // Multi-byte accesses are little endian, with addresses wrapping around the address space.
+    // Implementations can override them to access memory more directly than a byte at a time, but
+    // should still fail at the first byte that fails, leaving the bytes before it written.
+    fn read_u16(&mut self, addr: u32) -> Result<u16, BusError> {
+        Ok(self.read(addr)? as u16 | (self.read(addr.wrapping_add(1))? as u16) << 8)
+    }
+
+    fn read_u32(&mut self, addr: u32) -> Result<u32, BusError> {
+        Ok(self.read_u16(addr)? as u32 | (self.read_u16(addr.wrapping_add(2))? as u32) << 16)
+    }
+
+    fn write_u16(&mut self, addr: u32, data: u16) -> Result<(), BusError> {
+        self.write(addr, data as u8)?;
+        self.write(addr.wrapping_add(1), (data >> 8) as u8)
+    }
+
+    fn write_u32(&mut self, addr: u32, data: u32) -> Result<(), BusError> {
+        self.write_u16(addr, data as u16)?;
+        self.write_u16(addr.wrapping_add(2), (data >> 16) as u16)
+    }
+
+    fn read_slice(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), BusError> {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = self.read(addr.wrapping_add(i as u32))?;
+        }
+        Ok(())
+    }
+
+    fn write_slice(&mut self, addr: u32, data: &[u8]) -> Result<(), BusError> {
+        for (i, &byte) in data.iter().enumerate() {
+            self.write(addr.wrapping_add(i as u32), byte)?;
+        }
+        Ok(())
+    }
+
+    // Size of the physical address space backed by this implementation
+    fn size(&self) -> u64 {
+        1 << 32
+    }
+
+    // Whether a physical address belongs to a memory mapped device, which makes accessing it
+    // slower
+    fn is_device(&self, _addr: u32) -> bool {
+        false
+    }
+
+    // Cycle at which the backend next needs run_event to be called, if any
+    fn next_event(&self) -> Option<u64> {
+        None
+    }
+
+    // Called once the cycle counter reaches next_event, returning a maskable interrupt to request
+    fn run_event(&mut self, _now: u64) -> Option<u8> {
+        None
+    }
 
-    fn write(&mut self, addr: u32, data: u8);
+    // Physical address the cpu starts executing at after a reset
+    fn reset_vector(&self) -> u32 {
+        0
+    }
+
+    // Called when the cpu is reset, for devices to return to their power-on state. Memory keeps
+    // its contents.
+    fn reset(&mut self) {}
+}
+
+// What a backend does with accesses to physical addresses past the memory it has
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unbacked {
+    // Reads return zero and writes are ignored
+    ReadZero,
+
+    // Accesses raise a bus error exception
+    BusError,
 }
 
-const SIMPLE_ADDRESS_SIZE: usize = 0x1000000;
+const SIMPLE_ADDRESS_SIZE: u32 = 0x1000000;
 
+// Memory allocated up front from physical address 0
 pub struct SimpleAddress {
     memory: Vec<u8>,
+    unbacked: Unbacked,
+}
+
+impl SimpleAddress {
+    pub fn new(size: u32, unbacked: Unbacked) -> SimpleAddress {
+        SimpleAddress { memory: vec![0; size as usize], unbacked }
+    }
 }
 
 impl Default for SimpleAddress {
     fn default() -> SimpleAddress {
-        SimpleAddress {
-            memory: vec![0; SIMPLE_ADDRESS_SIZE],
+        SimpleAddress::new(SIMPLE_ADDRESS_SIZE, Unbacked::ReadZero)
+    }
+}
+
+impl SimpleAddress {
+    fn unbacked(&self, addr: u32) -> Result<(), BusError> {
+        match self.unbacked {
+            Unbacked::ReadZero => Ok(()),
+            Unbacked::BusError => Err(BusError(addr)),
         }
     }
 }
 
 impl Address for SimpleAddress {
-    fn read(&mut self, addr: u32) -> u8 {
-        if addr < 0x1000000 {
-            self.memory[addr as usize]
-        } else {
-            0
+    fn read(&mut self, addr: u32) -> Result<u8, BusError> {
+        match self.memory.get(addr as usize) {
+            Some(&byte) => Ok(byte),
+            None => self.unbacked(addr).map(|_| 0),
+        }
+    }
+
+    fn write(&mut self, addr: u32, data: u8) -> Result<(), BusError> {
+        match self.memory.get_mut(addr as usize) {
+            Some(byte) => {
+                *byte = data;
+                Ok(())
+            }
+            None => self.unbacked(addr),
+        }
+    }
+
+    fn read_u16(&mut self, addr: u32) -> Result<u16, BusError> {
+        match self.memory.get(addr as usize..addr as usize + 2) {
+            Some(bytes) => Ok(u16::from_le_bytes([bytes[0], bytes[1]])),
+            None => Ok(self.read(addr)? as u16 | (self.read(addr.wrapping_add(1))? as u16) << 8),
         }
     }
 
-    fn write(&mut self, addr: u32, data: u8) {
-        if addr < 0x1000000 {
-            self.memory[addr as usize] = data;
+    fn read_u32(&mut self, addr: u32) -> Result<u32, BusError> {
+        match self.memory.get(addr as usize..addr as usize + 4) {
+            Some(bytes) => Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])),
+            None => Ok(self.read_u16(addr)? as u32 | (self.read_u16(addr.wrapping_add(2))? as u32) << 16),
         }
     }
+
+    fn write_u16(&mut self, addr: u32, data: u16) -> Result<(), BusError> {
+        match self.memory.get_mut(addr as usize..addr as usize + 2) {
+            Some(bytes) => {
+                bytes.copy_from_slice(&data.to_le_bytes());
+                Ok(())
+            }
+            None => self.write_slice(addr, &data.to_le_bytes()),
+        }
+    }
+
+    fn write_u32(&mut self, addr: u32, data: u32) -> Result<(), BusError> {
+        match self.memory.get_mut(addr as usize..addr as usize + 4) {
+            Some(bytes) => {
+                bytes.copy_from_slice(&data.to_le_bytes());
+                Ok(())
+            }
+            None => self.write_slice(addr, &data.to_le_bytes()),
+        }
+    }
+
+    fn read_slice(&mut self, addr: u32, buf: &mut [u8]) -> Result<(), BusError> {
+        match self.memory.get(addr as usize..addr as usize + buf.len()) {
+            Some(bytes) => {
+                buf.copy_from_slice(bytes);
+                Ok(())
+            }
+            None => {
+                for (i, byte) in buf.iter_mut().enumerate() {
+                    *byte = self.read(addr.wrapping_add(i as u32))?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn write_slice(&mut self, addr: u32, data: &[u8]) -> Result<(), BusError> {
+        match self.memory.get_mut(addr as usize..addr as usize + data.len()) {
+            Some(bytes) => {
+                bytes.copy_from_slice(data);
+                Ok(())
+            }
+            None => {
+                for (i, &byte) in data.iter().enumerate() {
+                    self.write(addr.wrapping_add(i as u32), byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.memory.len() as u64
+    }
 }
 
 pub struct Cpu<T>
@@ -78,23 +303,11 @@ where
     // General purpose floating point registers
     fs: [f32; 16],
 
-    // Flags
-    //                      MRFAN PCVZQLLL
-    // 10987654 32109876 54321098 76543210
-    // 33222222 22221111 111111
-    // LLL      - Last interrupt
-    // Q        - Interrupt enable
-    // Z        - Zero
-    // V        - oVerflow
-    // C        - Carry
-    // P        - Parity
-    // N        - Negative
-    // A        - nAn
-    // F        - inFinite
-    // R        - user Ring (if enabled, certain features will be locked down until an interrupt
-    //            occurs)
-    // M        - Memory map enable
-    flags: u32,
+    // Float status and control register, see `float`
+    fpcr: u32,
+
+    // Flags, laid out as in the README (see `flags`)
+    flags: Flags,
 
     // Bits that are marked as 0 disable those interrupts from being added to the queue and being
     // handled
@@ -109,98 +322,211 @@ where
     // Queue of previously requested interrupts
     interrupt_queue: VecDeque<u32>,
 
+    // Breakpoints and watchpoints
+    triggers: debug::Triggers,
+
+    // Execution trace recorder
+    tracer: Option<trace::Tracer>,
+
+    // Undo history for reverse execution
+    history: Option<history::History>,
+
+    // Timing model
+    cycles: u64,
+    costs: timing::CostTable,
+    tlb: timing::Tlb,
+
+    // Guest visible performance counters
+    counters: perf::Counters,
+
+    // Sampling profiler
+    profiler: Option<profile::Profiler>,
+
+    // Code coverage recorder
+    coverage: Option<coverage::Coverage>,
+
+    // Predecoded instructions, if enabled
+    decode_cache: Option<Box<decode::DecodeCache>>,
+
+    // Translated basic blocks, if enabled
+    blocks: Option<Box<block::BlockCache>>,
+
     addressing: T,
 }
 
-// Flags
-static F_INTERRUPT_ENABLE: u32 = 3;
-static F_ZERO: u32 = 4;
-static F_OVERFLOW: u32 = 5;
-static F_CARRY: u32 = 6;
-static F_PARITY: u32 = 7;
-static F_NEGATIVE: u32 = 8;
-static F_NAN: u32 = 9;
-static F_INFINITE: u32 = 10;
-static F_USER_RING: u32 = 11;
-static F_MEMMAP_ENABLE: u32 = 12;
-
 // Registers
 static R_INT: usize = 12;
 static R_PC: usize = 13;
 static R_BASE: usize = 14;
 static R_SP: usize = 15;
 
-macro_rules! clear_flags {
-    ($self: ident, $($flags: ident),*) => {
-        $self.flags &= !($((1 << $flags))|*);
-    }
-}
-
 impl<T> Cpu<T>
 where
     T: Address,
 {
     pub fn new(t: T) -> Cpu<T> {
-        Cpu {
+        let mut cpu = Cpu {
             xs: [0; 16],
             fs: [0.0; 16],
-            flags: 0,
+            fpcr: 0,
+            flags: Flags::default(),
             interrupt_mask: 0xff,
             memmap: 0,
             system_sp: 0,
             interrupt_queue: VecDeque::new(),
+            triggers: debug::Triggers::default(),
+            tracer: None,
+            history: None,
+            cycles: 0,
+            costs: timing::CostTable::default(),
+            tlb: timing::Tlb::default(),
+            counters: perf::Counters::default(),
+            profiler: None,
+            coverage: None,
+            decode_cache: None,
+            blocks: None,
             addressing: t,
+        };
+        cpu.xs[R_PC] = cpu.addressing.reset_vector();
+        cpu
+    }
+
+    // Restores the power-on state: every register is cleared, the pc is set to the reset vector,
+    // the cpu is in the system ring with paging and interrupts off, and the cycle and performance
+    // counters start again from zero. Memory keeps its contents, and breakpoints and the
+    // debugging tools stay set up, but recorded undo history is dropped.
+    pub fn reset(&mut self) {
+        self.xs = [0; 16];
+        self.fs = [0.0; 16];
+        self.fpcr = 0;
+        self.flags = Flags::default();
+        self.interrupt_mask = 0xff;
+        self.memmap = 0;
+        self.system_sp = 0;
+        self.interrupt_queue.clear();
+        self.cycles = 0;
+        self.tlb = timing::Tlb::default();
+        self.reset_counters();
+        if let Some(history) = &mut self.history {
+            history.clear();
         }
+        self.flush_decode_cache();
+        self.addressing.reset();
+        self.xs[R_PC] = self.addressing.reset_vector();
+    }
+
+    pub fn int_register(&self, x: usize) -> u32 {
+        self.xs[x]
+    }
+
+    pub fn set_int_register(&mut self, x: usize, val: u32) {
+        self.xs[x] = val;
+    }
+
+    pub fn float_register(&self, f: usize) -> f32 {
+        self.fs[f]
+    }
+
+    pub fn set_float_register(&mut self, f: usize, val: f32) {
+        self.fs[f] = val;
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.xs[R_PC]
+    }
+
+    pub fn flags(&self) -> u32 {
+        self.flags.bits()
+    }
+
+    pub fn set_flags(&mut self, flags: u32) {
+        self.flags = Flags::from_bits(flags);
+    }
+
+    pub fn interrupt_mask(&self) -> u8 {
+        self.interrupt_mask
+    }
+
+    pub fn set_interrupt_mask(&mut self, mask: u8) {
+        self.interrupt_mask = mask;
+    }
+
+    pub fn memmap(&self) -> u32 {
+        self.memmap
+    }
+
+    pub fn set_memmap(&mut self, memmap: u32) {
+        self.memmap = memmap;
+    }
+
+    pub fn system_sp(&self) -> u32 {
+        self.system_sp
+    }
+
+    pub fn interrupt_queue(&self) -> &VecDeque<u32> {
+        &self.interrupt_queue
+    }
+
+    pub fn addressing(&self) -> &T {
+        &self.addressing
+    }
+
+    // Memory may be changed through the returned reference, so this flushes the decode cache
+    pub fn addressing_mut(&mut self) -> &mut T {
+        self.flush_decode_cache();
+        &mut self.addressing
+    }
+
+    // Reads a byte of physical memory, keeping the decode cache and translated blocks
+    pub fn read_physical(&mut self, paddr: u32) -> Result<u8, BusError> {
+        self.addressing.read(paddr)
+    }
+
+    // Translates a virtual address into a physical address the same way the cpu would, without
+    // touching any registers
+    pub fn translate(&mut self, addr: u32, permissions: u8) -> Result<u32, InvalidMemoryAccess> {
+        self.check_memory(addr, permissions)
     }
 
     fn check_memory(&mut self, addr: u32, permissions: u8) -> Result<u32, InvalidMemoryAccess> {
-        if self.flags & (1 << F_MEMMAP_ENABLE) != 0 {
+        let addr = if self.get_flag(Flag::MemmapEnable) {
             let table_addr = self.memmap;
-            let table_addr = self.addressing.read(table_addr + (addr >> 24)) as u32
-                | (self.addressing.read(table_addr + (addr >> 24) + 1) as u32) << 8
-                | (self.addressing.read(table_addr + (addr >> 24) + 2) as u32) << 16
-                | (self.addressing.read(table_addr + (addr >> 24) + 3) as u32) << 24;
+            let table_addr = self.addressing.read_u32(table_addr + (addr >> 24))?;
 
             if table_addr == 0 {
                 return Err(InvalidMemoryAccess::UsedFreePage);
             }
 
-            let addr = (self.addressing.read(table_addr + (addr >> 16 & 0xff)) as u32
-                | (self.addressing.read(table_addr + (addr >> 16 & 0xff) + 1) as u32) << 8
-                | (self.addressing.read(table_addr + (addr >> 16 & 0xff) + 2) as u32) << 16
-                | (self.addressing.read(table_addr + (addr >> 16 & 0xff) + 3) as u32) << 24)
-                + (addr & 0xffff);
+            let addr = self.addressing.read_u32(table_addr + (addr >> 16 & 0xff))? + (addr & 0xffff);
             let (p, addr) = (((addr & 0xf0000000) >> 28) as u8, addr & 0x0fffffff);
 
             if p & 0x08 == 0 {
-                Err(InvalidMemoryAccess::UsedFreePage)
+                return Err(InvalidMemoryAccess::UsedFreePage);
             } else if p & permissions != permissions {
-                Err(InvalidMemoryAccess::InvalidPermissions(p, permissions))
-            } else {
-                Ok(addr)
+                return Err(InvalidMemoryAccess::InvalidPermissions(p, permissions));
             }
+            addr
         } else {
-            Ok(addr)
-        }
+            addr
+        };
+        Ok(addr)
     }
 
-    fn set_flag(&mut self, flag: u32, val: bool) {
-        self.flags |= (val as u32) << flag;
+    fn set_flag(&mut self, flag: Flag, val: bool) {
+        self.flags.set(flag, val);
     }
 
-    fn get_flag(&self, flag: u32) -> bool {
-        self.flags & (1 << flag) != 0
+    fn get_flag(&self, flag: Flag) -> bool {
+        self.flags.get(flag)
     }
 
     fn set_carry(&mut self, val: bool) {
-        clear_flags!(self, F_CARRY);
-        self.set_flag(F_CARRY, val);
+        self.set_flag(Flag::Carry, val);
     }
 
     fn set_user_ring(&mut self, val: bool) -> Result<(), InvalidMemoryAccess> {
-        if !self.get_flag(F_USER_RING) {
-            clear_flags!(self, F_USER_RING);
-            self.set_flag(F_USER_RING, val);
+        if !self.get_flag(Flag::UserRing) {
+            self.set_flag(Flag::UserRing, val);
             Ok(())
         } else {
             Err(InvalidMemoryAccess::UnprivilegedOpcode)
@@ -208,9 +534,8 @@ where
     }
 
     fn set_memmap_enable(&mut self, val: bool) -> Result<(), InvalidMemoryAccess> {
-        if !self.get_flag(F_USER_RING) {
-            clear_flags!(self, F_MEMMAP_ENABLE);
-            self.set_flag(F_MEMMAP_ENABLE, val);
+        if !self.get_flag(Flag::UserRing) {
+            self.set_flag(Flag::MemmapEnable, val);
             Ok(())
         } else {
             Err(InvalidMemoryAccess::UnprivilegedOpcode)
@@ -218,32 +543,17 @@ where
     }
 
     fn set_interrupt_enable(&mut self, val: bool) -> Result<(), InvalidMemoryAccess> {
-        if !self.get_flag(F_USER_RING) {
-            clear_flags!(self, F_INTERRUPT_ENABLE);
-            self.set_flag(F_INTERRUPT_ENABLE, val);
+        if !self.get_flag(Flag::UserRing) {
+            self.set_flag(Flag::InterruptEnable, val);
             Ok(())
         } else {
             Err(InvalidMemoryAccess::UnprivilegedOpcode)
         }
     }
 
-    fn call(&mut self) -> Result<(), InvalidMemoryAccess> {
-        let addr = (self.exec()? as u32)
-            | (self.exec()? as u32) << 8
-            | (self.exec()? as u32) << 16
-            | (self.exec()? as u32) << 24;
-
-        let data = self.xs[R_BASE];
-        for i in (0..4).rev() {
-            self.write(self.xs[R_SP], (data >> (i * 8)) as u8)?;
-            self.xs[R_SP] -= 1;
-        }
-
-        let data = self.xs[R_PC];
-        for i in (0..4).rev() {
-            self.write(self.xs[R_SP], (data >> (i * 8)) as u8)?;
-            self.xs[R_SP] -= 1;
-        }
+    fn call(&mut self, addr: u32) -> Result<(), InvalidMemoryAccess> {
+        self.push_u32(self.xs[R_BASE])?;
+        self.push_u32(self.xs[R_PC])?;
 
         self.xs[R_BASE] = self.xs[R_SP];
         self.xs[R_PC] = addr;
@@ -252,16 +562,8 @@ where
 
     fn ret(&mut self) -> Result<(), InvalidMemoryAccess> {
         self.xs[R_PC] = 0;
-        for i in 0..4 {
-            self.xs[R_BASE] += 1;
-            self.xs[R_PC] |= (self.read(self.xs[R_BASE])? as u32) << (8 * i);
-        }
-
-        let mut data = 0;
-        for i in 0..4 {
-            self.xs[R_BASE] += 1;
-            data |= (self.read(self.xs[R_BASE])? as u32) << (8 * i);
-        }
+        self.xs[R_PC] = self.pop_frame_u32()?;
+        let data = self.pop_frame_u32()?;
 
         self.xs[R_SP] = self.xs[R_BASE];
         self.xs[R_BASE] = data;
@@ -269,72 +571,46 @@ where
         Ok(())
     }
 
-    fn branch_true(&mut self, flag: u32) -> Result<(), InvalidMemoryAccess> {
-        let addr = (self.exec()? as u32)
-            | (self.exec()? as u32) << 8
-            | (self.exec()? as u32) << 16
-            | (self.exec()? as u32) << 24;
-        if self.flags & (1 << flag) != 0 {
+    fn branch_true(&mut self, flag: Flag, addr: u32) {
+        let taken = self.get_flag(flag);
+        self.cover_branch(taken);
+        if taken {
             self.xs[R_PC] = addr;
+            self.charge(self.costs.branch_taken);
+            self.counters.count(perf::Counter::BranchesTaken, 1);
         }
-        Ok(())
     }
 
-    fn branch_false(&mut self, flag: u32) -> Result<(), InvalidMemoryAccess> {
-        let addr = (self.exec()? as u32)
-            | (self.exec()? as u32) << 8
-            | (self.exec()? as u32) << 16
-            | (self.exec()? as u32) << 24;
-        if self.flags & (1 << flag) == 0 {
+    fn branch_false(&mut self, flag: Flag, addr: u32) {
+        let taken = !self.get_flag(flag);
+        self.cover_branch(taken);
+        if taken {
             self.xs[R_PC] = addr;
+            self.charge(self.costs.branch_taken);
+            self.counters.count(perf::Counter::BranchesTaken, 1);
         }
-        Ok(())
     }
 
-    fn load_lit_int(&mut self, x0: usize) -> Result<(), InvalidMemoryAccess> {
-        let data = (self.exec()? as u32)
-            | (self.exec()? as u32) << 8
-            | (self.exec()? as u32) << 16
-            | (self.exec()? as u32) << 24;
+    fn load_lit_int(&mut self, x0: usize, data: u32) {
         self.xs[x0] = data;
         self.update_flags_int(data);
-        Ok(())
     }
 
-    fn load_lit_float(&mut self, f0: usize) -> Result<(), InvalidMemoryAccess> {
-        let data = (self.exec()? as u32)
-            | (self.exec()? as u32) << 8
-            | (self.exec()? as u32) << 16
-            | (self.exec()? as u32) << 24;
+    fn load_lit_float(&mut self, f0: usize, data: u32) {
         let data = f32::from_bits(data);
         self.fs[f0] = data;
         self.update_flags_float(data);
-        Ok(())
     }
 
-    fn load_int(&mut self, x0: usize) -> Result<(), InvalidMemoryAccess> {
-        let addr = (self.exec()? as u32)
-            | (self.exec()? as u32) << 8
-            | (self.exec()? as u32) << 16
-            | (self.exec()? as u32) << 24;
-        let data = (self.read(addr)? as u32)
-            | (self.read(addr + 1)? as u32) << 8
-            | (self.read(addr + 2)? as u32) << 16
-            | (self.read(addr + 3)? as u32) << 24;
+    fn load_int(&mut self, x0: usize, addr: u32) -> Result<(), InvalidMemoryAccess> {
+        let data = self.read_u32(addr)?;
         self.xs[x0] = data;
         self.update_flags_int(data);
         Ok(())
     }
 
-    fn load_float(&mut self, f0: usize) -> Result<(), InvalidMemoryAccess> {
-        let addr = (self.exec()? as u32)
-            | (self.exec()? as u32) << 8
-            | (self.exec()? as u32) << 16
-            | (self.exec()? as u32) << 24;
-        let data = (self.read(addr)? as u32)
-            | (self.read(addr + 1)? as u32) << 8
-            | (self.read(addr + 2)? as u32) << 16
-            | (self.read(addr + 3)? as u32) << 24;
+    fn load_float(&mut self, f0: usize, addr: u32) -> Result<(), InvalidMemoryAccess> {
+        let data = self.read_u32(addr)?;
         let data = f32::from_bits(data);
         self.fs[f0] = data;
         self.update_flags_float(data);
@@ -342,17 +618,16 @@ where
     }
 
     fn iadd(&mut self, x0: usize, x1: usize) {
-        let res = self.xs[x0] as u64 + self.xs[x1] as u64 + self.get_flag(F_CARRY) as u64;
-        clear_flags!(self, F_ZERO, F_OVERFLOW, F_CARRY, F_NEGATIVE, F_PARITY);
-        self.set_flag(F_ZERO, res as u32 == 0);
-        self.set_flag(F_NEGATIVE, res & 0x80000000 != 0);
-        self.set_flag(F_CARRY, res & 0x100000000 != 0);
+        let res = self.xs[x0] as u64 + self.xs[x1] as u64 + self.get_flag(Flag::Carry) as u64;
+        self.set_flag(Flag::Zero, res as u32 == 0);
+        self.set_flag(Flag::Negative, res & 0x80000000 != 0);
+        self.set_flag(Flag::Carry, res & 0x100000000 != 0);
         self.set_flag(
-            F_OVERFLOW,
+            Flag::Overflow,
             self.xs[x0] & 0x80000000 == self.xs[x1] & 0x80000000
                 && self.xs[x0] & 0x80000000 != res as u32 & 0x80000000,
         );
-        self.set_flag(F_PARITY, res & 1 != 0);
+        self.set_flag(Flag::Parity, res & 1 != 0);
         self.xs[x0] = res as u32;
     }
 
@@ -363,10 +638,9 @@ where
     }
 
     fn update_flags_int(&mut self, x: u32) {
-        clear_flags!(self, F_ZERO, F_NEGATIVE, F_PARITY);
-        self.set_flag(F_ZERO, x == 0);
-        self.set_flag(F_NEGATIVE, x & 0x80000000 != 0);
-        self.set_flag(F_PARITY, x & 1 != 0);
+        self.set_flag(Flag::Zero, x == 0);
+        self.set_flag(Flag::Negative, x & 0x80000000 != 0);
+        self.set_flag(Flag::Parity, x & 1 != 0);
     }
 
     fn imul(&mut self, x0: usize, x1: usize) {
@@ -384,31 +658,80 @@ where
         self.update_flags_int(self.xs[x0]);
     }
 
+    // Writes the result of an arithmetic instruction and the exceptions it raised
+    fn float_result(&mut self, f0: usize, (res, exceptions): (f32, u32)) {
+        self.fs[f0] = res;
+        self.update_flags_float(res);
+        self.raise_float(exceptions);
+    }
+
     fn update_flags_float(&mut self, x: f32) {
-        clear_flags!(self, F_ZERO, F_NEGATIVE, F_NAN, F_INFINITE);
-        self.set_flag(F_ZERO, x == 0.0);
-        self.set_flag(F_NEGATIVE, x.is_sign_negative());
-        self.set_flag(F_NAN, x.is_nan());
-        self.set_flag(F_INFINITE, x.is_infinite());
+        self.set_flag(Flag::Zero, x == 0.0);
+        self.set_flag(Flag::Negative, x.is_sign_negative());
+        self.set_flag(Flag::NaN, x.is_nan());
+        self.set_flag(Flag::Infinite, x.is_infinite());
     }
 
     fn fadd(&mut self, f0: usize, f1: usize) {
-        self.fs[f0] += self.fs[f1];
-        self.update_flags_float(self.fs[f0]);
+        let res = float::add(self.fs[f0], self.fs[f1], self.rounding());
+        self.float_result(f0, res);
     }
 
     fn fsub(&mut self, f0: usize, f1: usize) {
-        self.fs[f0] -= self.fs[f1];
-        self.update_flags_float(self.fs[f0]);
+        let res = float::sub(self.fs[f0], self.fs[f1], self.rounding());
+        self.float_result(f0, res);
     }
 
     fn fmul(&mut self, f0: usize, f1: usize) {
-        self.fs[f0] *= self.fs[f1];
-        self.update_flags_float(self.fs[f0]);
+        let res = float::mul(self.fs[f0], self.fs[f1], self.rounding());
+        self.float_result(f0, res);
     }
 
     fn fdiv(&mut self, f0: usize, f1: usize) {
-        self.fs[f0] /= self.fs[f1];
+        let res = float::div(self.fs[f0], self.fs[f1], self.rounding());
+        self.float_result(f0, res);
+    }
+
+    // Sets the flags as if for -1, 0 or 1 when f0 is less than, equal to or greater than f1, or
+    // for NaN if they are unordered, without changing either register
+    fn fcmp(&mut self, f0: usize, f1: usize) {
+        let res = match self.fs[f0].partial_cmp(&self.fs[f1]) {
+            Some(Ordering::Less) => -1.0,
+            Some(Ordering::Equal) => 0.0,
+            Some(Ordering::Greater) => 1.0,
+            None => f32::NAN,
+        };
+        self.update_flags_float(res);
+    }
+
+    fn fneg(&mut self, f0: usize, f1: usize) {
+        self.fs[f0] = -self.fs[f1];
+        self.update_flags_float(self.fs[f0]);
+    }
+
+    fn fabs(&mut self, f0: usize, f1: usize) {
+        self.fs[f0] = self.fs[f1].abs();
+        self.update_flags_float(self.fs[f0]);
+    }
+
+    fn fsqrt(&mut self, f0: usize, f1: usize) {
+        let res = float::sqrt(self.fs[f1], self.rounding());
+        self.float_result(f0, res);
+    }
+
+    // A NaN operand is ignored in favour of the other one
+    fn fmin(&mut self, f0: usize, f1: usize) {
+        self.fs[f0] = self.fs[f0].min(self.fs[f1]);
+        self.update_flags_float(self.fs[f0]);
+    }
+
+    fn fmax(&mut self, f0: usize, f1: usize) {
+        self.fs[f0] = self.fs[f0].max(self.fs[f1]);
+        self.update_flags_float(self.fs[f0]);
+    }
+
+    fn fround(&mut self, f0: usize, f1: usize, mode: Rounding) {
+        self.fs[f0] = float::round(self.fs[f1], mode);
         self.update_flags_float(self.fs[f0]);
     }
 
@@ -417,15 +740,12 @@ where
             (self.xs[x0] as u64) << self.xs[x1] as u64
         } else {
             0
-        } | self.get_flag(F_CARRY) as u64;
+        } | self.get_flag(Flag::Carry) as u64;
 
-        clear_flags!(self, F_ZERO, F_CARRY, F_NEGATIVE, F_PARITY);
-        self.set_flag(F_ZERO, res as u32 == 0);
-        self.set_flag(F_NEGATIVE, res & 0x80000000 != 0);
-        if self.xs[x1] == 1 {
-            self.set_flag(F_CARRY, res & 0x100000000 != 0);
-        }
-        self.set_flag(F_PARITY, res & 1 != 0);
+        self.set_flag(Flag::Zero, res as u32 == 0);
+        self.set_flag(Flag::Negative, res & 0x80000000 != 0);
+        self.set_flag(Flag::Carry, self.xs[x1] == 1 && res & 0x100000000 != 0);
+        self.set_flag(Flag::Parity, res & 1 != 0);
         self.xs[x0] = res as u32;
     }
 
@@ -434,15 +754,12 @@ where
             (self.xs[x0] as u64) >> self.xs[x1] as u64
         } else {
             0
-        } | self.get_flag(F_CARRY) as u64;
+        } | self.get_flag(Flag::Carry) as u64;
 
-        clear_flags!(self, F_ZERO, F_CARRY, F_NEGATIVE, F_PARITY);
-        self.set_flag(F_ZERO, res as u32 == 0);
-        self.set_flag(F_NEGATIVE, res & 0x80000000 != 0);
-        if self.xs[x1] == 1 {
-            self.set_flag(F_CARRY, self.xs[x0] & 1 != 0);
-        }
-        self.set_flag(F_PARITY, res & 1 != 0);
+        self.set_flag(Flag::Zero, res as u32 == 0);
+        self.set_flag(Flag::Negative, res & 0x80000000 != 0);
+        self.set_flag(Flag::Carry, self.xs[x1] == 1 && self.xs[x0] & 1 != 0);
+        self.set_flag(Flag::Parity, res & 1 != 0);
         self.xs[x0] = res as u32;
     }
 
@@ -471,14 +788,21 @@ where
         self.update_flags_float(self.fs[x0]);
     }
 
-    fn move_int_float(&mut self, x0: usize, f1: usize) {
-        self.xs[x0] = (self.fs[f1] as i32) as u32;
+    // Converts to a signed integer, saturating and setting the overflow flag if it is out of range
+    // or NaN, which is an invalid operation
+    fn move_int_float(&mut self, x0: usize, f1: usize, mode: Rounding) {
+        let (res, saturated) = float::to_int(self.fs[f1], mode);
+        self.xs[x0] = res as u32;
         self.update_flags_int(self.xs[x0]);
+        self.set_flag(Flag::Overflow, saturated);
+        if saturated {
+            self.raise_float(float::FP_INVALID);
+        }
     }
 
     fn move_float_int(&mut self, f0: usize, x1: usize) {
-        self.fs[f0] = (self.xs[x1] as i32) as f32;
-        self.update_flags_float(self.fs[f0]);
+        let res = float::from_int(self.xs[x1] as i32, self.rounding());
+        self.float_result(f0, res);
     }
 
     fn transmute_int_float(&mut self, x0: usize, f1: usize) {
@@ -493,10 +817,7 @@ where
 
     fn load_indirect_int(&mut self, x0: usize, addr: usize) -> Result<(), InvalidMemoryAccess> {
         let addr = self.xs[addr];
-        let data = (self.read(addr)? as u32)
-            | (self.read(addr + 1)? as u32) << 8
-            | (self.read(addr + 2)? as u32) << 16
-            | (self.read(addr + 3)? as u32) << 24;
+        let data = self.read_u32(addr)?;
         self.xs[x0] = data;
         self.update_flags_int(data);
         Ok(())
@@ -504,10 +825,7 @@ where
 
     fn load_indirect_float(&mut self, f0: usize, addr: usize) -> Result<(), InvalidMemoryAccess> {
         let addr = self.xs[addr];
-        let data = (self.read(addr)? as u32)
-            | (self.read(addr + 1)? as u32) << 8
-            | (self.read(addr + 2)? as u32) << 16
-            | (self.read(addr + 3)? as u32) << 24;
+        let data = self.read_u32(addr)?;
         let data = f32::from_bits(data);
         self.fs[f0] = data;
         self.update_flags_float(data);
@@ -516,16 +834,12 @@ where
 
     fn store_indirect_int(&mut self, x0: usize, addr: usize) -> Result<(), InvalidMemoryAccess> {
         let addr = self.xs[addr];
-        self.write(addr, self.xs[x0] as u8)?;
-        self.write(addr + 1, (self.xs[x0] >> 8) as u8)?;
-        self.write(addr + 2, (self.xs[x0] >> 16) as u8)?;
-        self.write(addr + 3, (self.xs[x0] >> 24) as u8)
+        self.write_u32(addr, self.xs[x0])
     }
 
     fn store_indirect_short(&mut self, x0: usize, addr: usize) -> Result<(), InvalidMemoryAccess> {
         let addr = self.xs[addr];
-        self.write(addr, self.xs[x0] as u8)?;
-        self.write(addr + 1, (self.xs[x0] >> 8) as u8)
+        self.write_u16(addr, self.xs[x0] as u16)
     }
 
     fn store_indirect_byte(&mut self, x0: usize, addr: usize) -> Result<(), InvalidMemoryAccess> {
@@ -535,234 +849,238 @@ where
 
     fn store_indirect_float(&mut self, f0: usize, addr: usize) -> Result<(), InvalidMemoryAccess> {
         let addr = self.xs[addr];
-        let data = self.fs[f0].to_bits();
-        self.write(addr, data as u8)?;
-        self.write(addr + 1, (data >> 8) as u8)?;
-        self.write(addr + 2, (data >> 16) as u8)?;
-        self.write(addr + 3, (data >> 24) as u8)
-    }
-
-    fn store_int(&mut self, x0: usize) -> Result<(), InvalidMemoryAccess> {
-        let addr = (self.exec()? as u32)
-            | (self.exec()? as u32) << 8
-            | (self.exec()? as u32) << 16
-            | (self.exec()? as u32) << 24;
-        self.write(addr, self.xs[x0] as u8)?;
-        self.write(addr + 1, (self.xs[x0] >> 8) as u8)?;
-        self.write(addr + 2, (self.xs[x0] >> 16) as u8)?;
-        self.write(addr + 3, (self.xs[x0] >> 24) as u8)
-    }
-
-    fn store_short(&mut self, x0: usize) -> Result<(), InvalidMemoryAccess> {
-        let addr = (self.exec()? as u32)
-            | (self.exec()? as u32) << 8
-            | (self.exec()? as u32) << 16
-            | (self.exec()? as u32) << 24;
-        self.write(addr, self.xs[x0] as u8)?;
-        self.write(addr + 1, (self.xs[x0] >> 8) as u8)
-    }
-
-    fn store_byte(&mut self, x0: usize) -> Result<(), InvalidMemoryAccess> {
-        let addr = (self.exec()? as u32)
-            | (self.exec()? as u32) << 8
-            | (self.exec()? as u32) << 16
-            | (self.exec()? as u32) << 24;
+        self.write_u32(addr, self.fs[f0].to_bits())
+    }
+
+    fn store_int(&mut self, x0: usize, addr: u32) -> Result<(), InvalidMemoryAccess> {
+        self.write_u32(addr, self.xs[x0])
+    }
+
+    fn store_short(&mut self, x0: usize, addr: u32) -> Result<(), InvalidMemoryAccess> {
+        self.write_u16(addr, self.xs[x0] as u16)
+    }
+
+    fn store_byte(&mut self, x0: usize, addr: u32) -> Result<(), InvalidMemoryAccess> {
         self.write(addr, self.xs[x0] as u8)
     }
 
-    fn store_float(&mut self, f0: usize) -> Result<(), InvalidMemoryAccess> {
-        let addr = (self.exec()? as u32)
-            | (self.exec()? as u32) << 8
-            | (self.exec()? as u32) << 16
-            | (self.exec()? as u32) << 24;
-        let data = self.fs[f0].to_bits();
-        self.write(addr, data as u8)?;
-        self.write(addr + 1, (data >> 8) as u8)?;
-        self.write(addr + 2, (data >> 16) as u8)?;
-        self.write(addr + 3, (data >> 24) as u8)
+    fn store_float(&mut self, f0: usize, addr: u32) -> Result<(), InvalidMemoryAccess> {
+        self.write_u32(addr, self.fs[f0].to_bits())
     }
 
     fn privileged_move(&mut self, x0: usize, p: usize) -> Result<(), InvalidMemoryAccess> {
-        if p as u32 & F_USER_RING != 0 {
+        // The float control register belongs to the program, so it can be written from either ring
+        if self.get_flag(Flag::UserRing) && p != float::FPCR_SELECTOR {
             return Err(InvalidMemoryAccess::UnprivilegedOpcode);
         }
 
         match p {
-            0 => self.flags = self.xs[x0],
+            0 => self.flags = Flags::from_bits(self.xs[x0]),
             1 => self.memmap = self.xs[x0],
             2 => self.interrupt_mask = self.xs[x0] as u8,
+            float::FPCR_SELECTOR => self.set_float_control(self.xs[x0]),
 
-            _ => ()
+            _ => {
+                self.counters.write(p, self.xs[x0]);
+            }
         }
 
         Ok(())
     }
 
-    fn unprivileged_move(&mut self, p: usize, x0: usize) {
+    fn unprivileged_move(&mut self, p: usize, x0: usize) -> Result<(), InvalidMemoryAccess> {
+        if perf::Counters::is_selector(p) && self.get_flag(Flag::UserRing) && !self.counters.user_read() {
+            return Err(InvalidMemoryAccess::UnprivilegedOpcode);
+        }
+
         match p {
-            0 => self.xs[x0] = self.flags,
+            0 => self.xs[x0] = self.flags.bits(),
             1 => self.xs[x0] = self.memmap,
             2 => self.xs[x0] = self.interrupt_mask as u32,
+            3 => self.xs[x0] = self.cycles as u32,
+            4 => self.xs[x0] = (self.cycles >> 32) as u32,
+            float::FPCR_SELECTOR => self.xs[x0] = self.fpcr,
 
-            _ => ()
+            _ => {
+                if let Some(value) = self.counters.read(p) {
+                    self.xs[x0] = value;
+                }
+            }
         }
+
+        Ok(())
     }
 
     fn exec(&mut self) -> Result<u8, InvalidMemoryAccess> {
+        self.charge_translation(self.xs[R_PC]);
         let addr = self.check_memory(self.xs[R_PC], EXEC)?;
-        let res = self.addressing.read(addr);
+        let res = self.addressing.read(addr)?;
         self.xs[R_PC] += 1;
+        if let Some(tracer) = &mut self.tracer {
+            tracer.fetch(res);
+        }
         Ok(res)
     }
 
     fn read(&mut self, addr: u32) -> Result<u8, InvalidMemoryAccess> {
-        let addr = self.check_memory(addr, READ)?;
-        Ok(self.addressing.read(addr))
+        self.charge_translation(addr);
+        let paddr = self.check_memory(addr, READ)?;
+        self.charge_device(paddr);
+        let data = self.addressing.read(paddr)?;
+        self.triggers.check_access(addr, paddr, data, false);
+        if let Some(tracer) = &mut self.tracer {
+            tracer.access(addr, paddr, data, false);
+        }
+        if let Some(history) = &mut self.history {
+            history.access(addr, paddr, data, None);
+        }
+        Ok(data)
     }
 
     fn write(&mut self, addr: u32, data: u8) -> Result<(), InvalidMemoryAccess> {
-        let addr = self.check_memory(addr, WRITE)?;
-        self.addressing.write(addr, data);
+        self.charge_translation(addr);
+        let paddr = self.check_memory(addr, WRITE)?;
+        self.charge_device(paddr);
+        if let Some(history) = &mut self.history {
+            history.access(addr, paddr, data, Some(self.addressing.read(paddr)?));
+        }
+        self.addressing.write(paddr, data)?;
+        self.invalidate_code(paddr, 1);
+        self.triggers.check_access(addr, paddr, data, true);
+        if let Some(tracer) = &mut self.tracer {
+            tracer.access(addr, paddr, data, true);
+        }
         Ok(())
     }
 
-    fn decode_instruction(&mut self) -> Result<(), InvalidMemoryAccess> {
-        let opcode = self.exec()?;
-        match opcode & 0xc0 {
-            // 0b00xxxxxx -> no arguments
-            0x00 => {
-                match opcode & 0x3f {
-                    // Branches
-                    // Jumping is just mov x13, addr
-                    // Takes in 32 bit data as an argument
-                    0x00 => self.branch_true(F_ZERO)?,
-                    0x01 => self.branch_true(F_OVERFLOW)?,
-                    0x02 => self.branch_true(F_CARRY)?,
-                    0x03 => self.branch_true(F_NEGATIVE)?,
-                    0x04 => self.branch_true(F_PARITY)?,
-                    0x05 => self.branch_true(F_NAN)?,
-                    0x06 => self.branch_true(F_INFINITE)?,
-                    0x07 => self.branch_true(F_MEMMAP_ENABLE)?,
-                    0x08 => self.branch_false(F_ZERO)?,
-                    0x09 => self.branch_false(F_OVERFLOW)?,
-                    0x0a => self.branch_false(F_CARRY)?,
-                    0x0b => self.branch_false(F_NEGATIVE)?,
-                    0x0c => self.branch_false(F_PARITY)?,
-                    0x0d => self.branch_false(F_NAN)?,
-                    0x0e => self.branch_false(F_INFINITE)?,
-                    0x0f => self.branch_false(F_MEMMAP_ENABLE)?,
-
-                    // Setting and clearing flags
-                    0x10 => self.set_carry(false),
-                    0x11 => self.set_carry(true),
-                    0x12 => self.set_memmap_enable(false)?,
-                    0x13 => self.set_memmap_enable(true)?,
-                    0x14 => self.set_interrupt_enable(false)?,
-                    0x15 => self.set_interrupt_enable(true)?,
-                    0x17 => self.set_user_ring(true)?,
-
-                    0x18 => self.call()?,
-                    0x19 => self.ret()?,
-
-                    _ => (),
-                }
-            }
+    // Physical address of the size bytes at addr if they can be accessed all at once, which they
+    // can if they are in one page and nothing needs to see each byte. Otherwise, or if translating
+    // addr faults, they are accessed a byte at a time to get the same faults and records.
+    fn contiguous(&mut self, addr: u32, size: u32, permissions: u8) -> Option<u32> {
+        if addr % PAGE_SIZE + size > PAGE_SIZE
+            || self.tracer.is_some()
+            || self.history.is_some()
+            || self.triggers.has_watchpoints()
+        {
+            return None;
+        }
 
-            // 0b01xxyyyy data -> one register argument and 32 bit data
-            0x40 => {
-                let data = opcode as usize & 0x0f;
-                match opcode & 0x30 {
-                    // Load literal
-                    0x00 => self.load_lit_int(data)?,
-                    0x10 => self.load_lit_float(data)?,
+        // The translation of every byte after the first hits the TLB, which costs nothing
+        self.charge_translation(addr);
+        let paddr = self.check_memory(addr, permissions).ok()?;
+        if self.get_flag(Flag::MemmapEnable) && paddr > 0x0fffffff - (size - 1) {
+            return None;
+        }
 
-                    // Load memory address
-                    0x20 => self.load_int(data)?,
-                    0x30 => self.load_float(data)?,
+        // Past the end of memory a bus error part way through has to leave the same state behind
+        // as the byte at a time path
+        if paddr as u64 + size as u64 > self.addressing.size() {
+            return None;
+        }
+        for i in 0..size {
+            self.charge_device(paddr + i);
+        }
+        Some(paddr)
+    }
 
-                    _ => unreachable!("nya :("),
-                }
-            }
+    // Faults if alignment checking is enabled and addr is not a multiple of size
+    fn check_alignment(&self, addr: u32, size: u32) -> Result<(), InvalidMemoryAccess> {
+        match self.get_flag(Flag::AlignmentCheck) && !addr.is_multiple_of(size) {
+            true => Err(InvalidMemoryAccess::Misaligned(addr)),
+            false => Ok(()),
+        }
+    }
 
-            // 0b10xxxxxx 0byyyyzzzz -> two register arguments
-            0x80 => {
-                let data = self.exec()?;
-                let (fst, snd) = (((data & 0xf0) >> 4) as usize, (data & 0x0f) as usize);
-
-                match opcode & 0x3f {
-                    // Integer arithmetic
-                    0x00 => self.iadd(fst, snd),
-                    0x01 => self.isub(fst, snd),
-                    0x02 => self.imul(fst, snd),
-                    0x03 => self.idiv(fst, snd),
-                    0x04 => self.imod(fst, snd),
-
-                    // Floating point arithmetic
-                    0x05 => self.fadd(fst, snd),
-                    0x06 => self.fsub(fst, snd),
-                    0x07 => self.fmul(fst, snd),
-                    0x08 => self.fdiv(fst, snd),
-
-                    // Bitwise operations
-                    0x09 => self.bsl(fst, snd),
-                    0x0a => self.bsr(fst, snd),
-                    0x0b => self.and(fst, snd),
-                    0x0c => self.or(fst, snd),
-                    0x0d => self.xor(fst, snd),
-
-                    // Move and transmute operations
-                    0x0e => self.move_int(fst, snd),
-                    0x0f => self.move_float(fst, snd),
-                    0x10 => self.move_int_float(fst, snd),
-                    0x11 => self.move_float_int(fst, snd),
-                    0x12 => self.transmute_int_float(fst, snd),
-                    0x13 => self.transmute_float_int(fst, snd),
-
-                    // Load operations
-                    0x14 => self.load_indirect_int(fst, snd)?,
-                    0x15 => self.load_indirect_float(fst, snd)?,
-
-                    // Store operations
-                    0x16 => self.store_indirect_int(fst, snd)?,
-                    0x17 => self.store_indirect_short(fst, snd)?,
-                    0x18 => self.store_indirect_byte(fst, snd)?,
-                    0x19 => self.store_indirect_float(fst, snd)?,
-
-                    // Privileged move operations
-                    0x1a => self.privileged_move(fst, snd)?,
-                    0x1b => self.unprivileged_move(fst, snd),
-
-                    _ => (),
-                }
-            }
+    fn read_u32(&mut self, addr: u32) -> Result<u32, InvalidMemoryAccess> {
+        self.check_alignment(addr, 4)?;
+        if let Some(paddr) = self.contiguous(addr, 4, READ) {
+            return Ok(self.addressing.read_u32(paddr)?);
+        }
 
-            // 0b11xxyyyy data -> one register argument and 32 bit data
-            0xc0 => {
-                let data = opcode as usize & 0x0f;
-                match opcode & 0x30 {
-                    // Store at memory address
-                    0x00 => self.store_int(data)?,
-                    0x10 => self.store_short(data)?,
-                    0x20 => self.store_byte(data)?,
-                    0x30 => self.store_float(data)?,
-
-                    _ => unreachable!("nya :("),
-                }
-            }
+        let mut data = 0;
+        for i in 0..4 {
+            data |= (self.read(addr.wrapping_add(i))? as u32) << (8 * i);
+        }
+        Ok(data)
+    }
+
+    fn write_u16(&mut self, addr: u32, data: u16) -> Result<(), InvalidMemoryAccess> {
+        self.check_alignment(addr, 2)?;
+        if let Some(paddr) = self.contiguous(addr, 2, WRITE) {
+            self.invalidate_code(paddr, 2);
+            return Ok(self.addressing.write_u16(paddr, data)?);
+        }
+
+        self.write(addr, data as u8)?;
+        self.write(addr.wrapping_add(1), (data >> 8) as u8)
+    }
+
+    fn write_u32(&mut self, addr: u32, data: u32) -> Result<(), InvalidMemoryAccess> {
+        self.check_alignment(addr, 4)?;
+        if let Some(paddr) = self.contiguous(addr, 4, WRITE) {
+            return self.write_paddr_u32(paddr, data);
+        }
+
+        for i in 0..4 {
+            self.write(addr.wrapping_add(i), (data >> (8 * i)) as u8)?;
+        }
+        Ok(())
+    }
 
-            _ => unreachable!("nya :("),
+    fn write_paddr_u32(&mut self, paddr: u32, data: u32) -> Result<(), InvalidMemoryAccess> {
+        self.invalidate_code(paddr, 4);
+        Ok(self.addressing.write_u32(paddr, data)?)
+    }
+
+    // Pushes data onto the stack, from its highest byte down. The word written is aligned if sp is
+    // one below a multiple of four.
+    fn push_u32(&mut self, data: u32) -> Result<(), InvalidMemoryAccess> {
+        let sp = self.xs[R_SP];
+        self.check_alignment(sp.wrapping_sub(3), 4)?;
+        if let Some(paddr) = self.contiguous(sp.wrapping_sub(3), 4, WRITE) {
+            self.write_paddr_u32(paddr, data)?;
+            self.xs[R_SP] = sp - 4;
+            return Ok(());
         }
 
+        for i in (0..4).rev() {
+            self.write(self.xs[R_SP], (data >> (i * 8)) as u8)?;
+            self.xs[R_SP] -= 1;
+        }
         Ok(())
     }
 
+    // Pops a word of the current frame record, from its lowest byte up
+    fn pop_frame_u32(&mut self) -> Result<u32, InvalidMemoryAccess> {
+        let bp = self.xs[R_BASE];
+        self.check_alignment(bp.wrapping_add(1), 4)?;
+        if let Some(paddr) = self.contiguous(bp.wrapping_add(1), 4, READ) {
+            let data = self.addressing.read_u32(paddr)?;
+            self.xs[R_BASE] = bp + 4;
+            return Ok(data);
+        }
+
+        let mut data = 0;
+        for i in 0..4 {
+            self.xs[R_BASE] += 1;
+            data |= (self.read(self.xs[R_BASE])? as u32) << (8 * i);
+        }
+        Ok(data)
+    }
+
+    fn decode_instruction(&mut self) -> Result<(), InvalidMemoryAccess> {
+        let op = self.fetch()?;
+        self.execute(op)
+    }
+
+    #[allow(unused_variables)]
     fn call_interrupt(&mut self, interrupt: u32) {
         let flags = self.flags;
         let int = self.xs[R_INT];
         let pc = self.xs[R_PC];
         self.xs[R_INT] = interrupt;
 
-        if self.get_flag(F_USER_RING) {
+        if self.get_flag(Flag::UserRing) {
             let sp = self.xs[R_SP];
             let base = self.xs[R_BASE];
             self.xs[R_SP] = self.system_sp;
@@ -770,27 +1088,56 @@ where
 
             for i in 4..8 {
             }
-        } else {
         }
     }
 
+    // Whether the next step handles an interrupt instead of executing an instruction
+    fn interrupt_pending(&self) -> bool {
+        !self.interrupt_queue.is_empty() && self.get_flag(Flag::InterruptEnable)
+    }
+
+    // Raises the exception for an instruction that failed with e
+    fn fault(&mut self, e: InvalidMemoryAccess) {
+        if matches!(e, InvalidMemoryAccess::UsedFreePage | InvalidMemoryAccess::InvalidPermissions(_, _)) {
+            self.counters.count(perf::Counter::PageFaults, 1);
+        }
+        self.triggers.fault(e);
+        self.nmi(match e {
+            InvalidMemoryAccess::UsedFreePage => 0x00000000,
+            InvalidMemoryAccess::InvalidPermissions(_, _) => 0x00000001,
+            InvalidMemoryAccess::UnprivilegedOpcode => 0x00000002,
+            InvalidMemoryAccess::Misaligned(_) => 0x00000003,
+            InvalidMemoryAccess::BusError(_) => 0x00000004,
+        })
+    }
+
     pub fn step(&mut self) {
-        if !self.interrupt_queue.is_empty() && self.flags & F_INTERRUPT_ENABLE != 0 {
+        self.begin_trace();
+        self.begin_history();
+        let interrupted = self.interrupt_pending();
+        if interrupted {
             let interrupt = self.interrupt_queue.pop_front().unwrap();
+            if let Some(tracer) = &mut self.tracer {
+                tracer.interrupt(interrupt);
+            }
+            self.charge(self.costs.interrupt);
+            self.counters.count(perf::Counter::Interrupts, 1);
             self.call_interrupt(interrupt);
 
         } else {
+            let pc = self.xs[R_PC];
             match self.decode_instruction() {
-                Ok(_) => (),
-                Err(e) => {
-                    self.nmi(match e {
-                        InvalidMemoryAccess::UsedFreePage => 0x00000000,
-                        InvalidMemoryAccess::InvalidPermissions(_, _) => 0x00000001,
-                        InvalidMemoryAccess::UnprivilegedOpcode => 0x00000002,
-                    })
+                Ok(_) => {
+                    self.counters.count(perf::Counter::Instructions, 1);
+                    self.cover_instruction(pc);
                 }
+                Err(e) => self.fault(e),
             }
         }
+        self.run_events();
+        self.end_trace();
+        self.end_history(interrupted);
+        self.sample_profile();
     }
 
     pub fn irq(&mut self, id: u8) {
@@ -799,8 +1146,15 @@ where
         }
     }
 
+    // Queues nonmaskable interrupt id, which the interrupt mask doesn't apply to. An interrupt
+    // that is already queued isn't queued again, so an instruction that keeps faulting while
+    // interrupts are disabled doesn't grow the queue.
     pub fn nmi(&mut self, id: u32) {
-        // self.interrupt_queue.push_back(id | 0x80000000);
+        let interrupt = id | 0x80000000;
+        self.triggers.nmi(interrupt);
+        if !self.interrupt_queue.contains(&interrupt) {
+            self.interrupt_queue.push_back(interrupt);
+        }
     }
 }
 
@@ -817,28 +1171,28 @@ mod tests {
         cpu.xs[1] = 10;
         cpu.iadd(0, 1);
         assert_eq!(cpu.xs[0], 15);
-        assert!(!cpu.get_flag(F_CARRY));
-        assert!(!cpu.get_flag(F_OVERFLOW));
-        assert!(!cpu.get_flag(F_NEGATIVE));
+        assert!(!cpu.get_flag(Flag::Carry));
+        assert!(!cpu.get_flag(Flag::Overflow));
+        assert!(!cpu.get_flag(Flag::Negative));
 
         // Overflow
         cpu.xs[0] = (1 << 31) - 1;
         cpu.xs[1] = 1;
         cpu.iadd(0, 1);
         assert_eq!(cpu.xs[0], 0x80000000);
-        assert!(!cpu.get_flag(F_CARRY));
-        assert!(cpu.get_flag(F_OVERFLOW));
-        assert!(cpu.get_flag(F_NEGATIVE));
+        assert!(!cpu.get_flag(Flag::Carry));
+        assert!(cpu.get_flag(Flag::Overflow));
+        assert!(cpu.get_flag(Flag::Negative));
 
         // Carry
         cpu.xs[0] = 0xffffffff;
         cpu.xs[1] = 0;
-        cpu.set_flag(F_CARRY, true);
+        cpu.set_flag(Flag::Carry, true);
         cpu.iadd(0, 1);
         assert_eq!(cpu.xs[0], 0);
-        assert!(cpu.get_flag(F_CARRY));
-        assert!(!cpu.get_flag(F_OVERFLOW));
-        assert!(!cpu.get_flag(F_NEGATIVE));
+        assert!(cpu.get_flag(Flag::Carry));
+        assert!(!cpu.get_flag(Flag::Overflow));
+        assert!(!cpu.get_flag(Flag::Negative));
     }
 
     #[test]
@@ -850,21 +1204,108 @@ mod tests {
         cpu.xs[1] = 2;
         cpu.bsl(0, 1);
         assert_eq!(cpu.xs[0], 12);
-        assert!(!cpu.get_flag(F_CARRY));
+        assert!(!cpu.get_flag(Flag::Carry));
 
         // Overflow
         cpu.xs[0] = 3;
         cpu.xs[1] = 32;
         cpu.bsl(0, 1);
         assert_eq!(cpu.xs[0], 0);
-        assert!(!cpu.get_flag(F_CARRY));
+        assert!(!cpu.get_flag(Flag::Carry));
 
         // Carry
         cpu.xs[0] = 0xffffffff;
         cpu.xs[1] = 1;
         cpu.bsl(0, 1);
         assert_eq!(cpu.xs[0], 0xfffffffe);
-        assert!(cpu.get_flag(F_CARRY));
+        assert!(cpu.get_flag(Flag::Carry));
+    }
+
+    #[test]
+    fn cpu_fcmp() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        let flags = |cpu: &Cpu<SimpleAddress>| [Flag::Zero, Flag::Negative, Flag::NaN].map(|f| cpu.get_flag(f));
+
+        cpu.fs[0] = 1.5;
+        cpu.fs[1] = 2.0;
+        cpu.fcmp(0, 1);
+        assert_eq!(flags(&cpu), [false, true, false]);
+        cpu.fcmp(1, 0);
+        assert_eq!(flags(&cpu), [false, false, false]);
+
+        // Zeros of either sign and infinities of the same sign are equal
+        cpu.fs[0] = -0.0;
+        cpu.fs[1] = 0.0;
+        cpu.fcmp(0, 1);
+        assert_eq!(flags(&cpu), [true, false, false]);
+        cpu.fs[0] = f32::INFINITY;
+        cpu.fs[1] = f32::INFINITY;
+        cpu.fcmp(0, 1);
+        assert_eq!(flags(&cpu), [true, false, false]);
+        assert!(!cpu.get_flag(Flag::Infinite));
+
+        // Unordered
+        cpu.fs[1] = f32::NAN;
+        cpu.fcmp(0, 1);
+        assert_eq!(flags(&cpu), [false, false, true]);
+        assert_eq!(cpu.fs[0], f32::INFINITY);
+    }
+
+    #[test]
+    #[allow(clippy::float_cmp)]
+    fn cpu_float_ops() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+
+        cpu.fs[1] = -2.25;
+        cpu.fneg(0, 1);
+        assert_eq!(cpu.fs[0], 2.25);
+        assert!(!cpu.get_flag(Flag::Negative));
+        cpu.fabs(0, 1);
+        assert_eq!(cpu.fs[0], 2.25);
+        cpu.fsqrt(0, 1);
+        assert!(cpu.get_flag(Flag::NaN));
+        cpu.fs[1] = 6.25;
+        cpu.fsqrt(0, 1);
+        assert_eq!(cpu.fs[0], 2.5);
+        assert!(!cpu.get_flag(Flag::NaN));
+
+        // A NaN is ignored by min and max
+        cpu.fs[1] = f32::NAN;
+        cpu.fmin(0, 1);
+        assert_eq!(cpu.fs[0], 2.5);
+        cpu.fs[1] = -1.0;
+        cpu.fmin(0, 1);
+        assert_eq!(cpu.fs[0], -1.0);
+        assert!(cpu.get_flag(Flag::Negative));
+        cpu.fs[1] = 3.0;
+        cpu.fmax(0, 1);
+        assert_eq!(cpu.fs[0], 3.0);
+
+        cpu.fs[1] = -2.5;
+        cpu.fround(0, 1, Rounding::NearestEven);
+        assert_eq!(cpu.fs[0], -2.0);
+        cpu.fround(0, 1, Rounding::Down);
+        assert_eq!(cpu.fs[0], -3.0);
+        cpu.fs[1] = -0.5;
+        cpu.fround(0, 1, Rounding::Up);
+        assert!(cpu.get_flag(Flag::Zero) && cpu.get_flag(Flag::Negative));
+
+        // Conversions saturate and set the overflow flag instead of wrapping
+        cpu.fs[1] = 2.5;
+        cpu.move_int_float(0, 1, Rounding::Up);
+        assert_eq!(cpu.xs[0], 3);
+        assert!(!cpu.get_flag(Flag::Overflow));
+        cpu.fs[1] = -1e10;
+        cpu.move_int_float(0, 1, Rounding::TowardZero);
+        assert_eq!(cpu.xs[0], 0x80000000);
+        assert!(cpu.get_flag(Flag::Overflow) && cpu.get_flag(Flag::Negative));
+        cpu.fs[1] = f32::NAN;
+        cpu.move_int_float(0, 1, Rounding::NearestEven);
+        assert_eq!(cpu.xs[0], 0);
+        assert!(cpu.get_flag(Flag::Overflow) && cpu.get_flag(Flag::Zero));
+        cpu.fs[1] = 7.0;
+        cpu.move_int_float(0, 1, Rounding::Down);
+        assert!(!cpu.get_flag(Flag::Overflow));
     }
 
     #[test]
@@ -879,7 +1320,8 @@ mod tests {
 
         // Load literal
         cpu.xs[R_PC] = 0xff00;
-        cpu.load_lit_int(0).unwrap();
+        let data = decode::next_u32(&mut || cpu.exec()).unwrap();
+        cpu.load_lit_int(0, data);
         assert_eq!(cpu.xs[0], 0xa0b0c0d0);
 
         // Set up memory
@@ -890,7 +1332,8 @@ mod tests {
 
         // Simple addressing
         cpu.xs[R_PC] = 0x00;
-        cpu.load_int(1).unwrap();
+        let addr = decode::next_u32(&mut || cpu.exec()).unwrap();
+        cpu.load_int(1, addr).unwrap();
         assert_eq!(cpu.xs[1], 0xa0b0c0d0);
 
         // Indirect addressing
@@ -914,7 +1357,8 @@ mod tests {
 
         // Load literal
         cpu.xs[R_PC] = 0xff00;
-        cpu.load_lit_float(0).unwrap();
+        let data = decode::next_u32(&mut || cpu.exec()).unwrap();
+        cpu.load_lit_float(0, data);
         assert_eq!(cpu.fs[0], 0.618);
 
         // Set up memory
@@ -925,7 +1369,8 @@ mod tests {
 
         // Simple addressing
         cpu.xs[R_PC] = 0x00;
-        cpu.load_float(1).unwrap();
+        let addr = decode::next_u32(&mut || cpu.exec()).unwrap();
+        cpu.load_float(1, addr).unwrap();
         assert_eq!(cpu.fs[1], 0.618);
 
         // Indirect addressing
@@ -948,7 +1393,8 @@ mod tests {
         cpu.xs[R_PC] = 0x0000;
 
         // Simple addressing
-        cpu.store_int(0).unwrap();
+        let addr = decode::next_u32(&mut || cpu.exec()).unwrap();
+        cpu.store_int(0, addr).unwrap();
         assert_eq!(cpu.addressing.memory[0xff00], 0xd0);
         assert_eq!(cpu.addressing.memory[0xff01], 0xc0);
         assert_eq!(cpu.addressing.memory[0xff02], 0xb0);
@@ -976,7 +1422,8 @@ mod tests {
         cpu.xs[R_PC] = 0x0000;
 
         // Simple addressing
-        cpu.store_short(0).unwrap();
+        let addr = decode::next_u32(&mut || cpu.exec()).unwrap();
+        cpu.store_short(0, addr).unwrap();
         assert_eq!(cpu.addressing.memory[0xff00], 0xb0);
         assert_eq!(cpu.addressing.memory[0xff01], 0xa0);
 
@@ -1000,7 +1447,8 @@ mod tests {
         cpu.xs[R_PC] = 0x0000;
 
         // Simple addressing
-        cpu.store_byte(0).unwrap();
+        let addr = decode::next_u32(&mut || cpu.exec()).unwrap();
+        cpu.store_byte(0, addr).unwrap();
         assert_eq!(cpu.addressing.memory[0xff00], 0xa0);
 
         // Indirect addressing
@@ -1022,7 +1470,8 @@ mod tests {
         cpu.xs[R_PC] = 0x0000;
 
         // Simple addressing
-        cpu.store_float(0).unwrap();
+        let addr = decode::next_u32(&mut || cpu.exec()).unwrap();
+        cpu.store_float(0, addr).unwrap();
         // 0x3f1e353f
         assert_eq!(cpu.addressing.memory[0xff00], 0x3f);
         assert_eq!(cpu.addressing.memory[0xff01], 0x35);
@@ -1052,7 +1501,8 @@ mod tests {
         cpu.addressing.memory[0x1237] = 0x00;
 
         // "Call" the function
-        cpu.call().unwrap();
+        let addr = decode::next_u32(&mut || cpu.exec()).unwrap();
+        cpu.call(addr).unwrap();
         assert_eq!(cpu.xs[R_PC], 0xaf42);
         assert_eq!(cpu.xs[R_BASE], 0xbfc0);
 
@@ -1067,7 +1517,7 @@ mod tests {
     #[test]
     fn cpu_memmap() {
         let mut cpu = Cpu::new(SimpleAddress::default());
-        cpu.flags |= 1 << F_MEMMAP_ENABLE;
+        cpu.set_flag(Flag::MemmapEnable, true);
         cpu.memmap = 0x1234;
         cpu.addressing.memory[0x1234] = 0x0a;
         cpu.addressing.memory[0x1235] = 0x0b;
@@ -1082,4 +1532,161 @@ mod tests {
         assert_eq!(cpu.read(0xbc).unwrap(), 0x42);
         assert!(cpu.exec().is_err());
     }
+
+    #[test]
+    fn cpu_nmi() {
+        // Enabling paging from the user ring faults even with every interrupt masked
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[..2].copy_from_slice(&[0x17, 0x13]);
+        cpu.interrupt_mask = 0;
+        cpu.run(2);
+        assert_eq!(cpu.interrupt_queue, [0x80000002]);
+        assert_eq!(cpu.xs[R_PC], 2);
+
+        cpu.irq(1);
+        assert_eq!(cpu.interrupt_queue.len(), 1);
+
+        cpu.set_flag(Flag::InterruptEnable, true);
+        cpu.step();
+        assert!(cpu.interrupt_queue.is_empty());
+        assert_eq!(cpu.xs[R_INT], 0x80000002);
+    }
+
+    #[test]
+    fn cpu_nmi_repeated() {
+        // lw x0, [0x2000] past the end of memory, run again and again with interrupts disabled
+        let mut cpu = Cpu::new(SimpleAddress::new(0x1000, Unbacked::BusError));
+        cpu.addressing.memory[..5].copy_from_slice(&[0x60, 0x00, 0x20, 0, 0]);
+        for _ in 0..3 {
+            cpu.xs[R_PC] = 0;
+            let cause = Some(InvalidMemoryAccess::BusError(0x2000));
+            assert_eq!(cpu.run(1), debug::StopReason::Fault { interrupt: 0x80000004, pc: 0, cause });
+        }
+
+        // Every fault stops the run, but the pending interrupt is only queued once
+        assert_eq!(cpu.interrupt_queue, [0x80000004]);
+    }
+
+    // Only implements single byte accesses, to compare with the defaults
+    struct Bytes(SimpleAddress);
+
+    impl Address for Bytes {
+        fn read(&mut self, addr: u32) -> Result<u8, BusError> {
+            self.0.read(addr)
+        }
+
+        fn write(&mut self, addr: u32, data: u8) -> Result<(), BusError> {
+            self.0.write(addr, data)
+        }
+    }
+
+    #[test]
+    fn address_words() {
+        let mut fast = SimpleAddress::default();
+        let mut bytes = Bytes(SimpleAddress::default());
+        for &addr in [0x10, 0xfffffe, 0xffffffff].iter() {
+            fast.write_u32(addr, 0x11223344).unwrap();
+            bytes.write_u32(addr, 0x11223344).unwrap();
+            assert_eq!(fast.read_u32(addr), bytes.read_u32(addr));
+            fast.write_u16(addr.wrapping_add(0x20), 0x5566).unwrap();
+            bytes.write_u16(addr.wrapping_add(0x20), 0x5566).unwrap();
+            assert_eq!(fast.read_u16(addr.wrapping_add(0x20)), bytes.read_u16(addr.wrapping_add(0x20)));
+        }
+        assert_eq!(fast.memory, bytes.0.memory);
+        assert_eq!(fast.read_u32(0x10), Ok(0x11223344));
+
+        // Bytes past the end of memory are dropped and read as zero, and addresses wrap around
+        assert_eq!(fast.read_u32(0xfffffe), Ok(0x3344));
+        assert_eq!(fast.memory[0..3], [0x33, 0x22, 0x11]);
+
+        let mut buf = [0; 4];
+        fast.write_slice(0xfffffe, &[1, 2, 3, 4]).unwrap();
+        fast.read_slice(0xfffffe, &mut buf).unwrap();
+        assert_eq!(buf, [1, 2, 0, 0]);
+        fast.read_slice(0x10, &mut buf).unwrap();
+        assert_eq!(buf, [0x44, 0x33, 0x22, 0x11]);
+    }
+
+    #[test]
+    fn cpu_words_across_pages() {
+        // Only the first page is mapped
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.set_flag(Flag::MemmapEnable, true);
+        cpu.memmap = 0x20000;
+        cpu.addressing.write_u32(0x20000, 0x30000).unwrap();
+        cpu.addressing.write_u32(0x30000, 0xf0000000).unwrap();
+
+        cpu.write_u32(0xfffc, 0xa0b0c0d0).unwrap();
+        assert_eq!(cpu.read_u32(0xfffc), Ok(0xa0b0c0d0));
+
+        // A word across the end of the page is still written up to the fault
+        assert_eq!(cpu.write_u32(0xfffe, 0x11223344), Err(InvalidMemoryAccess::UsedFreePage));
+        assert_eq!(cpu.addressing.memory[0xfffc..0x10000], [0xd0, 0xc0, 0x44, 0x33]);
+        assert!(cpu.read_u32(0xfffe).is_err());
+    }
+
+    #[test]
+    fn cpu_alignment_check() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.load_int(0, 0x101).unwrap();
+        cpu.store_short(0, 0x103).unwrap();
+
+        cpu.set_flag(Flag::AlignmentCheck, true);
+        assert_eq!(cpu.load_int(0, 0x102), Err(InvalidMemoryAccess::Misaligned(0x102)));
+        assert_eq!(cpu.load_float(0, 0x101), Err(InvalidMemoryAccess::Misaligned(0x101)));
+        assert_eq!(cpu.store_int(0, 0x103), Err(InvalidMemoryAccess::Misaligned(0x103)));
+        assert_eq!(cpu.store_short(0, 0x101), Err(InvalidMemoryAccess::Misaligned(0x101)));
+        cpu.load_int(0, 0x100).unwrap();
+        cpu.store_short(0, 0x102).unwrap();
+        cpu.store_byte(0, 0x101).unwrap();
+
+        // The stack pointer has to be one below a multiple of four
+        cpu.xs[R_SP] = 0x8000;
+        assert_eq!(cpu.call(0x40), Err(InvalidMemoryAccess::Misaligned(0x7ffd)));
+        assert_eq!(cpu.xs[R_SP], 0x8000);
+        cpu.xs[R_SP] = 0x7fff;
+        cpu.xs[R_PC] = 0x20;
+        cpu.call(0x40).unwrap();
+        cpu.ret().unwrap();
+        assert_eq!(cpu.xs[R_PC], 0x20);
+        assert_eq!(cpu.xs[R_SP], 0x7fff);
+
+        // Misaligned accesses raise nonmaskable interrupt 3 and are not page faults
+        cpu.addressing.memory[0x20..0x25].copy_from_slice(&[0x60, 0x01, 0x01, 0, 0]);
+        cpu.step();
+        assert_eq!(cpu.interrupt_queue.back(), Some(&0x80000003));
+        assert_eq!(cpu.counters.read(8), Some(0));
+    }
+
+    #[test]
+    fn address_unbacked() {
+        let mut memory = SimpleAddress::new(0x100, Unbacked::ReadZero);
+        memory.write_u32(0xfe, 0x11223344).unwrap();
+        assert_eq!(memory.read_u32(0xfe), Ok(0x3344));
+        assert_eq!(memory.size(), 0x100);
+
+        // Backends fail at the first unbacked byte, after writing the ones before it
+        let mut memory = SimpleAddress::new(0x100, Unbacked::BusError);
+        assert_eq!(memory.write_u32(0xfe, 0x11223344), Err(BusError(0x100)));
+        assert_eq!(memory.read_u16(0xfe), Ok(0x3344));
+        assert_eq!(memory.read(0xffffffff), Err(BusError(0xffffffff)));
+        let mut buf = [0; 4];
+        assert_eq!(memory.read_slice(0xfe, &mut buf), Err(BusError(0x100)));
+
+        // Which the cpu raises as nonmaskable interrupt 4, also when walking the page table
+        let mut cpu = Cpu::new(SimpleAddress::new(0x100, Unbacked::BusError));
+        cpu.xs[0] = 0x11223344;
+        assert_eq!(cpu.store_int(0, 0xfe), Err(InvalidMemoryAccess::BusError(0x100)));
+        assert_eq!(cpu.addressing.memory[0xfe..], [0x44, 0x33]);
+        cpu.xs[R_PC] = 0x100;
+        assert_eq!(cpu.exec(), Err(InvalidMemoryAccess::BusError(0x100)));
+
+        cpu.xs[R_PC] = 0;
+        cpu.memmap = 0x1000;
+        cpu.set_flag(Flag::MemmapEnable, true);
+        assert_eq!(cpu.translate(0, READ), Err(InvalidMemoryAccess::BusError(0x1000)));
+        cpu.step();
+        assert_eq!(cpu.interrupt_queue.back(), Some(&0x80000004));
+        assert_eq!(cpu.counters.read(8), Some(0));
+    }
 }
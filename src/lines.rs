@@ -0,0 +1,106 @@
+// Source line tables for debugging
+//
+// Built from the line info of an executable. Each entry covers the code from its address up to
+// the next entry in the same section, or the end of the section.
+
+use crate::object::Object;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineRange {
+    pub start: u32,
+    pub end: u32,
+    pub file: usize,
+    pub line: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LineTable {
+    files: Vec<String>,
+
+    // Sorted by start address and not overlapping
+    ranges: Vec<LineRange>,
+}
+
+impl LineTable {
+    pub fn new() -> LineTable {
+        LineTable::default()
+    }
+
+    pub fn from_object(object: &Object) -> LineTable {
+        let mut ranges = vec![];
+        for (i, section) in object.sections.iter().enumerate() {
+            let mut offsets: Vec<(u32, usize, u32)> = object
+                .lines
+                .iter()
+                .filter(|l| l.section == i)
+                .map(|l| (l.offset, l.file, l.line))
+                .collect();
+            offsets.sort_by_key(|&(offset, _, _)| offset);
+            offsets.dedup_by_key(|&mut (offset, _, _)| offset);
+
+            for (j, &(offset, file, line)) in offsets.iter().enumerate() {
+                let end = offsets.get(j + 1).map_or(section.size, |&(next, _, _)| next);
+                ranges.push(LineRange {
+                    start: section.addr.wrapping_add(offset),
+                    end: section.addr.wrapping_add(end),
+                    file,
+                    line,
+                });
+            }
+        }
+        ranges.sort_by_key(|r| r.start);
+        LineTable { files: object.files.clone(), ranges }
+    }
+
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+
+    pub fn ranges(&self) -> &[LineRange] {
+        &self.ranges
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    // The line range containing addr
+    pub fn range(&self, addr: u32) -> Option<&LineRange> {
+        let i = self.ranges.partition_point(|r| r.start <= addr).checked_sub(1)?;
+        Some(&self.ranges[i]).filter(|r| addr < r.end)
+    }
+
+    // The file name and line the code at addr was assembled from
+    pub fn find(&self, addr: u32) -> Option<(&str, u32)> {
+        self.range(addr).map(|r| (self.files[r.file].as_str(), r.line))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::{LineEntry, Section, SectionKind};
+
+    #[test]
+    fn lines_find() {
+        let mut text = Section::new(".text", SectionKind::Text, vec![0; 12]);
+        text.addr = 0x1000;
+        let object = Object {
+            sections: vec![text],
+            files: vec!["a.s".to_string(), "b.s".to_string()],
+            lines: vec![
+                LineEntry { section: 0, offset: 5, file: 1, line: 7 },
+                LineEntry { section: 0, offset: 0, file: 0, line: 2 },
+            ],
+            ..Object::default()
+        };
+
+        let lines = LineTable::from_object(&object);
+        assert_eq!(lines.find(0x1000), Some(("a.s", 2)));
+        assert_eq!(lines.find(0x1004), Some(("a.s", 2)));
+        assert_eq!(lines.find(0x1005), Some(("b.s", 7)));
+        assert_eq!(lines.find(0x100b), Some(("b.s", 7)));
+        assert_eq!(lines.find(0x100c), None);
+        assert_eq!(lines.find(0x0fff), None);
+    }
+}
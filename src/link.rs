@@ -0,0 +1,507 @@
+// Linker for object files
+//
+// Input sections are merged into output sections by name, in the order the objects are given,
+// and laid out according to a linker script. A script is a list of lines of the form
+//   entry <symbol>
+//   section <name> [at <address>] [align <bytes> | align page]
+// where `#` starts a comment. Output sections without an address are placed after the previous
+// one. Sections that are not named by the script are placed at the end, aligned to a page.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::object::{LineEntry, Object, ObjectKind, RelocationKind, Section, SectionKind, Symbol};
+use crate::{parse_num, EXEC, PAGE_SIZE, READ, WRITE};
+
+#[derive(Debug)]
+pub enum LinkError {
+    Script { line: usize, message: String },
+    NotRelocatable(String),
+    Duplicate { symbol: String, first: String, second: String },
+    Undefined { symbol: String, input: String },
+    UndefinedEntry(String),
+    Overlap { first: String, second: String },
+    OutOfRange(String),
+    SymbolOutOfRange { symbol: String, input: String },
+}
+
+impl std::fmt::Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            LinkError::Script { line, message } => write!(f, "linker script line {}: {}", line, message),
+            LinkError::NotRelocatable(input) => write!(f, "`{}` is not a relocatable object", input),
+            LinkError::Duplicate { symbol, first, second } => {
+                write!(f, "symbol `{}` is defined in both `{}` and `{}`", symbol, first, second)
+            }
+            LinkError::Undefined { symbol, input } => write!(f, "undefined symbol `{}` referenced in `{}`", symbol, input),
+            LinkError::UndefinedEntry(symbol) => write!(f, "entry symbol `{}` is not defined", symbol),
+            LinkError::Overlap { first, second } => write!(f, "sections `{}` and `{}` overlap", first, second),
+            LinkError::OutOfRange(section) => write!(f, "section `{}` does not fit in the address space", section),
+            LinkError::SymbolOutOfRange { symbol, input } => {
+                write!(f, "symbol `{}` in `{}` is outside of its section", symbol, input)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScriptSection {
+    pub name: String,
+    pub addr: Option<u32>,
+    pub align: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Script {
+    // Defaults to `main` if it is defined, otherwise the start of the first section
+    pub entry: Option<String>,
+    pub sections: Vec<ScriptSection>,
+}
+
+impl Default for Script {
+    // Text at address zero followed by data and bss, each on its own pages so that they can be
+    // mapped with different permissions
+    fn default() -> Script {
+        let section = |name: &str, addr, align| ScriptSection { name: name.to_string(), addr, align };
+        Script {
+            entry: None,
+            sections: vec![
+                section(".text", Some(0), PAGE_SIZE),
+                section(".data", None, PAGE_SIZE),
+                section(".bss", None, PAGE_SIZE),
+            ],
+        }
+    }
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Result<Script, LinkError> {
+        let mut script = Script { entry: None, sections: vec![] };
+
+        for (i, line) in text.lines().enumerate() {
+            let error = |message: &str| LinkError::Script { line: i + 1, message: message.to_string() };
+            let line = line.split('#').next().unwrap().trim();
+            let mut words = line.split_whitespace();
+
+            match words.next() {
+                None => (),
+                Some("entry") => match (words.next(), words.next()) {
+                    (Some(symbol), None) => script.entry = Some(symbol.to_string()),
+                    _ => return Err(error("expected `entry <symbol>`")),
+                },
+                Some("section") => {
+                    let name = words.next().ok_or_else(|| error("expected a section name"))?;
+                    let mut section = ScriptSection { name: name.to_string(), addr: None, align: 1 };
+                    while let Some(word) = words.next() {
+                        let value = words.next().ok_or_else(|| error(&format!("expected a value after `{}`", word)))?;
+                        match word {
+                            "at" => section.addr = Some(parse_num(value).ok_or_else(|| error("invalid address"))?),
+                            "align" if value == "page" => section.align = PAGE_SIZE,
+                            "align" => match parse_num(value) {
+                                Some(align) if align.is_power_of_two() => section.align = align,
+                                _ => return Err(error("alignment must be a power of two")),
+                            },
+                            _ => return Err(error(&format!("unknown section attribute `{}`", word))),
+                        }
+                    }
+                    if script.sections.iter().any(|s| s.name == section.name) {
+                        return Err(error(&format!("section `{}` is listed twice", section.name)));
+                    }
+                    script.sections.push(section);
+                }
+                Some(word) => return Err(error(&format!("unknown directive `{}`", word))),
+            }
+        }
+
+        Ok(script)
+    }
+}
+
+// Where an input section ended up in the output
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Placement {
+    pub input: String,
+    pub addr: u32,
+    pub size: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct Linked {
+    pub executable: Object,
+
+    // Input sections of each output section, in address order
+    pub placements: Vec<Vec<Placement>>,
+}
+
+fn align_up(addr: u64, align: u32) -> u64 {
+    let align = align.max(1) as u64;
+    addr.div_ceil(align) * align
+}
+
+fn perms(p: u8) -> String {
+    let bit = |b, c| if p & b != 0 { c } else { '-' };
+    [bit(READ, 'r'), bit(WRITE, 'w'), bit(EXEC, 'x')].iter().collect()
+}
+
+impl Linked {
+    // Writes a map of the output sections, the input sections placed in them and every symbol.
+    // Symbol lines are of the form `<address> <name>` so the map doubles as a symbol file.
+    pub fn write_map<W: Write>(&self, mut w: W) -> io::Result<()> {
+        writeln!(w, "# section            address     size        perms")?;
+        for (section, placements) in self.executable.sections.iter().zip(self.placements.iter()) {
+            writeln!(
+                w,
+                "{:<20} {:#010x}  {:#010x}  {}",
+                section.name,
+                section.addr,
+                section.size,
+                perms(section.permissions)
+            )?;
+            for p in placements.iter() {
+                writeln!(w, "  {:<18} {:#010x}  {:#010x}", p.input, p.addr, p.size)?;
+            }
+        }
+
+        if let Some(entry) = self.executable.entry {
+            writeln!(w, "\n# entry {:#010x}", entry)?;
+        }
+
+        writeln!(w, "\n# symbols")?;
+        let mut symbols: Vec<(u32, &str)> = self
+            .executable
+            .symbols
+            .iter()
+            .filter_map(|s| Some((self.executable.symbol_address(s)?, s.name.as_str())))
+            .collect();
+        symbols.sort();
+        for (addr, name) in symbols {
+            writeln!(w, "{:#010x} {}", addr, name)?;
+        }
+        w.flush()
+    }
+}
+
+// Links relocatable objects, given with the names used in error messages and the map, into an
+// executable
+pub fn link(script: &Script, inputs: &[(String, Object)]) -> Result<Linked, LinkError> {
+    for (name, object) in inputs.iter() {
+        if object.kind != ObjectKind::Relocatable {
+            return Err(LinkError::NotRelocatable(name.clone()));
+        }
+    }
+
+    // Output sections named by the script followed by the remaining input section names
+    let mut layout: Vec<ScriptSection> = script.sections.clone();
+    for (_, object) in inputs.iter() {
+        for s in object.sections.iter() {
+            if !layout.iter().any(|l| l.name == s.name) {
+                layout.push(ScriptSection { name: s.name.clone(), addr: None, align: PAGE_SIZE });
+            }
+        }
+    }
+
+    // Output section index and address of every input section, keyed by (input, section)
+    let mut placed: HashMap<(usize, usize), (usize, u32)> = HashMap::new();
+    let mut sections = vec![];
+    let mut placements = vec![];
+    let mut cursor = 0u64;
+
+    for l in layout.iter() {
+        let members: Vec<(usize, usize, &Section)> = inputs
+            .iter()
+            .enumerate()
+            .flat_map(|(i, (_, o))| o.sections.iter().enumerate().map(move |(j, s)| (i, j, s)))
+            .filter(|(_, _, s)| s.name == l.name)
+            .collect();
+        if members.is_empty() {
+            continue;
+        }
+
+        let align = members.iter().map(|(_, _, s)| s.align).fold(l.align, u32::max);
+        let start = match l.addr {
+            Some(addr) => addr as u64,
+            None => align_up(cursor, align),
+        };
+
+        let mut output = Section::new(&l.name, SectionKind::Bss, vec![]);
+        output.permissions = 0;
+        output.align = align;
+        let mut offset = 0u64;
+        let mut section_placements = vec![];
+        for &(i, j, s) in members.iter() {
+            offset = align_up(offset, s.align);
+            if start + offset + s.size as u64 > 1 << 32 {
+                return Err(LinkError::OutOfRange(l.name.clone()));
+            }
+
+            if s.kind != SectionKind::Bss && output.kind == SectionKind::Bss {
+                output.kind = s.kind;
+                output.data = vec![0; offset as usize];
+            }
+            if output.kind != SectionKind::Bss {
+                output.data.resize(offset as usize, 0);
+                match s.kind {
+                    SectionKind::Bss => output.data.resize((offset + s.size as u64) as usize, 0),
+                    _ => output.data.extend_from_slice(&s.data),
+                }
+            }
+            output.permissions |= s.permissions;
+
+            let addr = (start + offset) as u32;
+            placed.insert((i, j), (sections.len(), addr));
+            section_placements.push(Placement { input: inputs[i].0.clone(), addr, size: s.size });
+            offset += s.size as u64;
+        }
+
+        output.addr = start as u32;
+        output.size = offset as u32;
+        cursor = start + offset;
+        sections.push(output);
+        placements.push(section_placements);
+    }
+
+    let mut ordered: Vec<&Section> = sections.iter().filter(|s| s.size != 0).collect();
+    ordered.sort_by_key(|s| s.addr);
+    for pair in ordered.windows(2) {
+        if pair[0].addr as u64 + pair[0].size as u64 > pair[1].addr as u64 {
+            return Err(LinkError::Overlap { first: pair[0].name.clone(), second: pair[1].name.clone() });
+        }
+    }
+
+    // Address of every defined symbol, which may be at most the end of its section
+    let mut addresses: Vec<Vec<Option<u32>>> = vec![];
+    for (i, (name, object)) in inputs.iter().enumerate() {
+        let mut input_addresses = vec![];
+        for s in object.symbols.iter() {
+            input_addresses.push(match s.section {
+                Some(j) => {
+                    let addr = Some(s.value)
+                        .filter(|&value| value <= object.sections[j].size)
+                        .and_then(|value| placed[&(i, j)].1.checked_add(value));
+                    match addr {
+                        Some(addr) => Some(addr),
+                        None => return Err(LinkError::SymbolOutOfRange { symbol: s.name.clone(), input: name.clone() }),
+                    }
+                }
+                None => None,
+            });
+        }
+        addresses.push(input_addresses);
+    }
+
+    // Resolve global symbols
+    let mut globals: HashMap<&str, (usize, u32)> = HashMap::new();
+    for (i, (name, object)) in inputs.iter().enumerate() {
+        for (k, s) in object.symbols.iter().enumerate().filter(|(_, s)| s.global) {
+            if let Some(addr) = addresses[i][k] {
+                if let Some(&(first, _)) = globals.get(s.name.as_str()) {
+                    return Err(LinkError::Duplicate {
+                        symbol: s.name.clone(),
+                        first: inputs[first].0.clone(),
+                        second: name.clone(),
+                    });
+                }
+                globals.insert(&s.name, (i, addr));
+            }
+        }
+    }
+
+    // Apply relocations
+    for (i, (name, object)) in inputs.iter().enumerate() {
+        for r in object.relocations.iter() {
+            let symbol = &object.symbols[r.symbol];
+            let target = match addresses[i][r.symbol].or_else(|| globals.get(symbol.name.as_str()).map(|&(_, a)| a)) {
+                Some(target) => target,
+                None => return Err(LinkError::Undefined { symbol: symbol.name.clone(), input: name.clone() }),
+            };
+
+            let (out, addr) = placed[&(i, r.section)];
+            let at = (addr - sections[out].addr + r.offset) as usize;
+            match r.kind {
+                RelocationKind::Abs32 => {
+                    let value = target.wrapping_add(r.addend as u32);
+                    sections[out].data[at..at + 4].copy_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    // Keep every defined symbol for debugging
+    let mut symbols = vec![];
+    for (i, (_, object)) in inputs.iter().enumerate() {
+        for (k, s) in object.symbols.iter().enumerate() {
+            if let (Some(j), Some(addr)) = (s.section, addresses[i][k]) {
+                let out = placed[&(i, j)].0;
+                symbols.push(Symbol {
+                    name: s.name.clone(),
+                    section: Some(out),
+                    value: addr - sections[out].addr,
+                    global: s.global,
+                });
+            }
+        }
+    }
+
+    // Relocate line info into the output sections, merging source files with the same name
+    let mut files: Vec<String> = vec![];
+    let mut lines = vec![];
+    for (i, (_, object)) in inputs.iter().enumerate() {
+        for l in object.lines.iter() {
+            let name = &object.files[l.file];
+            let file = match files.iter().position(|f| f == name) {
+                Some(file) => file,
+                None => {
+                    files.push(name.clone());
+                    files.len() - 1
+                }
+            };
+            let (out, addr) = placed[&(i, l.section)];
+            lines.push(LineEntry { section: out, offset: addr - sections[out].addr + l.offset, file, line: l.line });
+        }
+    }
+
+    let entry = match &script.entry {
+        Some(entry) => match globals.get(entry.as_str()) {
+            Some(&(_, addr)) => Some(addr),
+            None => return Err(LinkError::UndefinedEntry(entry.clone())),
+        },
+        None => globals.get("main").map(|&(_, addr)| addr).or_else(|| sections.first().map(|s| s.addr)),
+    };
+
+    Ok(Linked {
+        executable: Object {
+            kind: ObjectKind::Executable,
+            entry,
+            sections,
+            symbols,
+            relocations: vec![],
+            files,
+            lines,
+        },
+        placements,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::object::Relocation;
+
+    fn symbol(name: &str, section: Option<usize>, value: u32) -> Symbol {
+        Symbol { name: name.to_string(), section, value, global: true }
+    }
+
+    fn relocation(section: usize, offset: u32, symbol: usize) -> Relocation {
+        Relocation { section, offset, symbol, addend: 0, kind: RelocationKind::Abs32 }
+    }
+
+    fn line(section: usize, offset: u32, line: u32) -> LineEntry {
+        LineEntry { section, offset, file: 0, line }
+    }
+
+    // main: lw x0, [counter]; call inc; ret
+    // inc: ret, with a counter in data and a buffer in bss
+    fn inputs() -> Vec<(String, Object)> {
+        let main = Object {
+            sections: vec![Section::new(".text", SectionKind::Text, vec![0x60, 0, 0, 0, 0, 0x18, 0, 0, 0, 0, 0x19])],
+            symbols: vec![symbol("main", Some(0), 0), symbol("inc", None, 0), symbol("counter", None, 0)],
+            relocations: vec![relocation(0, 6, 1), relocation(0, 1, 2)],
+            files: vec!["main.s".to_string()],
+            lines: vec![line(0, 0, 1), line(0, 5, 2), line(0, 10, 3)],
+            ..Object::default()
+        };
+        let mut text = Section::new(".text", SectionKind::Text, vec![0x19]);
+        text.align = 4;
+        let inc = Object {
+            sections: vec![text, Section::new(".data", SectionKind::Data, vec![1, 0, 0, 0]), Section::bss(".bss", 16)],
+            symbols: vec![symbol("inc", Some(0), 0), symbol("counter", Some(1), 0), symbol("buffer", Some(2), 0)],
+            files: vec!["inc.s".to_string()],
+            lines: vec![line(0, 0, 2)],
+            ..Object::default()
+        };
+        vec![("main.cwo".to_string(), main), ("inc.cwo".to_string(), inc)]
+    }
+
+    #[test]
+    fn link_layout() {
+        let linked = link(&Script::default(), &inputs()).unwrap();
+        let exe = &linked.executable;
+
+        assert_eq!(exe.entry, Some(0));
+        assert_eq!(exe.sections.len(), 3);
+        assert_eq!((exe.sections[0].addr, exe.sections[0].size), (0, 13));
+        assert_eq!(exe.sections[0].permissions, READ | EXEC);
+        assert_eq!(exe.sections[1].addr, PAGE_SIZE);
+        assert_eq!(exe.sections[2].addr, 2 * PAGE_SIZE);
+        assert_eq!(exe.sections[2].kind, SectionKind::Bss);
+
+        // inc is aligned to 4 after main's 11 bytes
+        assert_eq!(exe.sections[0].data[1..5], PAGE_SIZE.to_le_bytes());
+        assert_eq!(exe.sections[0].data[6..10], 12u32.to_le_bytes());
+        assert_eq!(linked.placements[0][1], Placement { input: "inc.cwo".to_string(), addr: 12, size: 1 });
+
+        // Line info follows the code it describes
+        assert_eq!(exe.files, vec!["main.s".to_string(), "inc.s".to_string()]);
+        assert_eq!(exe.lines[3], LineEntry { section: 0, offset: 12, file: 1, line: 2 });
+
+        let mut map = vec![];
+        linked.write_map(&mut map).unwrap();
+        let map = String::from_utf8(map).unwrap();
+        assert!(map.contains("\n0x0000000c inc\n"));
+        assert!(map.contains("\n0x00020000 buffer\n"));
+    }
+
+    #[test]
+    fn link_script() {
+        let script = Script::parse("entry inc\nsection .text at 0x1000 # code\nsection .bss at 0x8000\n").unwrap();
+        let linked = link(&script, &inputs()).unwrap();
+        let exe = &linked.executable;
+        assert_eq!(exe.entry, Some(0x100c));
+        assert_eq!(exe.sections[1].addr, 0x8000);
+
+        // .data is not in the script so it goes on the next page after .bss
+        assert_eq!((exe.sections[2].name.as_str(), exe.sections[2].addr), (".data", PAGE_SIZE));
+
+        assert!(matches!(Script::parse("section .text align 3"), Err(LinkError::Script { line: 1, .. })));
+        assert!(matches!(Script::parse("\nsegment .text"), Err(LinkError::Script { line: 2, .. })));
+
+        let script = Script::parse("section .text at 0\nsection .data at 4").unwrap();
+        assert!(matches!(link(&script, &inputs()), Err(LinkError::Overlap { .. })));
+    }
+
+    #[test]
+    fn link_errors() {
+        let mut inputs = inputs();
+        inputs[1].1.symbols[0].name = "dec".to_string();
+        assert!(matches!(link(&Script::default(), &inputs), Err(LinkError::Undefined { ref symbol, .. }) if symbol == "inc"));
+
+        let mut inputs = self::inputs();
+        inputs[1].1.symbols[0].name = "main".to_string();
+        assert!(matches!(link(&Script::default(), &inputs), Err(LinkError::Duplicate { .. })));
+
+        let script = Script { entry: Some("start".to_string()), ..Script::default() };
+        assert!(matches!(link(&script, &self::inputs()), Err(LinkError::UndefinedEntry(_))));
+
+        // A symbol may be at the end of its section but not past it, or past the address space
+        let mut inputs = self::inputs();
+        inputs[1].1.symbols[1].value = 4;
+        assert!(link(&Script::default(), &inputs).is_ok());
+        inputs[1].1.symbols[1].value = 5;
+        assert!(matches!(
+            link(&Script::default(), &inputs),
+            Err(LinkError::SymbolOutOfRange { ref symbol, ref input }) if symbol == "counter" && input == "inc.cwo"
+        ));
+        inputs[1].1.symbols[1].value = u32::MAX;
+        assert!(matches!(link(&Script::default(), &inputs), Err(LinkError::SymbolOutOfRange { .. })));
+
+        // The end of a section at the top of the address space has no address
+        let script = Script::parse("section .text\nsection .bss\nsection .data at 0xfffffffc").unwrap();
+        let mut inputs = self::inputs();
+        inputs[1].1.symbols[1].value = 3;
+        assert!(link(&script, &inputs).is_ok());
+        inputs[1].1.symbols[1].value = 4;
+        assert!(matches!(link(&script, &inputs), Err(LinkError::SymbolOutOfRange { .. })));
+    }
+}
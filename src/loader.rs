@@ -0,0 +1,317 @@
+// Program image loaders for flat binaries, Intel HEX and Motorola S-records
+
+use crate::{Address, BusError, Cpu, R_BASE, R_PC, R_SP};
+
+#[derive(Debug)]
+pub enum LoadError {
+    Parse { line: usize, message: String },
+    Overlap { first: u32, second: u32 },
+    OutOfRange { addr: u32, len: u64, limit: u64 },
+    BusError { addr: u32 },
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            LoadError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+            LoadError::Overlap { first, second } => {
+                write!(f, "segments at {:#010x} and {:#010x} overlap", first, second)
+            }
+            LoadError::OutOfRange { addr, len, limit } => write!(
+                f,
+                "segment at {:#010x} of {} bytes is outside of physical memory (limit {:#x})",
+                addr, len, limit
+            ),
+            LoadError::BusError { addr } => write!(f, "bus error writing physical {:#010x}", addr),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+// Number of zero bytes written at a time when filling a segment's zeros
+const ZERO_CHUNK: usize = 0x1000;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub addr: u32,
+    pub data: Vec<u8>,
+
+    // Number of zero bytes following data, so that large bss sections aren't allocated up front
+    pub zeros: u32,
+}
+
+impl Segment {
+    pub fn len(&self) -> u64 {
+        self.data.len() as u64 + self.zeros as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn end(&self) -> u64 {
+        self.addr as u64 + self.len()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Image {
+    pub segments: Vec<Segment>,
+    pub entry: Option<u32>,
+}
+
+fn parse_error<T>(line: usize, message: &str) -> Result<T, LoadError> {
+    Err(LoadError::Parse { line, message: message.to_string() })
+}
+
+// Decodes the hex digits of a record after its start character
+fn record_bytes(line: usize, record: &str) -> Result<Vec<u8>, LoadError> {
+    if !record.is_ascii() {
+        return parse_error(line, "record is not ASCII");
+    }
+    if !record.len().is_multiple_of(2) {
+        return parse_error(line, "odd number of hex digits");
+    }
+    (0..record.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&record[i..i + 2], 16))
+        .collect::<Result<_, _>>()
+        .or_else(|_| parse_error(line, "invalid hex digit"))
+}
+
+impl Image {
+    pub fn from_binary(data: &[u8], base: u32) -> Image {
+        Image {
+            segments: vec![Segment { addr: base, data: data.to_vec(), zeros: 0 }],
+            entry: Some(base),
+        }
+    }
+
+    // Adds data to the image, extending the last segment if it is contiguous
+    fn push(&mut self, addr: u32, data: &[u8]) {
+        match self.segments.last_mut() {
+            Some(s) if s.zeros == 0 && s.end() == addr as u64 => s.data.extend_from_slice(data),
+            _ => self.segments.push(Segment { addr, data: data.to_vec(), zeros: 0 }),
+        }
+    }
+
+    pub fn parse_ihex(text: &str) -> Result<Image, LoadError> {
+        let mut image = Image::default();
+        let mut base = 0u32;
+
+        for (i, line) in text.lines().enumerate() {
+            let line_no = i + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            let record = match line.strip_prefix(':') {
+                Some(record) => record_bytes(line_no, record)?,
+                None => return parse_error(line_no, "record does not start with `:`"),
+            };
+            if record.len() < 5 || record.len() != record[0] as usize + 5 {
+                return parse_error(line_no, "record length does not match its byte count");
+            }
+            if record.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) != 0 {
+                return parse_error(line_no, "checksum mismatch");
+            }
+
+            let offset = (record[1] as u32) << 8 | record[2] as u32;
+            let data = &record[4..record.len() - 1];
+            let word = |data: &[u8]| data.iter().fold(0u32, |acc, &b| acc << 8 | b as u32);
+            match record[3] {
+                0x00 => image.push(base.wrapping_add(offset), data),
+                0x01 => return Ok(image),
+                0x02 if data.len() == 2 => base = word(data) << 4,
+                0x03 if data.len() == 4 => {
+                    image.entry = Some((word(&data[..2]) << 4).wrapping_add(word(&data[2..])))
+                }
+                0x04 if data.len() == 2 => base = word(data) << 16,
+                0x05 if data.len() == 4 => image.entry = Some(word(data)),
+                0x02..=0x05 => return parse_error(line_no, "wrong data length for record type"),
+                t => return parse_error(line_no, &format!("unknown record type {:02x}", t)),
+            }
+        }
+
+        parse_error(text.lines().count(), "missing end of file record")
+    }
+
+    pub fn parse_srec(text: &str) -> Result<Image, LoadError> {
+        let mut image = Image::default();
+
+        for (i, line) in text.lines().enumerate() {
+            let line_no = i + 1;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if !line.is_ascii() {
+                return parse_error(line_no, "record is not ASCII");
+            }
+            let (kind, record) = match line.strip_prefix('S').and_then(|r| Some((*r.as_bytes().first()?, r.get(1..)?))) {
+                Some((kind, record)) => (kind, record_bytes(line_no, record)?),
+                None => return parse_error(line_no, "record does not start with `S`"),
+            };
+            if record.is_empty() || record.len() != record[0] as usize + 1 {
+                return parse_error(line_no, "record length does not match its byte count");
+            }
+            if record.iter().fold(0u8, |acc, b| acc.wrapping_add(*b)) != 0xff {
+                return parse_error(line_no, "checksum mismatch");
+            }
+
+            let addr_len = match kind {
+                b'0' | b'1' | b'5' | b'9' => 2,
+                b'2' | b'6' | b'8' => 3,
+                b'3' | b'7' => 4,
+                _ => return parse_error(line_no, &format!("unknown record type S{}", kind as char)),
+            };
+            if record.len() < addr_len + 2 {
+                return parse_error(line_no, "record too short for its address");
+            }
+
+            let addr = record[1..=addr_len].iter().fold(0u32, |acc, &b| acc << 8 | b as u32);
+            let data = &record[addr_len + 1..record.len() - 1];
+            match kind {
+                b'1' | b'2' | b'3' => image.push(addr, data),
+                b'7' | b'8' | b'9' => image.entry = Some(addr),
+
+                // Headers and record counts
+                _ => (),
+            }
+        }
+
+        Ok(image)
+    }
+
+    // Checks that no two segments overlap and that all of them fit below limit
+    pub fn validate(&self, limit: u64) -> Result<(), LoadError> {
+        let mut segments: Vec<&Segment> = self.segments.iter().filter(|s| !s.is_empty()).collect();
+        segments.sort_by_key(|s| s.addr);
+
+        for s in segments.iter() {
+            if s.end() > limit {
+                return Err(LoadError::OutOfRange { addr: s.addr, len: s.len(), limit });
+            }
+        }
+        for pair in segments.windows(2) {
+            if pair[0].end() > pair[1].addr as u64 {
+                return Err(LoadError::Overlap { first: pair[0].addr, second: pair[1].addr });
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T> Cpu<T>
+where
+    T: Address,
+{
+    // Writes the image into physical memory and sets the pc to its entry point (or the start of
+    // its first segment) and the stack and base pointers to sp
+    pub fn load_image(&mut self, image: &Image, sp: u32) -> Result<(), LoadError> {
+        image.validate(self.addressing.size())?;
+
+        let zeros = [0; ZERO_CHUNK];
+        for s in image.segments.iter() {
+            let mut written = self.addressing.write_slice(s.addr, &s.data);
+            let mut at = s.addr as u64 + s.data.len() as u64;
+            while written.is_ok() && at < s.end() {
+                let len = (s.end() - at).min(ZERO_CHUNK as u64) as usize;
+                written = self.addressing.write_slice(at as u32, &zeros[..len]);
+                at += len as u64;
+            }
+            if let Err(BusError(addr)) = written {
+                self.flush_decode_cache();
+                return Err(LoadError::BusError { addr });
+            }
+        }
+        self.flush_decode_cache();
+
+        if let Some(pc) = image.entry.or_else(|| image.segments.first().map(|s| s.addr)) {
+            self.xs[R_PC] = pc;
+        }
+        self.xs[R_SP] = sp;
+        self.xs[R_BASE] = sp;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleAddress;
+
+    #[test]
+    fn loader_ihex() {
+        let text = "\
+:0400000040050000B7
+:02000004000AF0
+:02FF000080017E
+:04000005000A1234A7
+:00000001FF
+";
+        let image = Image::parse_ihex(text).unwrap();
+        assert_eq!(image.segments, vec![
+            Segment { addr: 0, data: vec![0x40, 5, 0, 0], zeros: 0 },
+            Segment { addr: 0xaff00, data: vec![0x80, 0x01], zeros: 0 },
+        ]);
+        assert_eq!(image.entry, Some(0x000a1234));
+
+        let bad = text.replace(":02FF000080017E", ":02FF000080017F");
+        assert!(matches!(Image::parse_ihex(&bad), Err(LoadError::Parse { line: 3, .. })));
+        assert!(Image::parse_ihex(":0400000040050000B7").is_err());
+    }
+
+    #[test]
+    fn loader_srec() {
+        let text = "\
+S00600004844521B
+S107000040050000B3
+S107000400000000F4
+S5030002FA
+S9030000FC
+";
+        let image = Image::parse_srec(text).unwrap();
+        assert_eq!(image.segments, vec![Segment { addr: 0, data: vec![0x40, 5, 0, 0, 0, 0, 0, 0], zeros: 0 }]);
+        assert_eq!(image.entry, Some(0));
+
+        assert!(Image::parse_srec("S1070000400500004D").is_err());
+        assert!(Image::parse_srec("S4030000FC").is_err());
+
+        // Multibyte characters are rejected rather than split
+        assert!(matches!(Image::parse_srec("Sé030000FC"), Err(LoadError::Parse { line: 1, .. })));
+        assert!(matches!(Image::parse_srec("S1é"), Err(LoadError::Parse { line: 1, .. })));
+        assert!(matches!(Image::parse_srec("éS1"), Err(LoadError::Parse { line: 1, .. })));
+        assert!(matches!(Image::parse_ihex(":é"), Err(LoadError::Parse { line: 1, .. })));
+    }
+
+    #[test]
+    fn loader_validation() {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+
+        let mut image = Image::from_binary(&[1, 2, 3, 4], 0x100);
+        image.segments.push(Segment { addr: 0x102, data: vec![5], zeros: 0 });
+        assert!(matches!(cpu.load_image(&image, 0), Err(LoadError::Overlap { first: 0x100, second: 0x102 })));
+
+        let image = Image::from_binary(&[1, 2], 0xffffff);
+        assert!(matches!(cpu.load_image(&image, 0), Err(LoadError::OutOfRange { addr: 0xffffff, .. })));
+
+        let image = Image::from_binary(&[1, 2], 0xaf42);
+        cpu.load_image(&image, 0xbfff).unwrap();
+        assert_eq!(cpu.addressing.memory[0xaf43], 2);
+        assert_eq!(cpu.xs[R_PC], 0xaf42);
+        assert_eq!(cpu.xs[R_SP], 0xbfff);
+
+        // Zeros are written over what was there, and a segment of almost 4 GiB of them is only
+        // checked against the size of memory
+        let image = Image { segments: vec![Segment { addr: 0xaf40, data: vec![7], zeros: 3 }], entry: None };
+        cpu.load_image(&image, 0).unwrap();
+        assert_eq!(cpu.addressing.memory[0xaf40..0xaf45], [7, 0, 0, 0, 0]);
+        let bss = Segment { addr: 0x1000, data: vec![], zeros: u32::MAX - 0x1000 };
+        let image = Image { segments: vec![bss], entry: None };
+        assert!(matches!(cpu.load_image(&image, 0), Err(LoadError::OutOfRange { len: 0xffffefff, .. })));
+    }
+}
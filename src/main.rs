@@ -0,0 +1,847 @@
+use std::io::{self, BufRead, Write};
+
+use cpuwu::debug::{Access, Space, StopReason, Trigger};
+use cpuwu::disasm;
+use cpuwu::flags::{Flag, Flags};
+use cpuwu::float::FP_TRAP_INTERRUPT;
+use cpuwu::gdb::{self, GdbStub};
+use cpuwu::history::History;
+use cpuwu::loader::Image;
+use cpuwu::object::Object;
+use cpuwu::perf::Counter;
+use cpuwu::symbols::Symbols;
+use cpuwu::coverage::Coverage;
+use cpuwu::lines::LineTable;
+use cpuwu::profile::Profiler;
+use cpuwu::trace::{TraceFormat, Tracer};
+use cpuwu::{Cpu, InvalidMemoryAccess, SimpleAddress, EXEC, READ, WRITE};
+
+const DEFAULT_STEP_LIMIT: u64 = 10_000_000;
+const DEFAULT_TRACE_CAPACITY: u32 = 10_000;
+const DEFAULT_PROFILE_INTERVAL: u32 = 1000;
+const DEFAULT_HISTORY_CAPACITY: u32 = 100_000;
+
+const HELP: &str = "\
+commands:
+  load <file> [base [sp]]   load a raw binary (at base), Intel HEX (.hex), S-record (.srec) or
+                            executable (.cwx) image into physical memory and set the pc and sp
+  symbols <file>            load symbols from an executable or an `<addr> <name>` map file
+  step [n]                  execute n instructions (alias: s)
+  continue [n]              run until a breakpoint or watchpoint, at most n steps (alias: c)
+  reset                     reset the cpu to its power-on state, keeping memory
+  record [n]                record undo history for the last n steps (default 100000)
+  record stop               stop recording undo history
+  rstep [n]                 undo n steps (alias: rs)
+  rcontinue [n]             undo steps until a breakpoint or watchpoint (alias: rc)
+  save <file>               save a snapshot of the machine
+  restore <file>            restore a snapshot of the machine
+  break [-p] <addr>         set a breakpoint on a virtual (or physical) address (alias: b)
+  watch [-p] <addr> [len]   stop after len bytes at an address are written (default 4)
+  rwatch [-p] <addr> [len]  stop after len bytes at an address are read
+  awatch [-p] <addr> [len]  stop after len bytes at an address are read or written
+  delete <id>               delete a breakpoint or watchpoint (alias: d)
+  backtrace                 print the call stack (alias: bt)
+  regs                      print the integer and float registers
+  flags                     print the flags register
+  set <reg> <value>         set x0-x15, f0-f15, pc, bp, sp, flags, mask, memmap, fpcr, cycles or a
+                            flag
+  x[/<n><fmt>] <addr>       examine virtual memory, fmt is b (bytes), w (words) or i (instructions)
+  xp[/<n><fmt>] <addr>      examine physical memory
+  translate <addr>          translate a virtual address into a physical address (alias: tr)
+  info <what>               what is breakpoints, counters, interrupts, paging or symbols
+  trace on [capacity]       record executed instructions (default capacity 10000)
+  trace off                 stop recording
+  trace show [n]            print the last n recorded instructions (default 10)
+  trace save <file> [fmt]   write the trace as text, json or bin (default text)
+  profile on [interval]     sample the call stack every interval cycles (default 1000)
+  profile off               stop sampling
+  profile report            print samples per function
+  profile folded <file>     write the samples as folded stacks for flamegraph tools
+  profile annotate [file]   print or write the sampled functions' disassembly with hit counts
+  coverage on               record executed instructions and branch directions
+  coverage off              stop recording
+  coverage report           print how much has been covered
+  coverage lcov <file>      write an lcov report using the executable's line info
+  gdb <port|path>           wait for a gdb connection on a localhost port or unix socket path
+  help                      print this message
+  quit                      exit the debugger (alias: q)
+
+addresses can be numbers, symbols or either with an offset, like `main`, `buffer+8` or `0x100-4`";
+
+struct Debugger {
+    cpu: Cpu<SimpleAddress>,
+    symbols: Symbols,
+    lines: LineTable,
+}
+
+fn parse_num(s: &str) -> Result<u32, String> {
+    cpuwu::parse_num(s).ok_or_else(|| format!("invalid number `{}`", s))
+}
+
+fn parse_reg(s: &str) -> Option<(char, usize)> {
+    match s {
+        "pc" => return Some(('x', 13)),
+        "bp" => return Some(('x', 14)),
+        "sp" => return Some(('x', 15)),
+        _ => (),
+    }
+
+    let (kind, index) = match (s.strip_prefix('x'), s.strip_prefix('f')) {
+        (Some(index), _) => ('x', index),
+        (_, Some(index)) => ('f', index),
+        _ => return None,
+    };
+    match index.parse() {
+        Ok(index) if index < 16 => Some((kind, index)),
+        _ => None,
+    }
+}
+
+fn parse_space<'a>(args: &'a [&'a str]) -> (Space, &'a [&'a str]) {
+    match args.split_first() {
+        Some((&"-p", rest)) => (Space::Physical, rest),
+        _ => (Space::Virtual, args),
+    }
+}
+
+fn space_name(space: Space) -> &'static str {
+    match space {
+        Space::Virtual => "",
+        Space::Physical => " (physical)",
+    }
+}
+
+fn fault_name(e: &InvalidMemoryAccess) -> String {
+    match e {
+        InvalidMemoryAccess::UsedFreePage => "unmapped page".to_string(),
+        InvalidMemoryAccess::InvalidPermissions(p, r) => {
+            format!("page permissions {} do not allow {}", perms(*p), perms(*r))
+        }
+        InvalidMemoryAccess::UnprivilegedOpcode => "unprivileged opcode".to_string(),
+        InvalidMemoryAccess::Misaligned(addr) => format!("misaligned access at {:#010x}", addr),
+        InvalidMemoryAccess::BusError(paddr) => format!("bus error at physical {:#010x}", paddr),
+    }
+}
+
+fn perms(p: u8) -> String {
+    let mut s = String::new();
+    s.push(if p & READ != 0 { 'r' } else { '-' });
+    s.push(if p & WRITE != 0 { 'w' } else { '-' });
+    s.push(if p & EXEC != 0 { 'x' } else { '-' });
+    s
+}
+
+impl Debugger {
+    fn new() -> Debugger {
+        Debugger {
+            cpu: Cpu::new(SimpleAddress::default()),
+            symbols: Symbols::new(),
+            lines: LineTable::new(),
+        }
+    }
+
+    fn parse_addr(&self, s: &str) -> Result<u32, String> {
+        self.symbols.resolve(s).ok_or_else(|| format!("invalid address `{}`", s))
+    }
+
+    // An address followed by the symbol it is in, if any
+    fn location(&self, addr: u32) -> String {
+        match self.symbols.describe(addr) {
+            Some(symbol) => format!("{:#010x} <{}>", addr, symbol),
+            None => format!("{:#010x}", addr),
+        }
+    }
+
+    fn read_virtual(&mut self, addr: u32) -> Result<u8, InvalidMemoryAccess> {
+        let addr = self.cpu.translate(addr, 0)?;
+        Ok(self.cpu.read_physical(addr)?)
+    }
+
+    fn load(&mut self, path: &str, base: u32, sp: u32) -> Result<(), String> {
+        let data = std::fs::read(path).map_err(|e| format!("could not read `{}`: {}", path, e))?;
+        let extension = std::path::Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or("");
+        let parse_error = |e: &dyn std::fmt::Display| format!("could not parse `{}`: {}", path, e);
+        let image = match extension.to_ascii_lowercase().as_str() {
+            "hex" | "ihex" => Image::parse_ihex(&String::from_utf8_lossy(&data)).map_err(|e| parse_error(&e))?,
+            "srec" | "s19" | "s28" | "s37" | "mot" => {
+                Image::parse_srec(&String::from_utf8_lossy(&data)).map_err(|e| parse_error(&e))?
+            }
+            "cwo" | "cwx" => {
+                let object = Object::read(&data[..]).map_err(|e| parse_error(&e))?;
+                let image = object.to_image().map_err(|e| parse_error(&e))?;
+                self.symbols = Symbols::from_object(&object);
+                self.lines = LineTable::from_object(&object);
+                image
+            }
+            _ => Image::from_binary(&data, base),
+        };
+
+        self.cpu.load_image(&image, sp).map_err(|e| format!("could not load `{}`: {}", path, e))?;
+        for s in image.segments.iter() {
+            println!("loaded {} bytes at {:#010x}", s.len(), s.addr);
+        }
+        Ok(())
+    }
+
+    fn load_symbols(&mut self, path: &str) -> Result<(), String> {
+        let data = std::fs::read(path).map_err(|e| format!("could not read `{}`: {}", path, e))?;
+        let symbols = match Object::read(&data[..]) {
+            Ok(object) => {
+                self.lines = LineTable::from_object(&object);
+                Symbols::from_object(&object)
+            }
+            Err(_) => Symbols::parse_map(&String::from_utf8_lossy(&data))
+                .map_err(|e| format!("could not parse `{}`: {}", path, e))?,
+        };
+        println!("loaded {} symbols", symbols.len());
+        self.symbols = symbols;
+        Ok(())
+    }
+
+    fn run(&mut self, limit: u64) {
+        match self.cpu.run(limit) {
+            StopReason::StepLimit => (),
+            reason @ StopReason::Fault { .. } => {
+                println!("{}", self.fault_message(reason));
+                self.print_backtrace();
+            }
+            reason => println!("{}", reason),
+        }
+        self.print_current();
+    }
+
+    fn fault_message(&self, reason: StopReason) -> String {
+        let (interrupt, pc, cause) = match reason {
+            StopReason::Fault { interrupt, pc, cause } => (interrupt & 0x7fffffff, pc, cause),
+            _ => unreachable!(),
+        };
+        let fault = match cause {
+            Some(e) => fault_name(&e),
+            None if interrupt == FP_TRAP_INTERRUPT => "floating point exception".to_string(),
+            None => "unknown fault".to_string(),
+        };
+        format!("fault {:#x} ({}) at {}", interrupt, fault, self.location(pc))
+    }
+
+    fn print_backtrace(&mut self) {
+        print!("{}", self.cpu.backtrace().format(&self.symbols));
+    }
+
+    fn print_current(&mut self) {
+        let pc = self.cpu.pc();
+        match self.disassemble(pc) {
+            Some(i) => println!("{}: {}", self.location(pc), i.annotate(&self.symbols)),
+            None => println!("{}: <unreadable>", self.location(pc)),
+        }
+    }
+
+    fn disassemble(&mut self, addr: u32) -> Option<disasm::Instruction> {
+        disasm::disassemble(addr, |a| self.read_virtual(a).ok())
+    }
+
+    fn print_registers(&self) {
+        for i in 0..16 {
+            let name = match i {
+                13 => " (pc)",
+                14 => " (bp)",
+                15 => " (sp)",
+                _ => "",
+            };
+            let x = self.cpu.int_register(i);
+            println!(
+                "x{:<2} {:#010x} {:<11}{:<6} f{:<2} {:?}",
+                i, x, x as i32, name, i, self.cpu.float_register(i)
+            );
+        }
+        self.print_flags();
+    }
+
+    fn print_flags(&self) {
+        let flags = Flags::from_bits(self.cpu.flags());
+        let set: String = Flag::ALL
+            .iter()
+            .map(|&flag| if flags.get(flag) { flag.label() } else { '-' })
+            .collect();
+        println!(
+            "flags {:#010x} [{}] last interrupt {}, mask {:#04x}, memmap {:#010x}, system sp {:#010x}",
+            flags.bits(),
+            set,
+            flags.last_interrupt(),
+            self.cpu.interrupt_mask(),
+            self.cpu.memmap(),
+            self.cpu.system_sp()
+        );
+        println!("cycles {}", self.cpu.cycles());
+        println!("fpcr {:#010x}", self.cpu.float_control());
+    }
+
+    fn set(&mut self, reg: &str, value: &str) -> Result<(), String> {
+        if let Some(('x', x)) = parse_reg(reg) {
+            self.cpu.set_int_register(x, self.parse_addr(value)?);
+        } else if let Some(('f', f)) = parse_reg(reg) {
+            let value = value.parse().map_err(|_| format!("invalid float `{}`", value))?;
+            self.cpu.set_float_register(f, value);
+        } else if reg == "flags" {
+            self.cpu.set_flags(parse_num(value)?);
+        } else if reg == "mask" {
+            self.cpu.set_interrupt_mask(parse_num(value)? as u8);
+        } else if reg == "memmap" {
+            self.cpu.set_memmap(parse_num(value)?);
+        } else if reg == "fpcr" {
+            self.cpu.set_float_control(parse_num(value)?);
+        } else if reg == "cycles" {
+            let cycles = value.parse().map_err(|_| format!("invalid number `{}`", value))?;
+            self.cpu.set_cycles(cycles);
+        } else if let Some(&flag) = Flag::ALL
+            .iter()
+            .find(|flag| reg.len() == 1 && reg.eq_ignore_ascii_case(&flag.label().to_string()))
+        {
+            let mut flags = Flags::from_bits(self.cpu.flags());
+            flags.set(flag, parse_num(value)? & 1 != 0);
+            self.cpu.set_flags(flags.bits());
+        } else {
+            return Err(format!("unknown register `{}`", reg));
+        }
+        Ok(())
+    }
+
+    fn examine(&mut self, format: &str, addr: u32, physical: bool) -> Result<(), String> {
+        let format = format.strip_prefix('/').unwrap_or(format);
+        let split = format.find(|c: char| !c.is_ascii_digit()).unwrap_or(format.len());
+        let count = if split == 0 { 1 } else { parse_num(&format[..split])? };
+        let kind = format[split..].chars().next().unwrap_or('w');
+
+        let mut addr = addr;
+        for _ in 0..count {
+            match kind {
+                'i' if !physical => match self.disassemble(addr) {
+                    Some(i) => {
+                        if let Some((name, 0)) = self.symbols.find(addr) {
+                            println!("{}:", name);
+                        }
+                        println!("{:#010x}: {}", addr, i.annotate(&self.symbols));
+                        addr = addr.wrapping_add(i.len);
+                    }
+                    None => return Err(format!("cannot read {:#010x}", addr)),
+                },
+
+                'b' | 'w' => {
+                    let size = if kind == 'b' { 1 } else { 4 };
+                    let mut bytes = vec![];
+                    for i in 0..size {
+                        let a = addr.wrapping_add(i);
+                        let byte = if physical {
+                            self.cpu.read_physical(a).map_err(Into::into)
+                        } else {
+                            self.read_virtual(a)
+                        };
+                        bytes.push(byte.map_err(|e| format!("{:#010x}: {}", a, fault_name(&e)))?);
+                    }
+                    let value = bytes.iter().rev().fold(0u32, |acc, &b| acc << 8 | b as u32);
+                    println!("{:#010x}: {:#0width$x}", addr, value, width = size as usize * 2 + 2);
+                    addr = addr.wrapping_add(size);
+                }
+
+                _ => return Err(format!("unknown format `{}`", kind)),
+            }
+        }
+        Ok(())
+    }
+
+    fn info(&mut self, what: &str) -> Result<(), String> {
+        match what {
+            "breakpoints" | "b" => {
+                for (id, trigger) in self.cpu.triggers() {
+                    match *trigger {
+                        Trigger::Breakpoint { space: Space::Virtual, addr } => {
+                            println!("{:<3} break {}", id, self.location(addr))
+                        }
+
+                        Trigger::Breakpoint { space, addr } => {
+                            println!("{:<3} break {:#010x}{}", id, addr, space_name(space))
+                        }
+
+                        Trigger::Watchpoint { space, start, len, access } => {
+                            let kind = match access {
+                                Access::Read => "rwatch",
+                                Access::Write => "watch",
+                                Access::ReadWrite => "awatch",
+                            };
+                            println!(
+                                "{:<3} {} {:#010x}{} ({} bytes)",
+                                id, kind, start, space_name(space), len
+                            );
+                        }
+                    }
+                }
+            }
+
+            "interrupts" | "i" => {
+                let enabled = Flags::from_bits(self.cpu.flags()).get(Flag::InterruptEnable);
+                println!(
+                    "interrupts {}, mask {:#010b}",
+                    if enabled { "enabled" } else { "disabled" },
+                    self.cpu.interrupt_mask()
+                );
+                if self.cpu.interrupt_queue().is_empty() {
+                    println!("no pending interrupts");
+                }
+                for (i, int) in self.cpu.interrupt_queue().iter().enumerate() {
+                    if int & 0x80000000 != 0 {
+                        println!("{:<3} nmi {:#x}", i, int & 0x7fffffff);
+                    } else {
+                        println!("{:<3} irq {}", i, int);
+                    }
+                }
+            }
+
+            "paging" | "p" => {
+                let enabled = Flags::from_bits(self.cpu.flags()).get(Flag::MemmapEnable);
+                println!(
+                    "paging {}, page table at {:#010x}",
+                    if enabled { "enabled" } else { "disabled" },
+                    self.cpu.memmap()
+                );
+                if !enabled {
+                    return Ok(());
+                }
+
+                // Pages are 64 KiB
+                for page in 0..=0xffffu32 {
+                    let vaddr = page << 16;
+                    let paddr = match self.cpu.translate(vaddr, 0) {
+                        Ok(paddr) => paddr,
+                        Err(_) => continue,
+                    };
+                    let p = match self.cpu.translate(vaddr, READ | WRITE | EXEC) {
+                        Ok(_) => READ | WRITE | EXEC,
+                        Err(InvalidMemoryAccess::InvalidPermissions(p, _)) => p,
+                        Err(_) => 0,
+                    };
+                    println!("{:#010x} -> {:#010x} {}", vaddr, paddr, perms(p));
+                }
+            }
+
+            "counters" | "c" => {
+                for counter in Counter::ALL.iter() {
+                    println!("{:<9} {}", counter.name(), self.cpu.counters().get(*counter));
+                }
+                let access = if self.cpu.counters().user_read() { "allowed" } else { "denied" };
+                println!("user ring access {}", access);
+            }
+
+            "symbols" | "s" => {
+                for (addr, name) in self.symbols.iter() {
+                    println!("{:#010x} {}", addr, name);
+                }
+            }
+
+            _ => return Err(format!("unknown info `{}`", what)),
+        }
+        Ok(())
+    }
+
+    fn gdb(&mut self, target: &str) -> io::Result<()> {
+        let mut stub = GdbStub::new(&mut self.cpu);
+        if let Ok(port) = target.parse() {
+            println!("waiting for gdb on 127.0.0.1:{}", port);
+            stub.serve(gdb::accept_tcp(port)?)?;
+        } else {
+            #[cfg(unix)]
+            {
+                println!("waiting for gdb on {}", target);
+                let res = gdb::accept_unix(target).and_then(|conn| stub.serve(conn));
+                let _ = std::fs::remove_file(target);
+                res?;
+            }
+
+            #[cfg(not(unix))]
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "expected a port"));
+        }
+        println!("gdb detached");
+        self.print_current();
+        Ok(())
+    }
+
+    fn trace(&mut self, args: &[&str]) -> Result<(), String> {
+        match args.first().copied() {
+            Some("on") => {
+                let capacity = args.get(1).map(|c| parse_num(c)).transpose()?;
+                let capacity = capacity.unwrap_or(DEFAULT_TRACE_CAPACITY) as usize;
+                self.cpu.set_tracer(Some(Tracer::new(capacity)));
+            }
+
+            Some("off") => {
+                self.cpu.set_tracer(None);
+            }
+
+            Some("show") => {
+                let n = args.get(1).map(|n| parse_num(n)).transpose()?.unwrap_or(10) as usize;
+                let tracer = self.cpu.tracer().ok_or("tracing is off")?;
+                let mut text = vec![];
+                tracer
+                    .write_with_symbols(&mut text, TraceFormat::Text, &self.symbols)
+                    .map_err(|e| e.to_string())?;
+                let text = String::from_utf8_lossy(&text);
+                let lines: Vec<&str> = text.lines().collect();
+                for line in &lines[lines.len().saturating_sub(n)..] {
+                    println!("{}", line);
+                }
+            }
+
+            Some("save") => {
+                let path = args.get(1).ok_or("`trace save` expects a file")?;
+                let format = args.get(2).map(|f| f.parse()).transpose()?.unwrap_or(TraceFormat::Text);
+                let tracer = self.cpu.tracer().ok_or("tracing is off")?;
+                let file = std::fs::File::create(path).map_err(|e| format!("could not create `{}`: {}", path, e))?;
+                tracer
+                    .write_with_symbols(io::BufWriter::new(file), format, &self.symbols)
+                    .map_err(|e| format!("could not write `{}`: {}", path, e))?;
+                println!("wrote {} entries to {}", tracer.entries().len(), path);
+            }
+
+            _ => return Err("expected `trace on`, `trace off`, `trace show` or `trace save`".to_string()),
+        }
+        Ok(())
+    }
+
+    fn profile(&mut self, args: &[&str]) -> Result<(), String> {
+        match args.first().copied() {
+            Some("on") => {
+                let interval = args.get(1).map(|i| parse_num(i)).transpose()?;
+                let interval = interval.unwrap_or(DEFAULT_PROFILE_INTERVAL) as u64;
+                self.cpu.set_profiler(Some(Profiler::new(interval)));
+            }
+
+            Some("off") => {
+                self.cpu.set_profiler(None);
+            }
+
+            Some("report") => {
+                let profiler = self.cpu.profiler().ok_or("profiling is off")?;
+                profiler.write_report(io::stdout(), &self.symbols).map_err(|e| e.to_string())?;
+            }
+
+            Some("folded") => {
+                let path = args.get(1).ok_or("`profile folded` expects a file")?;
+                let profiler = self.cpu.profiler().ok_or("profiling is off")?;
+                let file = std::fs::File::create(path).map_err(|e| format!("could not create `{}`: {}", path, e))?;
+                profiler
+                    .write_folded(io::BufWriter::new(file), &self.symbols)
+                    .map_err(|e| format!("could not write `{}`: {}", path, e))?;
+                println!("wrote {} samples to {}", profiler.samples(), path);
+            }
+
+            Some("annotate") => {
+                // Instructions are fetched through the debugger, so take the profiler out of the
+                // cpu while writing
+                let profiler = self.cpu.set_profiler(None).ok_or("profiling is off")?;
+                let symbols = std::mem::take(&mut self.symbols);
+                let res = match args.get(1) {
+                    Some(path) => std::fs::File::create(path)
+                        .map_err(|e| format!("could not create `{}`: {}", path, e))
+                        .and_then(|file| {
+                            profiler
+                                .write_annotated(io::BufWriter::new(file), &symbols, |a| self.read_virtual(a).ok())
+                                .map_err(|e| format!("could not write `{}`: {}", path, e))
+                        }),
+                    None => profiler
+                        .write_annotated(io::stdout(), &symbols, |a| self.read_virtual(a).ok())
+                        .map_err(|e| e.to_string()),
+                };
+                self.symbols = symbols;
+                self.cpu.set_profiler(Some(profiler));
+                res?;
+            }
+
+            _ => return Err("expected `profile on`, `off`, `report`, `folded` or `annotate`".to_string()),
+        }
+        Ok(())
+    }
+
+    fn coverage(&mut self, args: &[&str]) -> Result<(), String> {
+        match args.first().copied() {
+            Some("on") => {
+                self.cpu.set_coverage(Some(Coverage::new()));
+            }
+
+            Some("off") => {
+                self.cpu.set_coverage(None);
+            }
+
+            Some("report") => {
+                let coverage = self.cpu.coverage().ok_or("coverage is off")?;
+                let (branches, both) = coverage.branches();
+                println!("{} instructions executed", coverage.instructions());
+                println!("{} branches executed, {} of them both taken and not taken", branches, both);
+            }
+
+            Some("lcov") => {
+                let path = args.get(1).ok_or("`coverage lcov` expects a file")?;
+                if self.lines.is_empty() {
+                    return Err("no line info loaded".to_string());
+                }
+                let coverage = self.cpu.set_coverage(None).ok_or("coverage is off")?;
+                let lines = std::mem::take(&mut self.lines);
+                let res = std::fs::File::create(path)
+                    .map_err(|e| format!("could not create `{}`: {}", path, e))
+                    .and_then(|file| {
+                        coverage
+                            .write_lcov(io::BufWriter::new(file), &lines, |a| self.read_virtual(a).ok())
+                            .map_err(|e| format!("could not write `{}`: {}", path, e))
+                    });
+                self.lines = lines;
+                self.cpu.set_coverage(Some(coverage));
+                res?;
+                println!("wrote {}", path);
+            }
+
+            _ => return Err("expected `coverage on`, `off`, `report` or `lcov`".to_string()),
+        }
+        Ok(())
+    }
+
+    fn command(&mut self, line: &str) -> Result<bool, String> {
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let (cmd, args) = match args.split_first() {
+            Some((cmd, args)) => (*cmd, args),
+            None => return Ok(true),
+        };
+        let arg = |i: usize| args.get(i).copied().ok_or_else(|| format!("`{}` expects more arguments", cmd));
+
+        match cmd {
+            "load" => {
+                let base = args.get(1).map(|b| parse_num(b)).transpose()?.unwrap_or(0);
+                let sp = args.get(2).map(|b| parse_num(b)).transpose()?.unwrap_or(0);
+                self.load(arg(0)?, base, sp)?;
+                self.print_current();
+            }
+
+            "symbols" => self.load_symbols(arg(0)?)?,
+
+            "step" | "s" => {
+                let n = args.first().map(|n| parse_num(n)).transpose()?.unwrap_or(1);
+                self.run(n as u64);
+            }
+
+            "continue" | "c" => {
+                let n = args.first().map(|n| parse_num(n)).transpose()?;
+                self.run(n.map(|n| n as u64).unwrap_or(DEFAULT_STEP_LIMIT));
+            }
+
+            "reset" => {
+                self.cpu.reset();
+                self.print_current();
+            }
+
+            "save" => {
+                let path = arg(0)?;
+                let file = std::fs::File::create(path).map_err(|e| format!("could not create `{}`: {}", path, e))?;
+                self.cpu
+                    .save_snapshot(io::BufWriter::new(file))
+                    .map_err(|e| format!("could not write `{}`: {}", path, e))?;
+            }
+
+            "restore" => {
+                let path = arg(0)?;
+                let file = std::fs::File::open(path).map_err(|e| format!("could not open `{}`: {}", path, e))?;
+                self.cpu
+                    .restore_snapshot(io::BufReader::new(file))
+                    .map_err(|e| format!("could not restore `{}`: {}", path, e))?;
+                self.print_current();
+            }
+
+            "record" => match args.first().copied() {
+                Some("stop") => {
+                    self.cpu.set_history(None);
+                }
+
+                n => {
+                    let n = n.map(parse_num).transpose()?.unwrap_or(DEFAULT_HISTORY_CAPACITY);
+                    self.cpu.set_history(Some(History::new(n as usize)));
+                }
+            },
+
+            "rstep" | "rs" => {
+                if self.cpu.history().is_none() {
+                    return Err("not recording, try `record`".to_string());
+                }
+                let n = args.first().map(|n| parse_num(n)).transpose()?.unwrap_or(1);
+                for _ in 0..n {
+                    if !self.cpu.reverse_step() {
+                        println!("reached the start of the recorded history");
+                        break;
+                    }
+                }
+                self.print_current();
+            }
+
+            "rcontinue" | "rc" => {
+                if self.cpu.history().is_none() {
+                    return Err("not recording, try `record`".to_string());
+                }
+                let n = args.first().map(|n| parse_num(n)).transpose()?;
+                match self.cpu.reverse_continue(n.map(|n| n as u64).unwrap_or(u64::MAX)) {
+                    StopReason::StepLimit => (),
+                    reason => println!("{}", reason),
+                }
+                self.print_current();
+            }
+
+            "break" | "b" => {
+                let (space, args) = parse_space(args);
+                let addr = self.parse_addr(args.first().ok_or("`break` expects an address")?)?;
+                let id = self.cpu.add_trigger(Trigger::Breakpoint { space, addr });
+                println!("breakpoint {} at {}{}", id, self.location(addr), space_name(space));
+            }
+
+            "watch" | "rwatch" | "awatch" => {
+                let (space, args) = parse_space(args);
+                let start = self.parse_addr(args.first().ok_or("expected an address")?)?;
+                let len = args.get(1).map(|l| parse_num(l)).transpose()?.unwrap_or(4);
+                let access = match cmd {
+                    "rwatch" => Access::Read,
+                    "watch" => Access::Write,
+                    _ => Access::ReadWrite,
+                };
+                let id = self.cpu.add_trigger(Trigger::Watchpoint { space, start, len, access });
+                println!("watchpoint {} at {}{}", id, self.location(start), space_name(space));
+            }
+
+            "delete" | "d" => {
+                let id = parse_num(arg(0)?)? as usize;
+                if self.cpu.remove_trigger(id).is_none() {
+                    return Err(format!("no breakpoint or watchpoint {}", id));
+                }
+            }
+
+            "backtrace" | "bt" => self.print_backtrace(),
+            "regs" => self.print_registers(),
+            "flags" => self.print_flags(),
+            "set" => self.set(arg(0)?, arg(1)?)?,
+
+            _ if cmd == "xp" || cmd.starts_with("xp/") => self.examine(&cmd[2..], self.parse_addr(arg(0)?)?, true)?,
+            _ if cmd == "x" || cmd.starts_with("x/") => self.examine(&cmd[1..], self.parse_addr(arg(0)?)?, false)?,
+
+            "translate" | "tr" => {
+                let addr = self.parse_addr(arg(0)?)?;
+                match self.cpu.translate(addr, 0) {
+                    Ok(paddr) => println!("{:#010x} -> {:#010x}", addr, paddr),
+                    Err(e) => println!("{:#010x}: {}", addr, fault_name(&e)),
+                }
+            }
+
+            "info" | "i" => self.info(arg(0)?)?,
+
+            "trace" => self.trace(args)?,
+
+            "profile" => self.profile(args)?,
+
+            "coverage" => self.coverage(args)?,
+
+            "gdb" => self.gdb(arg(0)?).map_err(|e| format!("gdb session failed: {}", e))?,
+
+            "help" | "h" => println!("{}", HELP),
+            "quit" | "q" => return Ok(false),
+
+            _ => return Err(format!("unknown command `{}`, try `help`", cmd)),
+        }
+
+        Ok(true)
+    }
+}
+
+fn main() {
+    let mut debugger = Debugger::new();
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(path) = args.get(1) {
+        let base = match args.get(2).map(|b| parse_num(b)).transpose() {
+            Ok(base) => base.unwrap_or(0),
+            Err(e) => {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        };
+        if let Err(e) = debugger.load(path, base, 0) {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let stdin = io::stdin();
+    let mut last = String::new();
+    loop {
+        print!("(cpuwu) ");
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => (),
+        }
+
+        // An empty line repeats the last command
+        let line = if line.trim().is_empty() { last.clone() } else { line.trim().to_string() };
+        match debugger.command(&line) {
+            Ok(true) => (),
+            Ok(false) => break,
+            Err(e) => println!("error: {}", e),
+        }
+        last = line;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpuwu::{Address, Unbacked};
+
+    // A debugger with the cpu at the start of program
+    fn debugger(cpu: Cpu<SimpleAddress>, program: &[u8]) -> Debugger {
+        let mut debugger = Debugger::new();
+        debugger.cpu = cpu;
+        for (i, &byte) in program.iter().enumerate() {
+            debugger.cpu.addressing_mut().write(i as u32, byte).unwrap();
+        }
+        debugger
+    }
+
+    #[test]
+    fn debugger_examine_keeps_blocks() {
+        // loop: stc; bc loop
+        let mut debugger = debugger(Cpu::new(SimpleAddress::default()), &[0x11, 0x02, 0x00, 0, 0, 0]);
+        debugger.cpu.set_block_translation(true);
+        debugger.cpu.run(10);
+        debugger.command("x/2i 0").unwrap();
+        debugger.command("xp/4b 0").unwrap();
+        debugger.command("x 0").unwrap();
+        debugger.command("xp 0").unwrap();
+        debugger.cpu.run(10);
+        assert_eq!(debugger.cpu.block_stats().map(|(translated, _)| translated), Some(1));
+    }
+
+    #[test]
+    fn debugger_unknown_commands() {
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.command("xyzzy 0"), Err("unknown command `xyzzy`, try `help`".to_string()));
+        assert_eq!(debugger.command("xpq 0"), Err("unknown command `xpq`, try `help`".to_string()));
+    }
+
+    #[test]
+    fn debugger_misaligned_fault() {
+        // lw x0, [0x2001]
+        let mut debugger = debugger(Cpu::new(SimpleAddress::default()), &[0x60, 0x01, 0x20, 0, 0]);
+        debugger.command("set g 1").unwrap();
+        let reason = debugger.cpu.run(1);
+        assert_eq!(debugger.fault_message(reason), "fault 0x3 (misaligned access at 0x00002001) at 0x00000000");
+    }
+
+    #[test]
+    fn debugger_bus_error() {
+        // lw x0, [0x2000] past the end of memory
+        let cpu = Cpu::new(SimpleAddress::new(0x1000, Unbacked::BusError));
+        let mut debugger = debugger(cpu, &[0x60, 0x00, 0x20, 0, 0]);
+        let reason = debugger.cpu.run(1);
+        assert_eq!(debugger.fault_message(reason), "fault 0x4 (bus error at physical 0x00002000) at 0x00000000");
+    }
+}
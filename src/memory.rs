@@ -0,0 +1,372 @@
+// Sparse memory and ROM
+//
+// `SparseAddress` can back the whole 32 bit physical address space without allocating it up
+// front. Memory is split into pages that are allocated the first time a nonzero byte is written to
+// them, and read as zeros until then. Pages are found through a two level table indexed by the
+// top ten bits and the next ten bits of the address.
+//
+// `Rom` maps a read-only image over another backend, and makes the cpu start executing at its
+// first byte after a reset.
+
+use crate::{Address, BusError, Unbacked};
+
+pub const SPARSE_PAGE_SIZE: u32 = 0x1000;
+
+const TABLE_ENTRIES: usize = 1024;
+
+type Page = Box<[u8; SPARSE_PAGE_SIZE as usize]>;
+
+pub struct SparseAddress {
+    tables: Vec<Option<Vec<Option<Page>>>>,
+    size: u64,
+    unbacked: Unbacked,
+    allocated: usize,
+}
+
+fn split(addr: u32) -> (usize, usize, usize) {
+    ((addr >> 22) as usize, (addr >> 12) as usize % TABLE_ENTRIES, (addr % SPARSE_PAGE_SIZE) as usize)
+}
+
+impl SparseAddress {
+    // Memory of size bytes from physical address 0, up to 4 GiB
+    pub fn new(size: u64, unbacked: Unbacked) -> SparseAddress {
+        SparseAddress { tables: vec![None; TABLE_ENTRIES], size: size.min(1 << 32), unbacked, allocated: 0 }
+    }
+
+    // Number of pages allocated
+    pub fn allocated(&self) -> usize {
+        self.allocated
+    }
+
+    fn backed(&self, addr: u32) -> bool {
+        (addr as u64) < self.size
+    }
+
+    fn page(&self, addr: u32) -> Option<&Page> {
+        let (table, page, _) = split(addr);
+        self.tables[table].as_ref()?[page].as_ref()
+    }
+
+    // The page containing addr, allocating it if needed
+    fn page_mut(&mut self, addr: u32) -> &mut Page {
+        let (table, page, _) = split(addr);
+        let page = &mut self.tables[table].get_or_insert_with(|| vec![None; TABLE_ENTRIES])[page];
+        if page.is_none() {
+            self.allocated += 1;
+        }
+        page.get_or_insert_with(|| Box::new([0; SPARSE_PAGE_SIZE as usize]))
+    }
+
+    // Allocated pages that are not all zeros, by address
+    pub fn pages(&self) -> impl Iterator<Item = (u32, &[u8])> {
+        self.tables.iter().enumerate().flat_map(|(i, table)| {
+            table.iter().flatten().enumerate().filter_map(move |(j, page)| {
+                let page = page.as_ref()?;
+                let addr = (i * TABLE_ENTRIES + j) as u32 * SPARSE_PAGE_SIZE;
+                Some((addr, &page[..])).filter(|(_, data)| data.iter().any(|&b| b != 0))
+            })
+        })
+    }
+
+    // Drops every page
+    pub fn clear(&mut self) {
+        self.tables.iter_mut().for_each(|t| *t = None);
+        self.allocated = 0;
+    }
+
+    fn unbacked(&self, addr: u32) -> Result<(), BusError> {
+        match self.unbacked {
+            Unbacked::ReadZero => Ok(()),
+            Unbacked::BusError => Err(BusError(addr)),
+        }
+    }
+
+    // Whether len bytes from addr are backed and in one page
+    fn within_page(&self, addr: u32, len: u32) -> bool {
+        addr % SPARSE_PAGE_SIZE + len <= SPARSE_PAGE_SIZE && self.backed(addr + (len - 1))
+    }
+}
+
+impl Default for SparseAddress {
+    fn default() -> SparseAddress {
+        SparseAddress::new(1 << 32, Unbacked::ReadZero)
+    }
+}
+
+impl Address for SparseAddress {
+    fn read(&mut self, addr: u32) -> Result<u8, BusError> {
+        match self.backed(addr) {
+            true => Ok(self.page(addr).map_or(0, |page| page[split(addr).2])),
+            false => self.unbacked(addr).map(|_| 0),
+        }
+    }
+
+    fn write(&mut self, addr: u32, data: u8) -> Result<(), BusError> {
+        if !self.backed(addr) {
+            return self.unbacked(addr);
+        }
+
+        // Writing zero to a page that was never allocated changes nothing
+        if data != 0 || self.page(addr).is_some() {
+            self.page_mut(addr)[split(addr).2] = data;
+        }
+        Ok(())
+    }
+
+    fn read_u32(&mut self, addr: u32) -> Result<u32, BusError> {
+        if !self.within_page(addr, 4) {
+            return Ok(self.read_u16(addr)? as u32 | (self.read_u16(addr.wrapping_add(2))? as u32) << 16);
+        }
+        match self.page(addr) {
+            Some(page) => {
+                let i = split(addr).2;
+                Ok(u32::from_le_bytes([page[i], page[i + 1], page[i + 2], page[i + 3]]))
+            }
+            None => Ok(0),
+        }
+    }
+
+    fn write_u32(&mut self, addr: u32, data: u32) -> Result<(), BusError> {
+        if !self.within_page(addr, 4) {
+            return self.write_slice(addr, &data.to_le_bytes());
+        }
+        if data != 0 || self.page(addr).is_some() {
+            let i = split(addr).2;
+            self.page_mut(addr)[i..i + 4].copy_from_slice(&data.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+// What a ROM does with writes to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RomWrites {
+    // Writes are ignored
+    Ignore,
+
+    // Writes raise a bus error exception
+    BusError,
+}
+
+// A read-only image at base, in front of the memory of another backend. The ROM holds the reset
+// vector, so a reset starts executing it at base.
+pub struct Rom<T> {
+    memory: T,
+    base: u32,
+    data: Vec<u8>,
+    writes: RomWrites,
+}
+
+impl<T> Rom<T>
+where
+    T: Address,
+{
+    // The image has to fit below 4 GiB
+    pub fn new(memory: T, base: u32, data: Vec<u8>, writes: RomWrites) -> Rom<T> {
+        assert!(base as u64 + data.len() as u64 <= 1 << 32, "ROM does not fit in the address space");
+        Rom { memory, base, data, writes }
+    }
+
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // The backend behind the ROM, which also has the memory the ROM covers
+    pub fn memory(&self) -> &T {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut T {
+        &mut self.memory
+    }
+
+    // Offset of addr into the ROM, if it is in it
+    fn offset(&self, addr: u32) -> Option<usize> {
+        Some(addr.wrapping_sub(self.base) as usize).filter(|&offset| offset < self.data.len())
+    }
+
+    // Whether none of the len bytes from addr are in the ROM
+    fn outside(&self, addr: u32, len: u32) -> bool {
+        let end = addr as u64 + len as u64;
+        end <= self.base as u64 || addr as u64 >= self.base as u64 + self.data.len() as u64
+    }
+}
+
+impl<T> Address for Rom<T>
+where
+    T: Address,
+{
+    fn read(&mut self, addr: u32) -> Result<u8, BusError> {
+        match self.offset(addr) {
+            Some(offset) => Ok(self.data[offset]),
+            None => self.memory.read(addr),
+        }
+    }
+
+    fn write(&mut self, addr: u32, data: u8) -> Result<(), BusError> {
+        match (self.offset(addr), self.writes) {
+            (None, _) => self.memory.write(addr, data),
+            (Some(_), RomWrites::Ignore) => Ok(()),
+            (Some(_), RomWrites::BusError) => Err(BusError(addr)),
+        }
+    }
+
+    fn read_u32(&mut self, addr: u32) -> Result<u32, BusError> {
+        if self.outside(addr, 4) {
+            return self.memory.read_u32(addr);
+        }
+        Ok(self.read_u16(addr)? as u32 | (self.read_u16(addr.wrapping_add(2))? as u32) << 16)
+    }
+
+    fn write_u32(&mut self, addr: u32, data: u32) -> Result<(), BusError> {
+        if self.outside(addr, 4) {
+            return self.memory.write_u32(addr, data);
+        }
+        self.write_slice(addr, &data.to_le_bytes())
+    }
+
+    fn size(&self) -> u64 {
+        self.memory.size().max(self.base as u64 + self.data.len() as u64)
+    }
+
+    fn is_device(&self, addr: u32) -> bool {
+        self.offset(addr).is_none() && self.memory.is_device(addr)
+    }
+
+    fn next_event(&self) -> Option<u64> {
+        self.memory.next_event()
+    }
+
+    fn run_event(&mut self, now: u64) -> Option<u8> {
+        self.memory.run_event(now)
+    }
+
+    fn reset_vector(&self) -> u32 {
+        self.base
+    }
+
+    fn reset(&mut self) {
+        self.memory.reset();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flags::Flag;
+    use crate::{Cpu, InvalidMemoryAccess, SimpleAddress, R_PC};
+
+    #[test]
+    fn sparse_pages() {
+        let mut memory = SparseAddress::default();
+        assert_eq!(memory.read(0xffffffff), Ok(0));
+        memory.write(0x1234, 0).unwrap();
+        assert_eq!(memory.allocated(), 0);
+
+        memory.write(0xfffffffe, 7).unwrap();
+        memory.write_u32(0x80000ffe, 0x11223344).unwrap();
+        memory.write_u32(0x1000, 0xaabbccdd).unwrap();
+        assert_eq!(memory.read(0xfffffffe), Ok(7));
+        assert_eq!(memory.read_u32(0x80000ffe), Ok(0x11223344));
+        assert_eq!(memory.read_u16(0x80001000), Ok(0x1122));
+        assert_eq!(memory.read_u32(0x1000), Ok(0xaabbccdd));
+        assert_eq!(memory.allocated(), 4);
+
+        let pages: Vec<u32> = memory.pages().map(|(addr, _)| addr).collect();
+        assert_eq!(pages, [0x1000, 0x80000000, 0x80001000, 0xfffff000]);
+        memory.clear();
+        assert_eq!(memory.read_u32(0x1000), Ok(0));
+    }
+
+    #[test]
+    fn sparse_unbacked() {
+        // Past the end reads zero and ignores writes
+        let mut memory = SparseAddress::new(0x2000, Unbacked::ReadZero);
+        memory.write_u32(0x1ffe, 0x11223344).unwrap();
+        assert_eq!(memory.read_u32(0x1ffe), Ok(0x3344));
+
+        // Or fails at the first byte past the end, after writing the ones before it
+        let mut memory = SparseAddress::new(0x2000, Unbacked::BusError);
+        assert_eq!(memory.write_u32(0x1ffe, 0x11223344), Err(BusError(0x2000)));
+        assert_eq!(memory.read_u16(0x1ffe), Ok(0x3344));
+        assert_eq!(memory.read_u32(0x1ffe), Err(BusError(0x2000)));
+
+        // Which the cpu raises as a bus error
+        // lw x0, [0x1ffc]; lw x0, [0x1ffe]
+        let mut cpu = Cpu::new(SparseAddress::new(0x2000, Unbacked::BusError));
+        cpu.addressing.write_slice(0, &[0x60, 0xfc, 0x1f, 0, 0, 0x60, 0xfe, 0x1f, 0, 0]).unwrap();
+        cpu.step();
+        assert!(cpu.interrupt_queue.is_empty());
+        assert_eq!(cpu.read_u32(0x1ffe), Err(InvalidMemoryAccess::BusError(0x2000)));
+        cpu.step();
+        assert_eq!(cpu.interrupt_queue.back(), Some(&0x80000004));
+    }
+
+    #[test]
+    fn rom_writes() {
+        let mut rom = Rom::new(SimpleAddress::default(), 0x100, vec![1, 2, 3, 4], RomWrites::Ignore);
+        rom.write_u32(0xfe, 0xaabbccdd).unwrap();
+        assert_eq!(rom.read_u32(0xfe), Ok(0x0201ccdd));
+        assert_eq!(rom.memory_mut().read_u32(0x100), Ok(0));
+        assert_eq!(rom.read_u32(0x104), Ok(0));
+
+        // The bytes below the ROM are still written
+        let mut rom = Rom::new(SimpleAddress::default(), 0x100, vec![1, 2, 3, 4], RomWrites::BusError);
+        assert_eq!(rom.write_u32(0xfe, 0x11223344), Err(BusError(0x100)));
+        assert_eq!(rom.read_u32(0xfe), Ok(0x02013344));
+
+        // sw x0, [0x102]
+        let mut cpu = Cpu::new(rom);
+        assert_eq!(cpu.pc(), 0x100);
+        cpu.xs[R_PC] = 0x20;
+        cpu.addressing.memory_mut().write_slice(0x20, &[0xc0, 0x02, 0x01, 0, 0]).unwrap();
+        cpu.step();
+        assert_eq!(cpu.interrupt_queue.back(), Some(&0x80000004));
+        assert_eq!(cpu.addressing.read_u32(0x100), Ok(0x04030201));
+    }
+
+    #[test]
+    fn rom_boot() {
+        // Maps virtual page 0 to 0x10000 and the ROM's page to itself, enables paging and jumps to
+        // the kernel at virtual address 0
+        #[rustfmt::skip]
+        let boot = vec![
+            0x40, 0x00, 0x00, 0x03, 0x00, // li x0, 0x30000
+            0xc0, 0x00, 0x00, 0x02, 0x00, // sw x0, [0x20000]
+            0xc0, 0x0f, 0x00, 0x02, 0x00, // sw x0, [0x2000f]
+            0x40, 0x00, 0x00, 0x01, 0xf0, // li x0, 0xf0010000
+            0xc0, 0x00, 0x00, 0x03, 0x00, // sw x0, [0x30000]
+            0x40, 0x00, 0x00, 0xff, 0xff, // li x0, 0xffff0000
+            0xc0, 0xff, 0x00, 0x03, 0x00, // sw x0, [0x300ff]
+            0x40, 0x00, 0x00, 0x02, 0x00, // li x0, 0x20000
+            0x9a, 0x01,                   // wsr memmap, x0
+            0x13,                         // paging on
+            0x4d, 0x00, 0x00, 0x00, 0x00, // jmp 0
+        ];
+        let mut cpu = Cpu::new(Rom::new(SimpleAddress::default(), 0x0fff0000, boot, RomWrites::BusError));
+
+        // li x1, 0x1234
+        cpu.addressing.memory_mut().write_slice(0x10000, &[0x41, 0x34, 0x12, 0, 0]).unwrap();
+        for _ in 0..2 {
+            assert_eq!(cpu.pc(), 0x0fff0000);
+            for _ in 0..12 {
+                cpu.step();
+            }
+            assert!(cpu.interrupt_queue.is_empty());
+            assert_eq!((cpu.pc(), cpu.xs[1]), (5, 0x1234));
+            assert!(cpu.get_flag(Flag::MemmapEnable));
+
+            // Resetting keeps memory, so the kernel boots again
+            cpu.reset();
+            assert_eq!((cpu.flags(), cpu.memmap, cpu.xs[1], cpu.cycles()), (0, 0, 0, 0));
+        }
+    }
+}
@@ -0,0 +1,544 @@
+// Object and executable file format
+//
+// Layout (all integers little endian, strings are a u16 length followed by utf-8 bytes):
+//   magic "CPUWUOBJ", u16 version, u8 kind, u8 has entry, u32 entry
+//   u32 section count, then per section:
+//     string name, u8 kind, u8 permissions, u32 address, u32 alignment, u32 size,
+//     size bytes of data unless the section is bss
+//   u32 symbol count, then per symbol:
+//     string name, u32 section (0xffffffff if undefined), u32 value, u8 global
+//   u32 relocation count, then per relocation:
+//     u32 section, u32 offset, u32 symbol, i32 addend, u8 kind
+//   u32 source file count, then a string per file name
+//   u32 line entry count, then per entry:
+//     u32 section, u32 offset, u32 file, u32 line
+//
+// Version 1 files end after the relocations and have no line info.
+//
+// Section permissions use the same READ, WRITE and EXEC bits as page table entries.
+
+use std::io::{self, Read, Write};
+
+use crate::loader::{Image, Segment};
+use crate::snapshot::{read_u16, read_u32, read_u8};
+use crate::{EXEC, READ, WRITE};
+
+const MAGIC: &[u8; 8] = b"CPUWUOBJ";
+pub const VERSION: u16 = 2;
+
+const UNDEFINED: u32 = 0xffffffff;
+
+// Page table entries mark used pages with this bit alongside the permission bits
+const PAGE_USED: u8 = 0b1000;
+
+#[derive(Debug)]
+pub enum ObjectError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Invalid(String),
+}
+
+impl std::fmt::Display for ObjectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            ObjectError::Io(e) => write!(f, "{}", e),
+            ObjectError::BadMagic => write!(f, "not a cpuwu object file"),
+            ObjectError::UnsupportedVersion(v) => write!(f, "unsupported object file version {}", v),
+            ObjectError::Invalid(e) => write!(f, "invalid object file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ObjectError {}
+
+impl From<io::Error> for ObjectError {
+    fn from(e: io::Error) -> ObjectError {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            ObjectError::Invalid("unexpected end of file".to_string())
+        } else {
+            ObjectError::Io(e)
+        }
+    }
+}
+
+fn invalid<T>(message: String) -> Result<T, ObjectError> {
+    Err(ObjectError::Invalid(message))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+    Relocatable,
+    Executable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SectionKind {
+    Text,
+    Data,
+
+    // Zero initialised, so no data is stored
+    Bss,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub name: String,
+    pub kind: SectionKind,
+    pub permissions: u8,
+    pub addr: u32,
+    pub align: u32,
+    pub size: u32,
+
+    // Empty for bss sections
+    pub data: Vec<u8>,
+}
+
+impl Section {
+    pub fn new(name: &str, kind: SectionKind, data: Vec<u8>) -> Section {
+        let permissions = match kind {
+            SectionKind::Text => READ | EXEC,
+            SectionKind::Data | SectionKind::Bss => READ | WRITE,
+        };
+        Section {
+            name: name.to_string(),
+            kind,
+            permissions,
+            addr: 0,
+            align: 1,
+            size: data.len() as u32,
+            data,
+        }
+    }
+
+    pub fn bss(name: &str, size: u32) -> Section {
+        Section { size, ..Section::new(name, SectionKind::Bss, vec![]) }
+    }
+
+    // Top four bits of a second level page table entry mapping this section
+    pub fn page_bits(&self) -> u32 {
+        ((PAGE_USED | self.permissions) as u32) << 28
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+
+    // None if the symbol is defined in another object
+    pub section: Option<usize>,
+
+    // Offset from the start of the section
+    pub value: u32,
+    pub global: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationKind {
+    // 32 bit little endian absolute address, as used by `call`, branches, loads and stores
+    Abs32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Relocation {
+    pub section: usize,
+    pub offset: u32,
+    pub symbol: usize,
+    pub addend: i32,
+    pub kind: RelocationKind,
+}
+
+// Debug line info: the code at offset in section, up to the next entry for the section, was
+// assembled from a line (counting from 1) of a source file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineEntry {
+    pub section: usize,
+    pub offset: u32,
+    pub file: usize,
+    pub line: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Object {
+    pub kind: ObjectKind,
+    pub entry: Option<u32>,
+    pub sections: Vec<Section>,
+    pub symbols: Vec<Symbol>,
+    pub relocations: Vec<Relocation>,
+
+    // Source file names referred to by line entries
+    pub files: Vec<String>,
+    pub lines: Vec<LineEntry>,
+}
+
+fn write_string<W: Write>(w: &mut W, s: &str) -> io::Result<()> {
+    if s.len() > u16::MAX as usize {
+        let message = format!("name of {} bytes is too long", s.len());
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+    }
+    w.write_all(&(s.len() as u16).to_le_bytes())?;
+    w.write_all(s.as_bytes())
+}
+
+fn read_string(r: &mut dyn Read) -> Result<String, ObjectError> {
+    let len = read_u16(r)? as usize;
+    let mut buf = vec![0; len];
+    r.read_exact(&mut buf)?;
+    String::from_utf8(buf).or_else(|_| invalid("name is not valid utf-8".to_string()))
+}
+
+impl Default for Object {
+    fn default() -> Object {
+        Object {
+            kind: ObjectKind::Relocatable,
+            entry: None,
+            sections: vec![],
+            symbols: vec![],
+            relocations: vec![],
+            files: vec![],
+            lines: vec![],
+        }
+    }
+}
+
+impl Object {
+    pub fn symbol(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|s| s.name == name)
+    }
+
+    // Address of a defined symbol, taking the address of its section into account
+    pub fn symbol_address(&self, symbol: &Symbol) -> Option<u32> {
+        let section = self.sections.get(symbol.section?)?;
+        Some(section.addr.wrapping_add(symbol.value))
+    }
+
+    pub fn write<W: Write>(&self, mut w: W) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&[(self.kind == ObjectKind::Executable) as u8, self.entry.is_some() as u8])?;
+        w.write_all(&self.entry.unwrap_or(0).to_le_bytes())?;
+
+        w.write_all(&(self.sections.len() as u32).to_le_bytes())?;
+        for s in self.sections.iter() {
+            write_string(&mut w, &s.name)?;
+            let kind = match s.kind {
+                SectionKind::Text => 0,
+                SectionKind::Data => 1,
+                SectionKind::Bss => 2,
+            };
+            w.write_all(&[kind, s.permissions])?;
+            w.write_all(&s.addr.to_le_bytes())?;
+            w.write_all(&s.align.to_le_bytes())?;
+            w.write_all(&s.size.to_le_bytes())?;
+            if s.kind != SectionKind::Bss {
+                w.write_all(&s.data)?;
+            }
+        }
+
+        w.write_all(&(self.symbols.len() as u32).to_le_bytes())?;
+        for s in self.symbols.iter() {
+            write_string(&mut w, &s.name)?;
+            w.write_all(&s.section.map_or(UNDEFINED, |s| s as u32).to_le_bytes())?;
+            w.write_all(&s.value.to_le_bytes())?;
+            w.write_all(&[s.global as u8])?;
+        }
+
+        w.write_all(&(self.relocations.len() as u32).to_le_bytes())?;
+        for r in self.relocations.iter() {
+            w.write_all(&(r.section as u32).to_le_bytes())?;
+            w.write_all(&r.offset.to_le_bytes())?;
+            w.write_all(&(r.symbol as u32).to_le_bytes())?;
+            w.write_all(&r.addend.to_le_bytes())?;
+            let kind = match r.kind {
+                RelocationKind::Abs32 => 0,
+            };
+            w.write_all(&[kind])?;
+        }
+
+        w.write_all(&(self.files.len() as u32).to_le_bytes())?;
+        for f in self.files.iter() {
+            write_string(&mut w, f)?;
+        }
+
+        w.write_all(&(self.lines.len() as u32).to_le_bytes())?;
+        for l in self.lines.iter() {
+            w.write_all(&(l.section as u32).to_le_bytes())?;
+            w.write_all(&l.offset.to_le_bytes())?;
+            w.write_all(&(l.file as u32).to_le_bytes())?;
+            w.write_all(&l.line.to_le_bytes())?;
+        }
+        w.flush()
+    }
+
+    pub fn read<R: Read>(mut r: R) -> Result<Object, ObjectError> {
+        let r = &mut r as &mut dyn Read;
+        let mut magic = [0; 8];
+        if r.read_exact(&mut magic).is_err() || &magic != MAGIC {
+            return Err(ObjectError::BadMagic);
+        }
+        let version = read_u16(r)?;
+        if version == 0 || version > VERSION {
+            return Err(ObjectError::UnsupportedVersion(version));
+        }
+
+        let kind = match read_u8(r)? {
+            0 => ObjectKind::Relocatable,
+            1 => ObjectKind::Executable,
+            k => return invalid(format!("unknown object kind {}", k)),
+        };
+        let has_entry = read_u8(r)? != 0;
+        let entry = read_u32(r)?;
+        let mut object = Object {
+            kind,
+            entry: if has_entry { Some(entry) } else { None },
+            ..Object::default()
+        };
+
+        for _ in 0..read_u32(r)? {
+            let name = read_string(r)?;
+            let kind = match read_u8(r)? {
+                0 => SectionKind::Text,
+                1 => SectionKind::Data,
+                2 => SectionKind::Bss,
+                k => return invalid(format!("section `{}` has unknown kind {}", name, k)),
+            };
+            let permissions = read_u8(r)?;
+            if permissions & !(READ | WRITE | EXEC) != 0 {
+                return invalid(format!("section `{}` has invalid permissions {:#x}", name, permissions));
+            }
+            let addr = read_u32(r)?;
+            let align = read_u32(r)?;
+            if !align.is_power_of_two() {
+                return invalid(format!("section `{}` alignment {} is not a power of two", name, align));
+            }
+            let size = read_u32(r)?;
+            if addr as u64 + size as u64 > 1 << 32 {
+                return invalid(format!("section `{}` does not fit in the address space", name));
+            }
+            let mut data = vec![];
+            if kind != SectionKind::Bss {
+                r.take(size as u64).read_to_end(&mut data)?;
+                if data.len() != size as usize {
+                    return invalid(format!("section `{}` is truncated", name));
+                }
+            }
+            object.sections.push(Section { name, kind, permissions, addr, align, size, data });
+        }
+
+        for _ in 0..read_u32(r)? {
+            let name = read_string(r)?;
+            let section = match read_u32(r)? {
+                UNDEFINED => None,
+                s if (s as usize) < object.sections.len() => Some(s as usize),
+                s => return invalid(format!("symbol `{}` refers to missing section {}", name, s)),
+            };
+            let value = read_u32(r)?;
+            if let Some(s) = section.map(|s| &object.sections[s]).filter(|s| value > s.size) {
+                return invalid(format!("symbol `{}` is outside of section `{}`", name, s.name));
+            }
+            let global = read_u8(r)? != 0;
+            object.symbols.push(Symbol { name, section, value, global });
+        }
+
+        for _ in 0..read_u32(r)? {
+            let section = read_u32(r)? as usize;
+            let offset = read_u32(r)?;
+            let symbol = read_u32(r)? as usize;
+            let addend = read_u32(r)? as i32;
+            let kind = match read_u8(r)? {
+                0 => RelocationKind::Abs32,
+                k => return invalid(format!("unknown relocation kind {}", k)),
+            };
+
+            match object.sections.get(section) {
+                Some(s) if s.kind == SectionKind::Bss => {
+                    return invalid(format!("relocation in bss section `{}`", s.name))
+                }
+                Some(s) if offset as u64 + 4 > s.size as u64 => {
+                    return invalid(format!("relocation at {:#x} is outside of section `{}`", offset, s.name))
+                }
+                Some(_) => (),
+                None => return invalid(format!("relocation refers to missing section {}", section)),
+            }
+            if symbol >= object.symbols.len() {
+                return invalid(format!("relocation refers to missing symbol {}", symbol));
+            }
+
+            object.relocations.push(Relocation { section, offset, symbol, addend, kind });
+        }
+
+        if version >= 2 {
+            for _ in 0..read_u32(r)? {
+                object.files.push(read_string(r)?);
+            }
+
+            for _ in 0..read_u32(r)? {
+                let section = read_u32(r)? as usize;
+                let offset = read_u32(r)?;
+                let file = read_u32(r)? as usize;
+                let line = read_u32(r)?;
+                match object.sections.get(section) {
+                    Some(s) if offset >= s.size => {
+                        return invalid(format!("line entry at {:#x} is outside of section `{}`", offset, s.name))
+                    }
+                    Some(_) => (),
+                    None => return invalid(format!("line entry refers to missing section {}", section)),
+                }
+                if file >= object.files.len() {
+                    return invalid(format!("line entry refers to missing file {}", file));
+                }
+                object.lines.push(LineEntry { section, offset, file, line });
+            }
+        }
+
+        let mut rest = [0];
+        if r.read(&mut rest)? != 0 {
+            return invalid("trailing data".to_string());
+        }
+        Ok(object)
+    }
+
+    // Converts an executable into a loadable image, zero filling bss sections
+    pub fn to_image(&self) -> Result<Image, ObjectError> {
+        if self.kind != ObjectKind::Executable {
+            return invalid("only executables can be loaded".to_string());
+        }
+
+        if let Some(s) = self.sections.iter().find(|s| s.addr as u64 + s.size as u64 > 1 << 32) {
+            return invalid(format!("section `{}` does not fit in the address space", s.name));
+        }
+
+        let segments = self
+            .sections
+            .iter()
+            .filter(|s| s.size != 0)
+            .map(|s| match s.kind {
+                SectionKind::Bss => Segment { addr: s.addr, data: vec![], zeros: s.size },
+                _ => Segment { addr: s.addr, data: s.data.clone(), zeros: 0 },
+            })
+            .collect();
+        Ok(Image { segments, entry: self.entry })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object() -> Object {
+        // call puts; ret
+        let mut text = Section::new(".text", SectionKind::Text, vec![0x18, 0, 0, 0, 0, 0x19]);
+        text.align = 4;
+        Object {
+            sections: vec![text, Section::new(".data", SectionKind::Data, b"uwu\0".to_vec()), Section::bss(".bss", 64)],
+            symbols: vec![
+                Symbol { name: "main".to_string(), section: Some(0), value: 0, global: true },
+                Symbol { name: "puts".to_string(), section: None, value: 0, global: true },
+                Symbol { name: "msg".to_string(), section: Some(1), value: 0, global: false },
+            ],
+            relocations: vec![Relocation { section: 0, offset: 1, symbol: 1, addend: 0, kind: RelocationKind::Abs32 }],
+            files: vec!["main.s".to_string()],
+            lines: vec![
+                LineEntry { section: 0, offset: 0, file: 0, line: 3 },
+                LineEntry { section: 0, offset: 5, file: 0, line: 4 },
+            ],
+            ..Object::default()
+        }
+    }
+
+    #[test]
+    fn object_round_trip() {
+        let object = object();
+        let mut data = vec![];
+        object.write(&mut data).unwrap();
+        assert_eq!(Object::read(&data[..]).unwrap(), object);
+
+        assert_eq!(object.sections[0].page_bits(), 0xd0000000);
+        assert_eq!(object.sections[2].page_bits(), 0xe0000000);
+        assert!(object.to_image().is_err());
+
+        // Names too long for their length prefix are refused rather than truncated
+        let mut long = object.clone();
+        long.symbols[0].name = "x".repeat(0x10000);
+        let err = long.write(&mut vec![]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        long.symbols[0].name.pop();
+        let mut data = vec![];
+        long.write(&mut data).unwrap();
+        assert_eq!(Object::read(&data[..]).unwrap(), long);
+    }
+
+    #[test]
+    fn object_validation() {
+        let mut data = vec![];
+        object().write(&mut data).unwrap();
+
+        assert!(matches!(Object::read(&b"CPUWUSNP"[..]), Err(ObjectError::BadMagic)));
+        assert!(matches!(Object::read(&data[..data.len() - 1]), Err(ObjectError::Invalid(_))));
+
+        let mut trailing = data.clone();
+        trailing.push(0);
+        assert!(matches!(Object::read(&trailing[..]), Err(ObjectError::Invalid(_))));
+
+        let mut bad = object();
+        bad.relocations[0].offset = 3;
+        let mut data = vec![];
+        bad.write(&mut data).unwrap();
+        assert!(matches!(Object::read(&data[..]), Err(ObjectError::Invalid(_))));
+
+        // Sections must fit in the address space, which also bounds what a bss section can allocate
+        let mut bad = object();
+        bad.sections[2].addr = 0xffffffc1;
+        let mut data = vec![];
+        bad.write(&mut data).unwrap();
+        assert!(matches!(Object::read(&data[..]), Err(ObjectError::Invalid(_))));
+        bad.sections[2].addr = 0xffffffc0;
+        bad.kind = ObjectKind::Executable;
+        assert!(bad.to_image().is_ok());
+        bad.sections[2].size = u32::MAX;
+        assert!(bad.to_image().is_err());
+
+        // Symbols can be at the end of their section but not past it
+        let mut bad = object();
+        bad.symbols[2].value = 4;
+        let mut data = vec![];
+        bad.write(&mut data).unwrap();
+        assert!(Object::read(&data[..]).is_ok());
+        bad.symbols[2].value = 5;
+        let mut data = vec![];
+        bad.write(&mut data).unwrap();
+        assert!(matches!(Object::read(&data[..]), Err(ObjectError::Invalid(_))));
+
+        let mut bad = object();
+        bad.lines[1].file = 1;
+        let mut data = vec![];
+        bad.write(&mut data).unwrap();
+        assert!(matches!(Object::read(&data[..]), Err(ObjectError::Invalid(_))));
+
+        // Version 1 files have no line info
+        let mut old = object();
+        old.files.clear();
+        old.lines.clear();
+        let mut data = vec![];
+        old.write(&mut data).unwrap();
+        data[8] = 1;
+        data.truncate(data.len() - 8);
+        assert_eq!(Object::read(&data[..]).unwrap(), old);
+    }
+
+    #[test]
+    fn object_to_image() {
+        let mut object = object();
+        object.kind = ObjectKind::Executable;
+        object.entry = Some(0x1000);
+        object.sections[0].addr = 0x1000;
+        object.sections[1].addr = 0x2000;
+        object.sections[2].addr = 0x3000;
+
+        let image = object.to_image().unwrap();
+        assert_eq!(image.entry, Some(0x1000));
+        assert_eq!(image.segments[1], Segment { addr: 0x2000, data: b"uwu\0".to_vec(), zeros: 0 });
+        assert_eq!(image.segments[2], Segment { addr: 0x3000, data: vec![], zeros: 64 });
+        assert_eq!(object.symbol_address(object.symbol("msg").unwrap()), Some(0x2000));
+    }
+}
@@ -0,0 +1,198 @@
+// Guest visible performance counters
+//
+// The counters are read with `rsr` through special registers 5 to 10, which give the low half of
+// a counter and latch its high half into `perfh` (11), so that a counter can be read consistently
+// with two instructions. Writing a counter with `wsr` sets it to the written value, zero extended.
+//
+// `perfctl` (12) controls the counters. Bit 0 allows the user ring to read them, otherwise doing
+// so is an unprivileged opcode. Writing bit 1 resets every counter.
+
+use crate::{Address, Cpu};
+
+pub const COUNTERS: usize = 6;
+
+pub const SELECTOR_BASE: usize = 5;
+pub const SELECTOR_HIGH: usize = 11;
+pub const SELECTOR_CONTROL: usize = 12;
+
+pub const CONTROL_USER_READ: u32 = 1 << 0;
+pub const CONTROL_RESET: u32 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Counter {
+    Instructions,
+    Cycles,
+
+    // Translations that missed the TLB and walked the page table
+    PageWalks,
+    PageFaults,
+    Interrupts,
+    BranchesTaken,
+}
+
+impl Counter {
+    pub const ALL: [Counter; COUNTERS] = [
+        Counter::Instructions,
+        Counter::Cycles,
+        Counter::PageWalks,
+        Counter::PageFaults,
+        Counter::Interrupts,
+        Counter::BranchesTaken,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Counter::Instructions => "instret",
+            Counter::Cycles => "perfcyc",
+            Counter::PageWalks => "walks",
+            Counter::PageFaults => "faults",
+            Counter::Interrupts => "ints",
+            Counter::BranchesTaken => "branches",
+        }
+    }
+
+    // The special register the counter is read from
+    pub fn selector(self) -> usize {
+        SELECTOR_BASE + self as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Counters {
+    pub(crate) values: [u64; COUNTERS],
+    pub(crate) high: u32,
+    pub(crate) user_read: bool,
+}
+
+impl Counters {
+    pub fn get(&self, counter: Counter) -> u64 {
+        self.values[counter as usize]
+    }
+
+    pub fn user_read(&self) -> bool {
+        self.user_read
+    }
+
+    pub(crate) fn count(&mut self, counter: Counter, n: u64) {
+        let value = &mut self.values[counter as usize];
+        *value = value.wrapping_add(n);
+    }
+
+    // Reads a special register in the counter range, or returns None if p is not one of them
+    pub(crate) fn read(&mut self, p: usize) -> Option<u32> {
+        match p {
+            _ if (SELECTOR_BASE..SELECTOR_BASE + COUNTERS).contains(&p) => {
+                let value = self.values[p - SELECTOR_BASE];
+                self.high = (value >> 32) as u32;
+                Some(value as u32)
+            }
+            SELECTOR_HIGH => Some(self.high),
+            SELECTOR_CONTROL => Some(self.user_read as u32),
+            _ => None,
+        }
+    }
+
+    // Writes a special register in the counter range, returning false if p is not one of them
+    pub(crate) fn write(&mut self, p: usize, value: u32) -> bool {
+        match p {
+            _ if (SELECTOR_BASE..SELECTOR_BASE + COUNTERS).contains(&p) => {
+                self.values[p - SELECTOR_BASE] = value as u64;
+            }
+            SELECTOR_HIGH => (),
+            SELECTOR_CONTROL => {
+                self.user_read = value & CONTROL_USER_READ != 0;
+                if value & CONTROL_RESET != 0 {
+                    self.values = [0; COUNTERS];
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    pub(crate) fn is_selector(p: usize) -> bool {
+        (SELECTOR_BASE..=SELECTOR_CONTROL).contains(&p)
+    }
+}
+
+impl<T> Cpu<T>
+where
+    T: Address,
+{
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    pub fn reset_counters(&mut self) {
+        self.counters.values = [0; COUNTERS];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flags::Flag;
+    use crate::SimpleAddress;
+
+    fn cpu(program: &[u8]) -> Cpu<SimpleAddress> {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[..program.len()].copy_from_slice(program);
+        cpu
+    }
+
+    #[test]
+    fn perf_counting() {
+        // bnz 0x0a; nop; nop; clc; rsr x0, instret; rsr x1, perfh
+        let mut cpu = cpu(&[0x08, 0x0a, 0, 0, 0, 0x3f, 0x3f, 0x3f, 0x3f, 0x3f, 0x10, 0x9b, 0x50, 0x9b, 0xb1]);
+        cpu.run(4);
+        assert_eq!(cpu.xs[0], 2);
+        assert_eq!(cpu.xs[1], 0);
+        assert_eq!(cpu.counters().get(Counter::Instructions), 4);
+        assert_eq!(cpu.counters().get(Counter::BranchesTaken), 1);
+        assert_eq!(cpu.counters().get(Counter::Cycles), cpu.cycles());
+
+        // A fetch from an unmapped page walks the page table and faults
+        let mut cpu = self::cpu(&[]);
+        cpu.set_flag(Flag::MemmapEnable, true);
+        cpu.memmap = 0x8000;
+        cpu.step();
+        assert_eq!(cpu.counters().get(Counter::PageFaults), 1);
+        assert_eq!(cpu.counters().get(Counter::PageWalks), 1);
+        assert_eq!(cpu.counters().get(Counter::Instructions), 0);
+    }
+
+    #[test]
+    fn perf_high_half() {
+        // rsr x0, perfcyc; rsr x1, perfh
+        let mut cpu = cpu(&[0x9b, 0x60, 0x9b, 0xb1]);
+        cpu.counters.values[Counter::Cycles as usize] = 0x1_ffff_fff0;
+        cpu.run(2);
+        assert_eq!(cpu.xs[0], 0xffff_fff2);
+        assert_eq!(cpu.xs[1], 1);
+    }
+
+    #[test]
+    fn perf_control() {
+        // li x0, 1; wsr perfctl, x0; user; rsr x1, instret; li x0, 2; wsr perfctl, x0
+        let mut cpu = cpu(&[0x40, 1, 0, 0, 0, 0x9a, 0x0c, 0x17, 0x9b, 0x51, 0x40, 2, 0, 0, 0, 0x9a, 0x0c]);
+        cpu.run(4);
+        assert!(cpu.counters().user_read());
+        assert_eq!(cpu.xs[1], 3);
+
+        // The user ring cannot reset the counters
+        cpu.run(2);
+        assert_eq!(cpu.interrupt_queue.back(), Some(&0x80000002));
+        assert_eq!(cpu.counters().get(Counter::Instructions), 5);
+
+        // Without user access, reading a counter from the user ring faults
+        let mut cpu = self::cpu(&[0x17, 0x9b, 0x51]);
+        cpu.run(2);
+        assert_eq!(cpu.interrupt_queue.back(), Some(&0x80000002));
+        assert_eq!(cpu.xs[1], 0);
+
+        // Resetting from the system ring
+        let mut cpu = self::cpu(&[0x40, 2, 0, 0, 0, 0x9a, 0x0c]);
+        cpu.run(2);
+        assert_eq!(cpu.counters().get(Counter::Instructions), 1);
+    }
+}
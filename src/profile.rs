@@ -0,0 +1,272 @@
+// Sampling profiler
+//
+// While a profiler is attached, the cpu records the pc and the frame pointer backtrace after the
+// first step that brings the cycle counter past each multiple of the sampling interval. Samples
+// are aggregated per function using symbols when they are written out.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::disasm;
+use crate::symbols::Symbols;
+use crate::{Address, Cpu};
+
+// Annotated disassembly of a function spans at most this many bytes from its symbol, beyond which
+// only the sampled instructions are shown
+const MAX_ANNOTATED_SPAN: u32 = 0x1000;
+
+pub struct Profiler {
+    interval: u64,
+    next: u64,
+    total: u64,
+
+    // Sample counts by call stack, outermost frame first
+    stacks: HashMap<Vec<u32>, u64>,
+
+    // Sample counts by pc
+    hits: HashMap<u32, u64>,
+}
+
+fn function_name(symbols: &Symbols, pc: u32) -> String {
+    match symbols.find(pc) {
+        Some((name, _)) => name.to_string(),
+        None => format!("{:#010x}", pc),
+    }
+}
+
+impl Profiler {
+    pub fn new(interval: u64) -> Profiler {
+        Profiler {
+            interval: interval.max(1),
+            next: 0,
+            total: 0,
+            stacks: HashMap::new(),
+            hits: HashMap::new(),
+        }
+    }
+
+    pub fn samples(&self) -> u64 {
+        self.total
+    }
+
+    pub fn hits(&self, pc: u32) -> u64 {
+        self.hits.get(&pc).copied().unwrap_or(0)
+    }
+
+    pub fn clear(&mut self) {
+        self.total = 0;
+        self.stacks.clear();
+        self.hits.clear();
+    }
+
+    fn sample(&mut self, stack: Vec<u32>) {
+        self.total += 1;
+        *self.hits.entry(*stack.last().unwrap()).or_insert(0) += 1;
+        *self.stacks.entry(stack).or_insert(0) += 1;
+    }
+
+    // Sample counts by call stack of function names, outermost first
+    fn folded(&self, symbols: &Symbols) -> Vec<(String, u64)> {
+        let mut folded: HashMap<String, u64> = HashMap::new();
+        for (stack, count) in self.stacks.iter() {
+            let names: Vec<String> = stack.iter().map(|&pc| function_name(symbols, pc)).collect();
+            *folded.entry(names.join(";")).or_insert(0) += count;
+        }
+
+        let mut folded: Vec<(String, u64)> = folded.into_iter().collect();
+        folded.sort();
+        folded
+    }
+
+    // Returns (function, self samples, total samples) for every sampled function, hottest first.
+    // Total samples count each sample once per function even if the function recursed.
+    pub fn functions(&self, symbols: &Symbols) -> Vec<(String, u64, u64)> {
+        let mut functions: HashMap<String, (u64, u64)> = HashMap::new();
+        for (stack, &count) in self.stacks.iter() {
+            let names: Vec<String> = stack.iter().map(|&pc| function_name(symbols, pc)).collect();
+            for (i, name) in names.iter().enumerate() {
+                let entry = functions.entry(name.clone()).or_insert((0, 0));
+                if i == names.len() - 1 {
+                    entry.0 += count;
+                }
+                if !names[..i].contains(name) {
+                    entry.1 += count;
+                }
+            }
+        }
+
+        let mut functions: Vec<(String, u64, u64)> = functions.into_iter().map(|(n, (s, t))| (n, s, t)).collect();
+        functions.sort_by(|a, b| b.1.cmp(&a.1).then(b.2.cmp(&a.2)).then(a.0.cmp(&b.0)));
+        functions
+    }
+
+    // Writes one `outer;inner count` line per call stack, the folded format read by flamegraph
+    // tools
+    pub fn write_folded<W: Write>(&self, mut w: W, symbols: &Symbols) -> io::Result<()> {
+        for (stack, count) in self.folded(symbols) {
+            writeln!(w, "{} {}", stack, count)?;
+        }
+        w.flush()
+    }
+
+    // Writes a flat profile of self and total samples per function
+    pub fn write_report<W: Write>(&self, mut w: W, symbols: &Symbols) -> io::Result<()> {
+        let percent = |n: u64| 100.0 * n as f64 / self.total.max(1) as f64;
+        writeln!(w, "{} samples", self.total)?;
+        writeln!(w, "   self  self%   total total%  function")?;
+        for (name, own, total) in self.functions(symbols) {
+            writeln!(w, "{:>7} {:>5.1}% {:>7} {:>5.1}%  {}", own, percent(own), total, percent(total), name)?;
+        }
+        w.flush()
+    }
+
+    // Writes the disassembly of every sampled function with the number of samples at each
+    // instruction, fetching instruction bytes with fetch
+    pub fn write_annotated<W, F>(&self, mut w: W, symbols: &Symbols, mut fetch: F) -> io::Result<()>
+    where
+        W: Write,
+        F: FnMut(u32) -> Option<u8>,
+    {
+        // Group sampled pcs by the symbol they are in
+        let mut functions: HashMap<Option<u32>, Vec<u32>> = HashMap::new();
+        for &pc in self.hits.keys() {
+            let start = symbols.find(pc).map(|(_, offset)| pc - offset);
+            functions.entry(start).or_default().push(pc);
+        }
+        let mut functions: Vec<(Option<u32>, Vec<u32>)> = functions.into_iter().collect();
+        functions.sort_by_key(|(_, pcs)| std::cmp::Reverse(pcs.iter().map(|pc| self.hits[pc]).sum::<u64>()));
+
+        for (start, mut pcs) in functions {
+            pcs.sort();
+            let last = *pcs.last().unwrap();
+            match start {
+                Some(start) => writeln!(w, "{}:", function_name(symbols, start))?,
+                None => writeln!(w, "<unknown>:")?,
+            }
+
+            // Disassemble the whole function up to its last sampled instruction if it is small
+            // enough, otherwise only the sampled instructions
+            let linear = start.is_some_and(|start| last - start <= MAX_ANNOTATED_SPAN);
+            let mut addr = if linear { start.unwrap() } else { pcs[0] };
+            while addr <= last {
+                let (text, len) = match disasm::disassemble(addr, &mut fetch) {
+                    Some(i) => (i.annotate(symbols), i.len),
+                    None => ("<unreadable>".to_string(), 1),
+                };
+
+                let count = self.hits(addr);
+                if count == 0 {
+                    writeln!(w, "{:>15}  {:#010x}: {}", "", addr, text)?;
+                } else {
+                    let percent = 100.0 * count as f64 / self.total as f64;
+                    writeln!(w, "{:>7} {:>5.1}%  {:#010x}: {}", count, percent, addr, text)?;
+                }
+
+                addr = match linear {
+                    true => addr.saturating_add(len.max(1)),
+                    false => match pcs.iter().find(|&&pc| pc > addr) {
+                        Some(&pc) => pc,
+                        None => break,
+                    },
+                };
+                if addr == u32::MAX {
+                    break;
+                }
+            }
+            writeln!(w)?;
+        }
+        w.flush()
+    }
+}
+
+impl<T> Cpu<T>
+where
+    T: Address,
+{
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) -> Option<Profiler> {
+        std::mem::replace(&mut self.profiler, profiler)
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn profiler_mut(&mut self) -> Option<&mut Profiler> {
+        self.profiler.as_mut()
+    }
+
+    pub(crate) fn sample_profile(&mut self) {
+        match &self.profiler {
+            Some(profiler) if self.cycles >= profiler.next => (),
+            _ => return,
+        }
+
+        let mut stack: Vec<u32> = self.backtrace().frames.iter().map(|f| f.pc).collect();
+        stack.reverse();
+        let profiler = self.profiler.as_mut().unwrap();
+        profiler.sample(stack);
+        profiler.next = self.cycles + profiler.interval;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{SimpleAddress, R_BASE, R_SP};
+
+    // main: call f; bnz main
+    // f: nop; nop; ret
+    fn profiled(interval: u64, steps: u64) -> Cpu<SimpleAddress> {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[0x00..0x0a].copy_from_slice(&[0x18, 0x10, 0, 0, 0, 0x08, 0, 0, 0, 0]);
+        cpu.addressing.memory[0x10..0x13].copy_from_slice(&[0x3f, 0x3f, 0x19]);
+        cpu.xs[R_SP] = 0x8000;
+        cpu.xs[R_BASE] = 0x8000;
+        cpu.set_profiler(Some(Profiler::new(interval)));
+        cpu.run(steps);
+        cpu
+    }
+
+    fn symbols() -> Symbols {
+        Symbols::parse_map("0x00 main\n0x10 f\n").unwrap()
+    }
+
+    #[test]
+    fn profile_folded() {
+        // Each iteration is five steps, two of which end in main
+        let cpu = profiled(1, 50);
+        let profiler = cpu.profiler().unwrap();
+        assert_eq!(profiler.samples(), 50);
+
+        let mut folded = vec![];
+        profiler.write_folded(&mut folded, &symbols()).unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "main 20\nmain;f 30\n");
+
+        let functions = profiler.functions(&symbols());
+        assert_eq!(functions[0], ("f".to_string(), 30, 30));
+        assert_eq!(functions[1], ("main".to_string(), 20, 50));
+
+        // Without symbols every pc is its own function
+        assert_eq!(profiler.functions(&Symbols::new()).len(), 5);
+    }
+
+    #[test]
+    fn profile_interval() {
+        // An iteration takes 4 + 1 + 1 + 4 + 3 cycles
+        let cpu = profiled(13, 50);
+        assert_eq!(cpu.profiler().unwrap().samples(), 10);
+    }
+
+    #[test]
+    fn profile_annotated() {
+        let cpu = profiled(1, 50);
+        let profiler = cpu.profiler().unwrap();
+        let mut text = vec![];
+        profiler
+            .write_annotated(&mut text, &symbols(), |a| cpu.addressing.memory.get(a as usize).copied())
+            .unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.contains("     10  20.0%  0x00000011: nop 0x3f"));
+        assert!(text.contains("main:\n     10  20.0%  0x00000000: call 0x00000010 <f>\n"));
+    }
+}
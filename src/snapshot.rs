@@ -0,0 +1,473 @@
+// Machine snapshots
+//
+// A snapshot is the magic bytes, a version, the cpu registers, cycle counter, performance counters
+// and interrupt queue, a length prefixed blob written by the addressing backend, and an Adler-32
+// checksum of everything before it. All integers are little endian.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+
+use crate::flags::Flags;
+use crate::memory::{Rom, SparseAddress, SPARSE_PAGE_SIZE};
+use crate::perf::Counters;
+use crate::{float, timing, Address, Cpu, SimpleAddress};
+
+const MAGIC: &[u8; 8] = b"CPUWUSNP";
+pub const VERSION: u16 = 1;
+
+// Granularity of zero chunk elision in SimpleAddress snapshots, unrelated to the cpu's pages
+const SNAPSHOT_CHUNK: usize = 0x1000;
+const END_OF_PAGES: u32 = 0xffffffff;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    Invalid(String),
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            SnapshotError::Io(e) => write!(f, "{}", e),
+            SnapshotError::BadMagic => write!(f, "not a cpuwu snapshot"),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {}", v),
+            SnapshotError::ChecksumMismatch => write!(f, "snapshot checksum mismatch"),
+            SnapshotError::Invalid(e) => write!(f, "invalid snapshot: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> SnapshotError {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            SnapshotError::Invalid("unexpected end of snapshot".to_string())
+        } else {
+            SnapshotError::Io(e)
+        }
+    }
+}
+
+// Implemented by addressing backends and devices that can be saved in a snapshot
+pub trait Snapshot {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()>;
+
+    // Restores state written by save, given exactly the bytes it wrote. Implementations should
+    // check all of the data, including that none is left over with expect_end, before changing
+    // anything, and leave themselves unchanged if it is invalid.
+    fn restore(&mut self, r: &mut dyn Read) -> Result<(), SnapshotError>;
+}
+
+// Fails if r has any data left
+pub fn expect_end(r: &mut dyn Read) -> Result<(), SnapshotError> {
+    match r.read(&mut [0])? {
+        0 => Ok(()),
+        _ => Err(SnapshotError::Invalid("trailing device data".to_string())),
+    }
+}
+
+pub(crate) fn read_u8(r: &mut dyn Read) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+pub(crate) fn read_u16(r: &mut dyn Read) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    r.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+pub(crate) fn read_u32(r: &mut dyn Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+pub(crate) fn read_u64(r: &mut dyn Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    b << 16 | a
+}
+
+impl Snapshot for SimpleAddress {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&(self.memory.len() as u32).to_le_bytes())?;
+        for (i, chunk) in self.memory.chunks(SNAPSHOT_CHUNK).enumerate() {
+            if chunk.iter().any(|&b| b != 0) {
+                w.write_all(&(i as u32).to_le_bytes())?;
+                w.write_all(chunk)?;
+            }
+        }
+        w.write_all(&END_OF_PAGES.to_le_bytes())
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> Result<(), SnapshotError> {
+        let size = read_u32(r)? as usize;
+        if size != self.memory.len() {
+            return Err(SnapshotError::Invalid(format!(
+                "memory size {:#x} does not match {:#x}",
+                size,
+                self.memory.len()
+            )));
+        }
+
+        let mut memory = vec![0; size];
+        loop {
+            let chunk = read_u32(r)?;
+            if chunk == END_OF_PAGES {
+                break;
+            }
+
+            let start = chunk as usize * SNAPSHOT_CHUNK;
+            if start >= memory.len() {
+                return Err(SnapshotError::Invalid(format!("chunk {:#x} out of range", chunk)));
+            }
+            let end = (start + SNAPSHOT_CHUNK).min(memory.len());
+            r.read_exact(&mut memory[start..end])?;
+        }
+        expect_end(r)?;
+
+        self.memory = memory;
+        Ok(())
+    }
+}
+
+// The size, then the address and contents of every page with nonzero bytes
+impl Snapshot for SparseAddress {
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&self.size().to_le_bytes())?;
+        for (addr, page) in self.pages() {
+            w.write_all(&(addr / SPARSE_PAGE_SIZE).to_le_bytes())?;
+            w.write_all(page)?;
+        }
+        w.write_all(&END_OF_PAGES.to_le_bytes())
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> Result<(), SnapshotError> {
+        let size = read_u64(r)?;
+        if size != self.size() {
+            return Err(SnapshotError::Invalid(format!(
+                "memory size {:#x} does not match {:#x}",
+                size,
+                self.size()
+            )));
+        }
+
+        let mut pages = vec![];
+        loop {
+            let page = read_u32(r)?;
+            if page == END_OF_PAGES {
+                break;
+            }
+            if page as u64 * SPARSE_PAGE_SIZE as u64 >= size {
+                return Err(SnapshotError::Invalid(format!("page {:#x} out of range", page)));
+            }
+            let mut data = vec![0; SPARSE_PAGE_SIZE as usize];
+            r.read_exact(&mut data)?;
+            pages.push((page * SPARSE_PAGE_SIZE, data));
+        }
+        expect_end(r)?;
+
+        self.clear();
+        for (addr, data) in pages {
+            self.write_slice(addr, &data).map_err(|e| SnapshotError::Invalid(e.to_string()))?;
+        }
+        Ok(())
+    }
+}
+
+// The ROM image can't change, so only the memory behind it is saved
+impl<T> Snapshot for Rom<T>
+where
+    T: Address + Snapshot,
+{
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        self.memory().save(w)
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> Result<(), SnapshotError> {
+        self.memory_mut().restore(r)
+    }
+}
+
+impl<T> Cpu<T>
+where
+    T: Address + Snapshot,
+{
+    pub fn save_snapshot<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let mut data = vec![];
+        data.extend_from_slice(MAGIC);
+        data.extend_from_slice(&VERSION.to_le_bytes());
+
+        for x in self.xs.iter() {
+            data.extend_from_slice(&x.to_le_bytes());
+        }
+        for f in self.fs.iter() {
+            data.extend_from_slice(&f.to_bits().to_le_bytes());
+        }
+        data.extend_from_slice(&self.flags.bits().to_le_bytes());
+        data.push(self.interrupt_mask);
+        data.extend_from_slice(&self.memmap.to_le_bytes());
+        data.extend_from_slice(&self.system_sp.to_le_bytes());
+        data.extend_from_slice(&self.cycles.to_le_bytes());
+        for value in self.counters.values.iter() {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        data.extend_from_slice(&self.counters.high.to_le_bytes());
+        data.push(self.counters.user_read as u8);
+        data.extend_from_slice(&self.fpcr.to_le_bytes());
+        data.extend_from_slice(&(self.interrupt_queue.len() as u32).to_le_bytes());
+        for interrupt in self.interrupt_queue.iter() {
+            data.extend_from_slice(&interrupt.to_le_bytes());
+        }
+
+        let mut device = vec![];
+        self.addressing.save(&mut device)?;
+        data.extend_from_slice(&(device.len() as u64).to_le_bytes());
+        data.extend_from_slice(&device);
+
+        let checksum = adler32(&data);
+        data.extend_from_slice(&checksum.to_le_bytes());
+        w.write_all(&data)?;
+        w.flush()
+    }
+
+    // Restores a snapshot written by save_snapshot. The cpu is left unchanged if the snapshot is
+    // invalid. Breakpoints, watchpoints and the tracer are kept, but undo history is discarded.
+    pub fn restore_snapshot<R: Read>(&mut self, mut r: R) -> Result<(), SnapshotError> {
+        let mut data = vec![];
+        r.read_to_end(&mut data)?;
+
+        if data.len() < MAGIC.len() || &data[..MAGIC.len()] != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        if data.len() < MAGIC.len() + 2 + 4 {
+            return Err(SnapshotError::Invalid("unexpected end of snapshot".to_string()));
+        }
+
+        let mut r = &data[MAGIC.len()..];
+        let version = read_u16(&mut r)?;
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        let (body, checksum) = data.split_at(data.len() - 4);
+        if adler32(body) != u32::from_le_bytes([checksum[0], checksum[1], checksum[2], checksum[3]]) {
+            return Err(SnapshotError::ChecksumMismatch);
+        }
+        let mut r = &body[MAGIC.len() + 2..];
+
+        let mut xs = [0; 16];
+        for x in xs.iter_mut() {
+            *x = read_u32(&mut r)?;
+        }
+        let mut fs = [0.0; 16];
+        for f in fs.iter_mut() {
+            *f = f32::from_bits(read_u32(&mut r)?);
+        }
+        let flags = read_u32(&mut r)?;
+        let interrupt_mask = read_u8(&mut r)?;
+        let memmap = read_u32(&mut r)?;
+        let system_sp = read_u32(&mut r)?;
+        let cycles = read_u64(&mut r)?;
+        let mut counters = Counters::default();
+        for value in counters.values.iter_mut() {
+            *value = read_u64(&mut r)?;
+        }
+        counters.high = read_u32(&mut r)?;
+        counters.user_read = read_u8(&mut r)? != 0;
+        let fpcr = read_u32(&mut r)? & float::FPCR_MASK;
+
+        let queued = read_u32(&mut r)? as usize;
+        if queued > r.len() / 4 {
+            return Err(SnapshotError::Invalid(format!("interrupt queue length {} too long", queued)));
+        }
+        let mut interrupt_queue = VecDeque::with_capacity(queued);
+        for _ in 0..queued {
+            interrupt_queue.push_back(read_u32(&mut r)?);
+        }
+
+        let len = read_u64(&mut r)?;
+        if len != r.len() as u64 {
+            return Err(SnapshotError::Invalid(format!(
+                "device data is {} bytes but {} remain",
+                len,
+                r.len()
+            )));
+        }
+
+        // Everything else has been checked, so this is the only step left that can fail
+        self.addressing.restore(&mut r)?;
+        self.flush_decode_cache();
+        self.tlb = timing::Tlb::default();
+
+        self.xs = xs;
+        self.fs = fs;
+        self.fpcr = fpcr;
+        self.flags = Flags::from_bits(flags);
+        self.interrupt_mask = interrupt_mask;
+        self.memmap = memmap;
+        self.system_sp = system_sp;
+        self.cycles = cycles;
+        self.counters = counters;
+        self.interrupt_queue = interrupt_queue;
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpu() -> Cpu<SimpleAddress> {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.xs[3] = 0xdeadbeef;
+        cpu.fs[2] = 0.618;
+        cpu.set_flags(0x1008);
+        cpu.memmap = 0x1234;
+        cpu.system_sp = 0xbfff;
+        cpu.interrupt_mask = 0x0f;
+        cpu.fpcr = 0x20111;
+        cpu.cycles = 0x1_0000_0042;
+        cpu.counters.values[3] = 7;
+        cpu.counters.user_read = true;
+        cpu.irq(2);
+        cpu.addressing.memory[0xaf42] = 0x42;
+        cpu.addressing.memory[0xffffff] = 0x24;
+        cpu
+    }
+
+    #[test]
+    fn snapshot_round_trip() {
+        let cpu = cpu();
+        let mut data = vec![];
+        cpu.save_snapshot(&mut data).unwrap();
+
+        // Only the two nonzero chunks are stored
+        assert!(data.len() < 3 * SNAPSHOT_CHUNK);
+
+        let mut restored = Cpu::new(SimpleAddress::default());
+        restored.restore_snapshot(&data[..]).unwrap();
+        assert_eq!(restored.xs, cpu.xs);
+        assert_eq!(restored.fs[2].to_bits(), cpu.fs[2].to_bits());
+        assert_eq!(restored.flags(), 0x1008);
+        assert_eq!(restored.memmap, 0x1234);
+        assert_eq!(restored.system_sp, 0xbfff);
+        assert_eq!(restored.interrupt_mask, 0x0f);
+        assert_eq!(restored.fpcr, 0x20111);
+        assert_eq!(restored.cycles, 0x1_0000_0042);
+        assert_eq!(restored.counters, cpu.counters);
+        assert_eq!(restored.interrupt_queue, cpu.interrupt_queue);
+        assert!(restored.addressing.memory == cpu.addressing.memory);
+    }
+
+    #[test]
+    fn snapshot_timing() {
+        // Two nops in an identity mapped page, with the page table at 0x20000
+        let paged = || {
+            let mut cpu = Cpu::new(SimpleAddress::default());
+            cpu.addressing.memory[..2].copy_from_slice(&[0x3f, 0x3f]);
+            cpu.addressing.memory[0x20000..0x20004].copy_from_slice(&0x30000u32.to_le_bytes());
+            cpu.addressing.memory[0x30000..0x30004].copy_from_slice(&0xf0000000u32.to_le_bytes());
+            cpu.memmap = 0x20000;
+            cpu.set_flag(crate::flags::Flag::MemmapEnable, true);
+            cpu
+        };
+        let mut data = vec![];
+        paged().save_snapshot(&mut data).unwrap();
+
+        // A cpu that has already filled its TLB misses it again after restoring, like a new one
+        let mut warm = paged();
+        warm.run(2);
+        warm.restore_snapshot(&data[..]).unwrap();
+        warm.run(2);
+        let mut cold = Cpu::new(SimpleAddress::default());
+        cold.restore_snapshot(&data[..]).unwrap();
+        cold.run(2);
+        assert_eq!(warm.cycles(), cold.cycles());
+    }
+
+    #[test]
+    fn snapshot_validation() {
+        let mut data = vec![];
+        cpu().save_snapshot(&mut data).unwrap();
+        let mut restored = Cpu::new(SimpleAddress::default());
+
+        let mut bad = data.clone();
+        bad[0] = b'X';
+        assert!(matches!(restored.restore_snapshot(&bad[..]), Err(SnapshotError::BadMagic)));
+
+        let mut bad = data.clone();
+        bad[8] = 2;
+        assert!(matches!(restored.restore_snapshot(&bad[..]), Err(SnapshotError::UnsupportedVersion(2))));
+
+        let mut bad = data.clone();
+        bad[20] ^= 1;
+        assert!(matches!(restored.restore_snapshot(&bad[..]), Err(SnapshotError::ChecksumMismatch)));
+
+        let bad = &data[..data.len() / 2];
+        assert!(restored.restore_snapshot(bad).is_err());
+
+        // Device data with a byte left over, with its length and the checksum fixed up
+        let mut device = vec![];
+        cpu().addressing.save(&mut device).unwrap();
+        let mut bad = data[..data.len() - 4].to_vec();
+        let len_at = bad.len() - device.len() - 8;
+        bad[len_at..len_at + 8].copy_from_slice(&(device.len() as u64 + 1).to_le_bytes());
+        bad.push(0);
+        let checksum = adler32(&bad);
+        bad.extend_from_slice(&checksum.to_le_bytes());
+        assert!(matches!(restored.restore_snapshot(&bad[..]), Err(SnapshotError::Invalid(_))));
+
+        // Nothing was restored
+        assert_eq!(restored.xs, [0; 16]);
+        assert_eq!(restored.addressing.memory[0xaf42], 0);
+    }
+
+    #[test]
+    fn snapshot_sparse() {
+        let mut cpu = Cpu::new(SparseAddress::default());
+        cpu.addressing.write_u32(0xfffffffc, 0x11223344).unwrap();
+        cpu.addressing.write(0x8000, 0x42).unwrap();
+        cpu.addressing.write(0x9000, 0x01).unwrap();
+        cpu.addressing.write(0x9000, 0x00).unwrap();
+        let mut data = vec![];
+        cpu.save_snapshot(&mut data).unwrap();
+
+        // The page that was zeroed again is not stored
+        assert!(data.len() < 3 * SPARSE_PAGE_SIZE as usize);
+
+        let mut restored = Cpu::new(SparseAddress::default());
+        restored.addressing.write(0x5000, 0x01).unwrap();
+        restored.restore_snapshot(&data[..]).unwrap();
+        assert_eq!(restored.addressing.read_u32(0xfffffffc), Ok(0x11223344));
+        assert_eq!(restored.addressing.read(0x8000), Ok(0x42));
+        assert_eq!(restored.addressing.read(0x5000), Ok(0));
+        assert_eq!(restored.addressing.allocated(), 2);
+
+        // Sizes have to match
+        let mut small = Cpu::new(SparseAddress::new(0x10000, crate::Unbacked::ReadZero));
+        assert!(matches!(small.restore_snapshot(&data[..]), Err(SnapshotError::Invalid(_))));
+    }
+}
@@ -0,0 +1,156 @@
+// Symbol tables for debugging
+//
+// Symbols are loaded from an executable or from a map file. A map file has one `<address> <name>`
+// pair per line; other lines (such as the section listing in maps written by the linker) and
+// anything after a `#` are ignored.
+
+use std::collections::HashMap;
+
+use crate::loader::LoadError;
+use crate::object::Object;
+use crate::parse_num;
+
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    // Sorted by address
+    by_addr: Vec<(u32, String)>,
+    by_name: HashMap<String, u32>,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    pub fn from_object(object: &Object) -> Symbols {
+        let mut symbols = Symbols::new();
+        for s in object.symbols.iter() {
+            if let Some(addr) = object.symbol_address(s) {
+                symbols.insert(addr, &s.name);
+            }
+        }
+        symbols
+    }
+
+    pub fn parse_map(text: &str) -> Result<Symbols, LoadError> {
+        let mut symbols = Symbols::new();
+        for (i, line) in text.lines().enumerate() {
+            let mut words = line.split('#').next().unwrap().split_whitespace();
+            let addr = match words.next().and_then(parse_num) {
+                Some(addr) => addr,
+                None => continue,
+            };
+
+            match (words.next(), words.next()) {
+                (Some(name), None) => symbols.insert(addr, name),
+                _ => {
+                    return Err(LoadError::Parse {
+                        line: i + 1,
+                        message: "expected `<address> <name>`".to_string(),
+                    })
+                }
+            }
+        }
+        Ok(symbols)
+    }
+
+    // Adds a symbol, replacing any existing symbol with the same name
+    pub fn insert(&mut self, addr: u32, name: &str) {
+        if let Some(old) = self.by_name.insert(name.to_string(), addr) {
+            self.by_addr.retain(|(a, n)| *a != old || n != name);
+        }
+        let i = self.by_addr.partition_point(|(a, _)| *a <= addr);
+        self.by_addr.insert(i, (addr, name.to_string()));
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_addr.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_addr.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (u32, &str)> {
+        self.by_addr.iter().map(|(a, n)| (*a, n.as_str()))
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<u32> {
+        self.by_name.get(name).copied()
+    }
+
+    // Finds the closest symbol at or below addr, returning its name and the offset of addr from it
+    pub fn find(&self, addr: u32) -> Option<(&str, u32)> {
+        let i = self.by_addr.partition_point(|(a, _)| *a <= addr);
+        let (a, name) = self.by_addr.get(i.checked_sub(1)?)?;
+        Some((name, addr - a))
+    }
+
+    // Formats addr as `name` or `name+0x8`, if there is a symbol at or below it
+    pub fn describe(&self, addr: u32) -> Option<String> {
+        match self.find(addr)? {
+            (name, 0) => Some(name.to_string()),
+            (name, offset) => Some(format!("{}+{:#x}", name, offset)),
+        }
+    }
+
+    // Evaluates a number, a symbol, or a symbol or number followed by `+` or `-` and an offset
+    pub fn resolve(&self, expr: &str) -> Option<u32> {
+        let term = |s: &str| parse_num(s.trim()).or_else(|| self.lookup(s.trim()));
+        match expr.rfind(['+', '-']) {
+            Some(i) if i > 0 => {
+                let (base, offset) = (term(&expr[..i])?, parse_num(expr[i + 1..].trim())?);
+                if expr.as_bytes()[i] == b'+' {
+                    Some(base.wrapping_add(offset))
+                } else {
+                    Some(base.wrapping_sub(offset))
+                }
+            }
+            _ => term(expr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAP: &str = "\
+# section            address     size        perms
+.text                0x00000000  0x0000000c  r-x
+  main.cwo           0x00000000  0x0000000b
+
+# symbols
+0x00000000 main
+0x0000000b inc
+0x00010000 buffer # 64 bytes
+";
+
+    #[test]
+    fn symbols_map() {
+        let symbols = Symbols::parse_map(MAP).unwrap();
+        assert_eq!(symbols.len(), 3);
+        assert_eq!(symbols.lookup("inc"), Some(0x0b));
+        assert_eq!(symbols.find(0x0a), Some(("main", 0x0a)));
+        assert_eq!(symbols.describe(0x10008), Some("buffer+0x8".to_string()));
+        assert_eq!(symbols.describe(0x0b), Some("inc".to_string()));
+
+        assert!(matches!(Symbols::parse_map("0x10 a b"), Err(LoadError::Parse { line: 1, .. })));
+        assert!(Symbols::new().find(0).is_none());
+    }
+
+    #[test]
+    fn symbols_resolve() {
+        let mut symbols = Symbols::parse_map(MAP).unwrap();
+        assert_eq!(symbols.resolve("buffer+8"), Some(0x10008));
+        assert_eq!(symbols.resolve("buffer - 0x10"), Some(0xfff0));
+        assert_eq!(symbols.resolve("0x20+4"), Some(0x24));
+        assert_eq!(symbols.resolve("42"), Some(42));
+        assert_eq!(symbols.resolve("nothing+1"), None);
+
+        // Redefining a symbol moves it
+        symbols.insert(0x20, "main");
+        assert_eq!(symbols.find(0x04), None);
+        assert_eq!(symbols.find(0x24), Some(("main", 4)));
+    }
+}
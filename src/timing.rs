@@ -0,0 +1,360 @@
+// Cycle timing model
+//
+// Every instruction costs a number of cycles depending on its class, plus penalties for taken
+// branches, translation lookaside buffer misses and the page walks they cause, and accesses to
+// memory mapped devices. The total is kept in a 64 bit cycle counter that guest code can read
+// with `rsr` through the `cycles` (low half) and `cycleh` (high half) selectors.
+//
+// The TLB only exists for timing purposes: translations always walk the page table, so a stale
+// entry can never change what an access does.
+
+use std::collections::BinaryHeap;
+use std::cmp::Reverse;
+
+use crate::flags::Flag;
+use crate::perf::Counter;
+use crate::{Address, Cpu, PAGE_SIZE};
+
+pub const OP_CLASSES: usize = 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OpClass {
+    Branch,
+    Flag,
+    Call,
+    Return,
+    LoadImmediate,
+    Load,
+    Store,
+    IntArith,
+    IntMulDiv,
+    FloatArith,
+    FloatDiv,
+    Bitwise,
+    Move,
+    System,
+}
+
+impl OpClass {
+    // Classifies an instruction by its first byte. Unknown opcodes execute as no-ops and are
+    // classed as moves.
+    pub fn of(opcode: u8) -> OpClass {
+        match opcode & 0xc0 {
+            0x00 => match opcode & 0x3f {
+                0x00..=0x0f => OpClass::Branch,
+                0x10 | 0x11 => OpClass::Flag,
+                0x12..=0x17 => OpClass::System,
+                0x18 => OpClass::Call,
+                0x19 => OpClass::Return,
+                _ => OpClass::Move,
+            },
+
+            0x40 => match opcode & 0x30 {
+                0x00 | 0x10 => OpClass::LoadImmediate,
+                _ => OpClass::Load,
+            },
+
+            0x80 => match opcode & 0x3f {
+                0x00 | 0x01 => OpClass::IntArith,
+                0x02..=0x04 => OpClass::IntMulDiv,
+                0x05..=0x07 => OpClass::FloatArith,
+                0x08 | 0x1f => OpClass::FloatDiv,
+                0x09..=0x0d => OpClass::Bitwise,
+                0x14 | 0x15 => OpClass::Load,
+                0x16..=0x19 => OpClass::Store,
+                0x1a | 0x1b => OpClass::System,
+                0x1c..=0x25 => OpClass::FloatArith,
+                _ => OpClass::Move,
+            },
+
+            _ => OpClass::Store,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CostTable {
+    // Base cost of each instruction class, indexed by `OpClass as usize`
+    pub ops: [u64; OP_CLASSES],
+
+    pub branch_taken: u64,
+    pub interrupt: u64,
+
+    // Charged once for each virtual address translation that misses the TLB, plus page_walk for
+    // each of the two page table levels read
+    pub tlb_miss: u64,
+    pub page_walk: u64,
+
+    // Charged for each byte accessed at an address the backend reports as a device
+    pub device_access: u64,
+}
+
+impl Default for CostTable {
+    fn default() -> CostTable {
+        let mut ops = [1; OP_CLASSES];
+        ops[OpClass::Branch as usize] = 2;
+        ops[OpClass::Call as usize] = 4;
+        ops[OpClass::Return as usize] = 4;
+        ops[OpClass::Load as usize] = 3;
+        ops[OpClass::Store as usize] = 3;
+        ops[OpClass::IntMulDiv as usize] = 8;
+        ops[OpClass::FloatArith as usize] = 4;
+        ops[OpClass::FloatDiv as usize] = 16;
+        ops[OpClass::System as usize] = 2;
+
+        CostTable {
+            ops,
+            branch_taken: 1,
+            interrupt: 8,
+            tlb_miss: 2,
+            page_walk: 4,
+            device_access: 10,
+        }
+    }
+}
+
+impl CostTable {
+    pub fn cost(&self, class: OpClass) -> u64 {
+        self.ops[class as usize]
+    }
+
+    pub fn set_cost(&mut self, class: OpClass, cycles: u64) {
+        self.ops[class as usize] = cycles;
+    }
+}
+
+const TLB_ENTRIES: usize = 16;
+
+// Direct mapped TLB of virtual page numbers for the page table at memmap
+#[derive(Clone, Copy)]
+pub(crate) struct Tlb {
+    entries: [Option<u32>; TLB_ENTRIES],
+    memmap: Option<u32>,
+}
+
+impl Default for Tlb {
+    fn default() -> Tlb {
+        Tlb { entries: [None; TLB_ENTRIES], memmap: None }
+    }
+}
+
+impl Tlb {
+    // Looks up the page of addr, filling the entry on a miss. Returns whether it hit.
+    fn lookup(&mut self, memmap: u32, addr: u32) -> bool {
+        if self.memmap != Some(memmap) {
+            self.flush();
+            self.memmap = Some(memmap);
+        }
+
+        let page = addr / PAGE_SIZE;
+        let entry = &mut self.entries[page as usize % TLB_ENTRIES];
+        let hit = *entry == Some(page);
+        *entry = Some(page);
+        hit
+    }
+
+    fn flush(&mut self) {
+        // Entries are only filled with memmap set, so there is nothing to do if it is not
+        if self.memmap.is_some() {
+            self.entries = [None; TLB_ENTRIES];
+            self.memmap = None;
+        }
+    }
+}
+
+// A queue of events at future cycles, for devices to keep track of their own timers. Events are
+// identified by a number chosen by the device.
+#[derive(Debug, Clone, Default)]
+pub struct EventQueue {
+    events: BinaryHeap<Reverse<(u64, u32)>>,
+}
+
+impl EventQueue {
+    pub fn new() -> EventQueue {
+        EventQueue::default()
+    }
+
+    pub fn schedule(&mut self, cycle: u64, id: u32) {
+        self.events.push(Reverse((cycle, id)));
+    }
+
+    // Cycle of the earliest event
+    pub fn next(&self) -> Option<u64> {
+        self.events.peek().map(|Reverse((cycle, _))| *cycle)
+    }
+
+    // Removes and returns the earliest event if it is due at cycle now
+    pub fn pop_due(&mut self, now: u64) -> Option<u32> {
+        match self.events.peek() {
+            Some(Reverse((cycle, _))) if *cycle <= now => self.events.pop().map(|Reverse((_, id))| id),
+            _ => None,
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+}
+
+impl<T> Cpu<T>
+where
+    T: Address,
+{
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn set_cycles(&mut self, cycles: u64) {
+        self.cycles = cycles;
+    }
+
+    pub fn costs(&self) -> &CostTable {
+        &self.costs
+    }
+
+    pub fn set_costs(&mut self, costs: CostTable) {
+        self.costs = costs;
+    }
+
+    pub(crate) fn charge(&mut self, cycles: u64) {
+        self.cycles = self.cycles.wrapping_add(cycles);
+        self.counters.count(Counter::Cycles, cycles);
+    }
+
+    // Charges the TLB and page walk penalties for an access to addr
+    pub(crate) fn charge_translation(&mut self, addr: u32) {
+        if !self.get_flag(Flag::MemmapEnable) {
+            self.tlb.flush();
+        } else if !self.tlb.lookup(self.memmap, addr) {
+            self.charge(self.costs.tlb_miss + 2 * self.costs.page_walk);
+            self.counters.count(Counter::PageWalks, 1);
+        }
+    }
+
+    pub(crate) fn charge_device(&mut self, paddr: u32) {
+        if self.addressing.is_device(paddr) {
+            self.charge(self.costs.device_access);
+        }
+    }
+
+    // Lets the backend handle an event that has come due, requesting the interrupt it returns
+    pub(crate) fn run_events(&mut self) {
+        if self.addressing.next_event().is_some_and(|cycle| cycle <= self.cycles) {
+            let irq = self.addressing.run_event(self.cycles);
+            self.flush_decode_cache();
+            if let Some(irq) = irq {
+                self.irq(irq);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{BusError, SimpleAddress};
+
+    // Raises interrupt 1 every 10 cycles, with a device register at 0x100000
+    struct Timer {
+        memory: SimpleAddress,
+        events: EventQueue,
+    }
+
+    impl Address for Timer {
+        fn read(&mut self, addr: u32) -> Result<u8, BusError> {
+            self.memory.read(addr)
+        }
+
+        fn write(&mut self, addr: u32, data: u8) -> Result<(), BusError> {
+            self.memory.write(addr, data)
+        }
+
+        fn is_device(&self, addr: u32) -> bool {
+            addr == 0x100000
+        }
+
+        fn next_event(&self) -> Option<u64> {
+            self.events.next()
+        }
+
+        fn run_event(&mut self, now: u64) -> Option<u8> {
+            self.events.pop_due(now)?;
+            self.events.schedule(now + 10, 0);
+            Some(1)
+        }
+    }
+
+    fn cpu(program: &[u8]) -> Cpu<Timer> {
+        let mut events = EventQueue::new();
+        events.schedule(10, 0);
+        let mut cpu = Cpu::new(Timer { memory: SimpleAddress::default(), events });
+        for (i, &b) in program.iter().enumerate() {
+            cpu.addressing.memory.write(i as u32, b).unwrap();
+        }
+        cpu
+    }
+
+    #[test]
+    fn timing_costs() {
+        // li x0, 1; bz 0; add x0, x0; rsr x1, cycles; sb x0, [0x100000]
+        let mut cpu = cpu(&[0x40, 1, 0, 0, 0, 0x00, 0, 0, 0, 0, 0x80, 0x00, 0x9b, 0x31, 0xe0, 0, 0, 0x10, 0]);
+        cpu.step();
+        assert_eq!(cpu.cycles(), 1);
+        cpu.step();
+        assert_eq!(cpu.cycles(), 3);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.xs[1], 6);
+        cpu.step();
+        assert_eq!(cpu.cycles(), 6 + 3 + 10);
+
+        // A taken branch costs more
+        let mut cpu = self::cpu(&[0x08, 0x10, 0, 0, 0]);
+        cpu.step();
+        assert_eq!(cpu.cycles(), 3);
+    }
+
+    #[test]
+    fn timing_tlb() {
+        // lw x0, [0x0]; lw x0, [0x0]
+        let mut cpu = cpu(&[0x60, 0, 0, 0, 0, 0x60, 0, 0, 0, 0]);
+
+        // Identity map the first page with all permissions
+        cpu.addressing.memory.write(0x20000, 0x00).unwrap();
+        cpu.addressing.memory.write(0x20001, 0x04).unwrap();
+        cpu.addressing.memory.write(0x20002, 0x03).unwrap();
+        cpu.addressing.memory.write(0x30403, 0xf0).unwrap();
+        cpu.memmap = 0x20000;
+        cpu.set_flag(Flag::MemmapEnable, true);
+
+        // The first fetch misses, everything after it hits
+        cpu.step();
+        assert_eq!(cpu.cycles(), 3 + 2 + 8);
+        cpu.step();
+        assert_eq!(cpu.cycles(), 2 * 3 + 2 + 8);
+
+        // Moving the page table flushes the TLB, even if the new one maps the same pages
+        cpu.addressing.memory.write(0x40001, 0x04).unwrap();
+        cpu.addressing.memory.write(0x40002, 0x03).unwrap();
+        cpu.memmap = 0x40000;
+        cpu.xs[13] = 0;
+        cpu.set_costs(CostTable { tlb_miss: 100, ..CostTable::default() });
+        cpu.step();
+        assert_eq!(cpu.cycles(), 3 * 3 + 2 + 8 + 108);
+    }
+
+    #[test]
+    fn timing_events() {
+        // Each nop costs one cycle
+        let mut cpu = cpu(&[0x3f; 32]);
+        for _ in 0..10 {
+            cpu.step();
+        }
+        assert_eq!(cpu.interrupt_queue.front(), Some(&1));
+        for _ in 0..10 {
+            cpu.step();
+        }
+        assert_eq!(cpu.interrupt_queue.len(), 2);
+        assert_eq!(cpu.addressing.events.next(), Some(30));
+    }
+}
@@ -0,0 +1,432 @@
+// Execution trace recorder
+//
+// When a tracer is attached, every step records the instruction executed (or interrupt taken),
+// the registers and flags it changed and the memory it accessed into a ring buffer.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+use crate::symbols::Symbols;
+use crate::{disasm, Address, Cpu};
+
+const BINARY_MAGIC: &[u8; 8] = b"CPUWUTRC";
+const BINARY_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub addr: u32,
+    pub paddr: u32,
+    pub value: u8,
+    pub write: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct TraceEntry {
+    // Index of the step since the tracer was attached
+    pub step: u64,
+    pub pc: u32,
+
+    // Instruction bytes fetched, empty if an interrupt was taken instead
+    pub bytes: Vec<u8>,
+
+    // Changed registers as (register, old, new)
+    pub xs: Vec<(u8, u32, u32)>,
+    pub fs: Vec<(u8, f32, f32)>,
+    pub flags: Option<(u32, u32)>,
+
+    pub accesses: Vec<MemoryAccess>,
+    pub interrupt: Option<u32>,
+}
+
+impl TraceEntry {
+    pub fn disassemble(&self) -> Option<disasm::Instruction> {
+        disasm::disassemble(self.pc, |a| self.bytes.get(a.wrapping_sub(self.pc) as usize).copied())
+    }
+
+    fn text(&self, symbols: &Symbols) -> String {
+        let mut s = format!("{:>8} {:#010x}  ", self.step, self.pc);
+        if let Some(symbol) = symbols.describe(self.pc) {
+            s.push_str(&format!("<{}>  ", symbol));
+        }
+        if let Some(interrupt) = self.interrupt {
+            s.push_str(&format!("interrupt {:#010x}", interrupt));
+        } else {
+            let bytes: String = self.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            let text = self.disassemble().map(|i| i.annotate(symbols)).unwrap_or_else(|| "<fault>".to_string());
+            s.push_str(&format!("{:<10}  {:<28}", bytes, text));
+        }
+
+        for (x, old, new) in self.xs.iter() {
+            s.push_str(&format!(" x{}: {:#x} -> {:#x}", x, old, new));
+        }
+        for (f, old, new) in self.fs.iter() {
+            s.push_str(&format!(" f{}: {:?} -> {:?}", f, old, new));
+        }
+        if let Some((old, new)) = self.flags {
+            s.push_str(&format!(" flags: {:#x} -> {:#x}", old, new));
+        }
+        for a in self.accesses.iter() {
+            s.push_str(&format!(
+                " {}[{:#010x}/{:#010x}] = {:#04x}",
+                if a.write { "w" } else { "r" },
+                a.addr,
+                a.paddr,
+                a.value
+            ));
+        }
+        s
+    }
+
+    fn json(&self, symbols: &Symbols) -> String {
+        let mut s = format!("{{\"step\":{},\"pc\":{}", self.step, self.pc);
+        if let Some(symbol) = symbols.describe(self.pc) {
+            s.push_str(&format!(",\"symbol\":{}", json_string(&symbol)));
+        }
+        if let Some(interrupt) = self.interrupt {
+            s.push_str(&format!(",\"interrupt\":{}", interrupt));
+        } else {
+            let bytes: String = self.bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            s.push_str(&format!(",\"bytes\":{}", json_string(&bytes)));
+            if let Some(i) = self.disassemble() {
+                s.push_str(&format!(",\"disasm\":{}", json_string(&i.annotate(symbols))));
+            }
+        }
+
+        let regs: Vec<String> = self
+            .xs
+            .iter()
+            .map(|(x, old, new)| format!("\"x{}\":[{},{}]", x, old, new))
+            .chain(self.fs.iter().map(|(f, old, new)| format!("\"f{}\":[{},{}]", f, json_float(*old), json_float(*new))))
+            .collect();
+        s.push_str(&format!(",\"regs\":{{{}}}", regs.join(",")));
+
+        if let Some((old, new)) = self.flags {
+            s.push_str(&format!(",\"flags\":[{},{}]", old, new));
+        }
+
+        let accesses: Vec<String> = self
+            .accesses
+            .iter()
+            .map(|a| {
+                format!(
+                    "{{\"addr\":{},\"paddr\":{},\"value\":{},\"write\":{}}}",
+                    a.addr, a.paddr, a.value, a.write
+                )
+            })
+            .collect();
+        s.push_str(&format!(",\"mem\":[{}]}}", accesses.join(",")));
+        s
+    }
+
+    // Only new register values are stored, as old ones are implied by the previous entries
+    fn binary<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.step.to_le_bytes())?;
+        w.write_all(&self.pc.to_le_bytes())?;
+        match self.interrupt {
+            Some(interrupt) => {
+                w.write_all(&[1])?;
+                w.write_all(&interrupt.to_le_bytes())?;
+            }
+
+            None => {
+                w.write_all(&[0, self.bytes.len() as u8])?;
+                w.write_all(&self.bytes)?;
+            }
+        }
+
+        w.write_all(&[self.xs.len() as u8])?;
+        for (x, _, new) in self.xs.iter() {
+            w.write_all(&[*x])?;
+            w.write_all(&new.to_le_bytes())?;
+        }
+        w.write_all(&[self.fs.len() as u8])?;
+        for (f, _, new) in self.fs.iter() {
+            w.write_all(&[*f])?;
+            w.write_all(&new.to_bits().to_le_bytes())?;
+        }
+        match self.flags {
+            Some((_, new)) => {
+                w.write_all(&[1])?;
+                w.write_all(&new.to_le_bytes())?;
+            }
+            None => w.write_all(&[0])?,
+        }
+
+        w.write_all(&(self.accesses.len() as u16).to_le_bytes())?;
+        for a in self.accesses.iter() {
+            w.write_all(&a.addr.to_le_bytes())?;
+            w.write_all(&a.paddr.to_le_bytes())?;
+            w.write_all(&[a.value, a.write as u8])?;
+        }
+        Ok(())
+    }
+}
+
+fn json_float(x: f32) -> String {
+    if x.is_finite() {
+        format!("{:?}", x)
+    } else {
+        json_string(&x.to_string())
+    }
+}
+
+// Quotes s as a JSON string
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    Text,
+    JsonLines,
+    Binary,
+}
+
+impl std::str::FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<TraceFormat, String> {
+        match s {
+            "text" => Ok(TraceFormat::Text),
+            "json" | "jsonl" => Ok(TraceFormat::JsonLines),
+            "bin" | "binary" => Ok(TraceFormat::Binary),
+            _ => Err(format!("unknown trace format `{}`", s)),
+        }
+    }
+}
+
+pub struct Tracer {
+    entries: VecDeque<TraceEntry>,
+    capacity: usize,
+    steps: u64,
+
+    // Entry for the step in progress along with the registers before it started
+    current: TraceEntry,
+    xs: [u32; 16],
+    fs: [f32; 16],
+    flags: u32,
+}
+
+impl Tracer {
+    pub fn new(capacity: usize) -> Tracer {
+        Tracer {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+            steps: 0,
+            current: TraceEntry::default(),
+            xs: [0; 16],
+            fs: [0.0; 16],
+            flags: 0,
+        }
+    }
+
+    pub fn entries(&self) -> &VecDeque<TraceEntry> {
+        &self.entries
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    pub(crate) fn fetch(&mut self, byte: u8) {
+        self.current.bytes.push(byte);
+    }
+
+    pub(crate) fn access(&mut self, addr: u32, paddr: u32, value: u8, write: bool) {
+        self.current.accesses.push(MemoryAccess { addr, paddr, value, write });
+    }
+
+    pub(crate) fn interrupt(&mut self, interrupt: u32) {
+        self.current.interrupt = Some(interrupt);
+    }
+
+    pub fn write<W: Write>(&self, w: W, format: TraceFormat) -> io::Result<()> {
+        self.write_with_symbols(w, format, &Symbols::new())
+    }
+
+    // Writes the trace with program counters and instruction targets described by symbols in
+    // the text and JSON Lines formats
+    pub fn write_with_symbols<W: Write>(&self, mut w: W, format: TraceFormat, symbols: &Symbols) -> io::Result<()> {
+        if format == TraceFormat::Binary {
+            w.write_all(BINARY_MAGIC)?;
+            w.write_all(&[BINARY_VERSION])?;
+        }
+
+        for entry in self.entries.iter() {
+            match format {
+                TraceFormat::Text => writeln!(w, "{}", entry.text(symbols))?,
+                TraceFormat::JsonLines => writeln!(w, "{}", entry.json(symbols))?,
+                TraceFormat::Binary => entry.binary(&mut w)?,
+            }
+        }
+        w.flush()
+    }
+}
+
+impl<T> Cpu<T>
+where
+    T: Address,
+{
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        std::mem::replace(&mut self.tracer, tracer)
+    }
+
+    pub fn tracer(&self) -> Option<&Tracer> {
+        self.tracer.as_ref()
+    }
+
+    pub fn tracer_mut(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_mut()
+    }
+
+    pub(crate) fn begin_trace(&mut self) {
+        if let Some(tracer) = &mut self.tracer {
+            tracer.current = TraceEntry {
+                step: tracer.steps,
+                pc: self.xs[crate::R_PC],
+                ..TraceEntry::default()
+            };
+            tracer.xs = self.xs;
+            tracer.fs = self.fs;
+            tracer.flags = self.flags.bits();
+        }
+    }
+
+    pub(crate) fn end_trace(&mut self) {
+        if let Some(tracer) = &mut self.tracer {
+            let mut entry = std::mem::take(&mut tracer.current);
+            for i in 0..16 {
+                if tracer.xs[i] != self.xs[i] {
+                    entry.xs.push((i as u8, tracer.xs[i], self.xs[i]));
+                }
+                if tracer.fs[i].to_bits() != self.fs[i].to_bits() {
+                    entry.fs.push((i as u8, tracer.fs[i], self.fs[i]));
+                }
+            }
+            if tracer.flags != self.flags.bits() {
+                entry.flags = Some((tracer.flags, self.flags.bits()));
+            }
+
+            if tracer.entries.len() == tracer.capacity {
+                tracer.entries.pop_front();
+            }
+            if tracer.capacity != 0 {
+                tracer.entries.push_back(entry);
+            }
+            tracer.steps += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SimpleAddress;
+
+    // li x0, 5; sw x0, [0x1000]; li x1, 7; add x0, x1
+    const PROGRAM: [u8; 17] = [
+        0x40, 5, 0, 0, 0, 0xc0, 0x00, 0x10, 0, 0, 0x41, 7, 0, 0, 0, 0x80, 0x01,
+    ];
+
+    fn traced(capacity: usize, steps: usize) -> Cpu<SimpleAddress> {
+        let mut cpu = Cpu::new(SimpleAddress::default());
+        cpu.addressing.memory[..PROGRAM.len()].copy_from_slice(&PROGRAM);
+        cpu.set_tracer(Some(Tracer::new(capacity)));
+        for _ in 0..steps {
+            cpu.step();
+        }
+        cpu
+    }
+
+    #[test]
+    fn trace_entries() {
+        let cpu = traced(16, 4);
+        let entries = cpu.tracer().unwrap().entries();
+        assert_eq!(entries.len(), 4);
+
+        assert_eq!(entries[0].bytes, vec![0x40, 5, 0, 0, 0]);
+        assert_eq!(entries[0].xs, vec![(0, 0, 5), (13, 0, 5)]);
+        assert_eq!(entries[0].disassemble().unwrap().text, "li x0, 0x5");
+
+        assert_eq!(entries[1].accesses.len(), 4);
+        assert_eq!(entries[1].accesses[0], MemoryAccess { addr: 0x1000, paddr: 0x1000, value: 5, write: true });
+
+        assert_eq!(entries[3].xs[0], (0, 5, 12));
+        assert_eq!(entries[3].flags, Some((0x80, 0)));
+    }
+
+    #[test]
+    fn trace_ring_buffer() {
+        let cpu = traced(2, 4);
+        let entries = cpu.tracer().unwrap().entries();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].step, 2);
+        assert_eq!(entries[1].pc, 0x0f);
+    }
+
+    #[test]
+    fn trace_formats() {
+        let cpu = traced(16, 2);
+        let tracer = cpu.tracer().unwrap();
+
+        let mut text = vec![];
+        tracer.write(&mut text, TraceFormat::Text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.lines().next().unwrap().contains("li x0, 0x5"));
+        assert!(text.contains("w[0x00001000/0x00001000] = 0x05"));
+
+        let mut json = vec![];
+        tracer.write(&mut json, TraceFormat::JsonLines).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert_eq!(
+            json.lines().next().unwrap(),
+            r#"{"step":0,"pc":0,"bytes":"4005000000","disasm":"li x0, 0x5","regs":{"x0":[0,5],"x13":[0,5]},"flags":[0,128],"mem":[]}"#
+        );
+
+        let symbols = Symbols::parse_map("0x0 start\n0x1000 counter\n").unwrap();
+        let mut text = vec![];
+        tracer.write_with_symbols(&mut text, TraceFormat::Text, &symbols).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        assert!(text.lines().nth(1).unwrap().contains("<start+0x5>"));
+        assert!(text.contains("sw x0, [0x00001000] <counter>"));
+
+        let mut json = vec![];
+        tracer.write_with_symbols(&mut json, TraceFormat::JsonLines, &symbols).unwrap();
+        assert!(String::from_utf8(json).unwrap().starts_with(r#"{"step":0,"pc":0,"symbol":"start","bytes""#));
+
+        // Quotes and backslashes in symbols are escaped
+        let symbols = Symbols::parse_map("0x0 say\"hi\"\\\n").unwrap();
+        let mut json = vec![];
+        tracer.write_with_symbols(&mut json, TraceFormat::JsonLines, &symbols).unwrap();
+        assert!(String::from_utf8(json).unwrap().starts_with(r#"{"step":0,"pc":0,"symbol":"say\"hi\"\\","bytes""#));
+
+        let mut binary = vec![];
+        tracer.write(&mut binary, TraceFormat::Binary).unwrap();
+        assert_eq!(&binary[..8], BINARY_MAGIC);
+        assert_eq!(binary[8], BINARY_VERSION);
+    }
+
+    #[test]
+    fn trace_json_strings() {
+        // Control characters are escaped, and infinities and NaN, which JSON numbers can't hold, are
+        // written as strings
+        assert_eq!(json_string("a\u{1}\tb\n"), r#""a\u0001\tb\n""#);
+        assert_eq!(json_float(1.5), "1.5");
+        assert_eq!(json_float(f32::INFINITY), r#""inf""#);
+        assert_eq!(json_float(f32::NAN), r#""NaN""#);
+    }
+}